@@ -0,0 +1,53 @@
+#!/bin/sh
+# compares the synchronous and io_uring engines by wiping and verifying a
+# scratch loop device (or a device you name, which WILL be destroyed).
+#
+#   sudo benches/io_engine.sh [size] [device]
+#
+# size defaults to 2G. without a device, a sparse file in $TMPDIR (or
+# /var/tmp) is attached to a loop device with direct I/O, and cleaned up after
+set -eu
+
+SIZE=${1:-2G}
+DEV=${2:-}
+BIN=target/release/checked_wipe
+
+cargo build --release --features io_uring
+if [ "$(id -u)" != 0 ]; then
+    echo "must be run as root" >&2
+    exit 1
+fi
+
+if [ -z "$DEV" ]; then
+    IMG=$(mktemp "${TMPDIR:-/var/tmp}/checked_wipe_bench.XXXXXX")
+    truncate -s "$SIZE" "$IMG"
+    DEV=$(losetup --find --show --direct-io=on "$IMG")
+    trap 'losetup -d "$DEV"; rm -f "$IMG"' EXIT
+fi
+BYTES=$(blockdev --getsize64 "$DEV")
+
+now() {
+    date +%s.%N
+}
+
+# prints MiB/s for BYTES in the seconds between $1 and $2
+rate() {
+    awk -v b="$BYTES" -v s="$1" -v e="$2" 'BEGIN { printf "%8.1f", b / 1048576 / (e - s) }'
+}
+
+echo "$DEV, $BYTES bytes"
+printf '%-34s %10s %10s\n' "engine" "write MiB/s" "read MiB/s"
+for cfg in "--queue-depth 1 --bs 1M" "--queue-depth 1 --bs 4M" \
+           "--queue-depth 4 --bs 1M" "--queue-depth 16 --bs 1M" \
+           "--queue-depth 32 --bs 1M" "--queue-depth 16 --bs 4M" \
+           "--queue-depth 1 --bs 1M --no-direct"; do
+    # shellcheck disable=SC2086
+    {
+        start=$(now)
+        $BIN -o json wipe -d "$DEV" --yes-destroy "$DEV" -n 1 -c $cfg >/dev/null 2>&1
+        written=$(now)
+        $BIN -o json verify "$DEV" -n 1 $cfg >/dev/null 2>&1
+        read=$(now)
+    }
+    printf '%-34s %10s %10s\n' "$cfg" "$(rate "$start" "$written")" "$(rate "$written" "$read")"
+done
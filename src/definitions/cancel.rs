@@ -0,0 +1,43 @@
+use std::sync::{
+    Arc,
+    atomic::{
+        AtomicBool,
+        Ordering
+    }
+};
+
+/// asks a wipe to stop. clones share the same flag, so one can be handed to
+/// the wipe and another kept to stop it with, such as from a signal handler
+#[derive (Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    /// asks whatever holds a clone of the token to stop at the next block
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// whether `cancel` has been called on the token or any of its clones
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clones_share_one_flag() {
+        let token = CancelToken::new();
+        let held = token.clone();
+        assert!(!token.is_cancelled() && !held.is_cancelled());
+        held.cancel();
+        assert!(token.is_cancelled() && held.is_cancelled());
+        assert!(!CancelToken::new().is_cancelled());
+    }
+}
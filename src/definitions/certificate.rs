@@ -0,0 +1,368 @@
+use std::{
+    fmt::Display,
+    io::Write,
+    os::unix::fs::OpenOptionsExt
+};
+use ed25519_dalek::{
+    Signer,
+    Verifier,
+    Signature,
+    SigningKey,
+    VerifyingKey
+};
+use rand_core::OsRng;
+use serde::{
+    Serialize,
+    Deserialize
+};
+use super::{
+    record::WipeRecord,
+    error::Error
+};
+
+/// where the signing key lives unless told otherwise
+pub const DEFAULT_KEY_PATH: &str = "/etc/checked_wipe/signing.key";
+
+/// the statement we make about a wipe, which is what gets signed
+#[derive (Clone, Serialize, Deserialize)]
+pub struct Certificate {
+    pub record: WipeRecord,
+    pub operator: String,
+    pub host: String,
+    pub issued: String
+}
+
+/// a certificate along with the key that signed it and the signature
+#[derive (Clone, Serialize, Deserialize)]
+pub struct SignedCertificate {
+    /// a copy of `payload` for people to read. it is not what gets checked
+    pub certificate: Certificate,
+    /// the certificate exactly as it was signed, as JSON. the signature
+    /// covers these bytes rather than whatever the certificate would turn
+    /// into if serialized again, as not every float reads back exactly
+    pub payload: String,
+    pub public_key: String,
+    pub signature: String
+}
+
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str, len: usize) -> Result<Vec<u8>, String> {
+    let hex = hex.trim();
+    if !hex.is_ascii() || hex.len() != len * 2 {
+        return Err(format!("expected {} hex characters", len * 2));
+    }
+
+    let mut bytes: Vec<u8> = Vec::new();
+    for i in (0..hex.len()).step_by(2) {
+        match u8::from_str_radix(&hex[i..i + 2], 16) {
+            Ok(a) => bytes.push(a),
+            Err(e) => return Err(format!("invalid hex: {}", e))
+        };
+    }
+
+    Ok(bytes)
+}
+
+/// turns the certificate into the exact bytes that get signed. going through
+/// a `Value` sorts the keys, so the verifier gets the same bytes back no
+/// matter how the file was laid out
+fn canonical_bytes(cert: &Certificate) -> Result<Vec<u8>, Error> {
+    let value = match serde_json::to_value(cert) {
+        Ok(a) => a,
+        Err(e) => return Err(Error::Internal(format!("Failed to serialize certificate: {}", e)))
+    };
+    match serde_json::to_vec(&value) {
+        Ok(a) => Ok(a),
+        Err(e) => Err(Error::Internal(format!("Failed to serialize certificate: {}", e)))
+    }
+}
+
+/// works out who is running the wipe, preferring the person behind sudo
+pub fn current_operator() -> String {
+    ["SUDO_USER", "USER", "LOGNAME"].iter()
+        .find_map(|v| std::env::var(v).ok())
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// the name of the machine the wipe is running on
+pub fn current_host() -> String {
+    match std::fs::read_to_string("/proc/sys/kernel/hostname") {
+        Ok(a) => a.trim().to_string(),
+        Err(_) => "unknown".to_string()
+    }
+}
+
+/// loads the signing key from `path`, creating a new one (readable only by
+/// us) if there is none yet
+pub fn load_or_create_key(path: &str) -> Result<SigningKey, Error> {
+    if let Ok(hex) = std::fs::read_to_string(path) {
+        let bytes = from_hex(&hex, 32).map_err(|e| Error::Invalid(format!("Invalid signing key in {}: {}", path, e)))?;
+        let mut secret = [0u8; 32];
+        secret.copy_from_slice(&bytes);
+        return Ok(SigningKey::from_bytes(&secret));
+    }
+
+    let key = SigningKey::generate(&mut OsRng);
+    if let Some(dir) = std::path::Path::new(path).parent() {
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            return Err(Error::from_io(&e, format!("Failed to create {}: {}", dir.display(), e)));
+        }
+    }
+    let mut file = match std::fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path) {
+        Ok(a) => a,
+        Err(e) => return Err(Error::from_io(&e, format!("Failed to create signing key {}: {}", path, e)))
+    };
+    if let Err(e) = writeln!(file, "{}", to_hex(&key.to_bytes())) {
+        return Err(Error::from_io(&e, format!("Failed to write signing key {}: {}", path, e)));
+    }
+
+    Ok(key)
+}
+
+/// gives the hex public key that goes with `key`, to hand to auditors
+pub fn public_key_hex(key: &SigningKey) -> String {
+    to_hex(key.verifying_key().as_bytes())
+}
+
+
+impl Certificate {
+    /// makes a certificate for a finished wipe
+    pub fn new(record: WipeRecord, operator: String) -> Self {
+        Certificate {
+            record,
+            operator,
+            host: current_host(),
+            issued: chrono::offset::Local::now().to_rfc3339()
+        }
+    }
+
+    /// signs the certificate with `key`
+    pub fn sign(self, key: &SigningKey) -> Result<SignedCertificate, Error> {
+        let payload = match String::from_utf8(canonical_bytes(&self)?) {
+            Ok(a) => a,
+            Err(e) => return Err(Error::Internal(format!("Failed to serialize certificate: {}", e)))
+        };
+        let signature = key.sign(payload.as_bytes());
+        Ok(SignedCertificate {
+            certificate: self,
+            payload,
+            public_key: public_key_hex(key),
+            signature: to_hex(&signature.to_bytes())
+        })
+    }
+}
+
+impl SignedCertificate {
+    /// checks the signature against the key in the certificate, and against
+    /// `expected_key` too if we were told which key to trust
+    pub fn verify(&self, expected_key: Option<&str>) -> Result<(), Error> {
+        if let Some(expected) = expected_key {
+            if expected.trim().to_lowercase() != self.public_key.to_lowercase() {
+                return Err(Error::Invalid("certificate was signed by a different key".to_string()));
+            }
+        }
+
+        let mut key_bytes = [0u8; 32];
+        key_bytes.copy_from_slice(&from_hex(&self.public_key, 32).map_err(|e| Error::Invalid(format!("bad public key: {}", e)))?);
+        let key = VerifyingKey::from_bytes(&key_bytes).map_err(|e| Error::Invalid(format!("bad public key: {}", e)))?;
+
+        let mut sig_bytes = [0u8; 64];
+        sig_bytes.copy_from_slice(&from_hex(&self.signature, 64).map_err(|e| Error::Invalid(format!("bad signature: {}", e)))?);
+        let signature = Signature::from_bytes(&sig_bytes);
+
+        match key.verify(self.payload.as_bytes(), &signature) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(Error::Invalid("signature does not match the certificate".to_string()))
+        }
+    }
+
+    /// writes the certificate to `path` as JSON, with text and HTML copies
+    /// next to it for people to read
+    pub fn save(&self, path: &str) -> Result<Vec<String>, Error> {
+        let json = match serde_json::to_string_pretty(self) {
+            Ok(a) => a,
+            Err(e) => return Err(Error::Internal(format!("Failed to serialize certificate: {}", e)))
+        };
+        let base = path.strip_suffix(".json").unwrap_or(path);
+        let files = vec![
+            (format!("{}.json", base), json),
+            (format!("{}.txt", base), self.to_string()),
+            (format!("{}.html", base), self.to_html())
+        ];
+
+        let mut written: Vec<String> = Vec::new();
+        for (file, contents) in files {
+            if let Err(e) = std::fs::write(&file, contents) {
+                return Err(Error::from_io(&e, format!("Failed to write certificate to {}: {}", file, e)));
+            }
+            written.push(file);
+        }
+
+        Ok(written)
+    }
+
+    /// reads a certificate previously saved to `path`. the certificate is
+    /// taken from the signed payload, so what is shown is what was signed
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let json = match std::fs::read_to_string(path) {
+            Ok(a) => a,
+            Err(e) => return Err(Error::from_io(&e, format!("Failed to read certificate {}: {}", path, e)))
+        };
+        let mut cert: SignedCertificate = match serde_json::from_str(&json) {
+            Ok(a) => a,
+            Err(e) => return Err(Error::Invalid(format!("Failed to parse certificate {}: {}", path, e)))
+        };
+        cert.certificate = match serde_json::from_str(&cert.payload) {
+            Ok(a) => a,
+            Err(e) => return Err(Error::Invalid(format!("Failed to parse the signed payload of {}: {}", path, e)))
+        };
+
+        Ok(cert)
+    }
+
+    /// renders the certificate as a standalone HTML page
+    pub fn to_html(&self) -> String {
+        let escape = |s: &str| s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+        let cert = &self.certificate;
+        let record = &cert.record;
+        let disk = &record.disk;
+        let opt = |v: &Option<String>| escape(v.as_deref().unwrap_or("unknown"));
+
+        let mut rows = String::new();
+        for pass in record.passes.iter() {
+            rows.push_str(&format!("<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{:.1}s</td><td>{}</td></tr>\n",
+                pass.number, escape(&pass.pattern), escape(&pass.started), pass.bytes_written, pass.seconds,
+                escape(&pass.verification())));
+        }
+
+        format!(r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Certificate of Erasure</title></head>
+<body>
+<h1>Certificate of Erasure</h1>
+<h2>Device</h2>
+<table>
+<tr><th>Path</th><td>{path}</td></tr>
+<tr><th>Vendor</th><td>{vendor}</td></tr>
+<tr><th>Model</th><td>{model}</td></tr>
+<tr><th>Serial</th><td>{serial}</td></tr>
+<tr><th>WWN</th><td>{wwn}</td></tr>
+<tr><th>Firmware</th><td>{firmware}</td></tr>
+<tr><th>Transport</th><td>{transport}</td></tr>
+<tr><th>Size</th><td>{bytes} bytes</td></tr>
+</table>
+<h2>Erasure</h2>
+<table>
+<tr><th>Method</th><td>{method}</td></tr>
+<tr><th>Started</th><td>{started}</td></tr>
+<tr><th>Finished</th><td>{finished}</td></tr>
+<tr><th>Result</th><td>{result}</td></tr>
+<tr><th>Unwritable</th><td>{unwritable}</td></tr>
+</table>
+<table>
+<tr><th>Pass</th><th>Pattern</th><th>Started</th><th>Bytes written</th><th>Duration</th><th>Verification</th></tr>
+{rows}</table>
+<h2>Issued</h2>
+<table>
+<tr><th>Operator</th><td>{operator}</td></tr>
+<tr><th>Host</th><td>{host}</td></tr>
+<tr><th>Issued</th><td>{issued}</td></tr>
+<tr><th>Tool</th><td>checked_wipe {version}</td></tr>
+<tr><th>Public key</th><td><code>{public_key}</code></td></tr>
+<tr><th>Signature</th><td><code>{signature}</code></td></tr>
+</table>
+</body>
+</html>
+"#,
+            path = escape(&disk.path), vendor = opt(&disk.vendor), model = opt(&disk.model),
+            serial = opt(&disk.serial), wwn = opt(&disk.wwn), firmware = opt(&disk.firmware),
+            transport = escape(&disk.transport), bytes = disk.bytes,
+            method = escape(&record.method), started = escape(&record.started),
+            finished = opt(&record.finished),
+            result = record.status(),
+            unwritable = if record.bad_ranges.is_empty() {"none".to_string()} else {
+                format!("{} block(s) of {} bytes, at LBA {}", record.bad_blocks(), disk.logical_block_size,
+                    record.bad_ranges.iter().map(|r| r.to_string()).collect::<Vec<String>>().join(", "))
+            },
+            rows = rows, operator = escape(&cert.operator), host = escape(&cert.host),
+            issued = escape(&cert.issued), version = escape(&record.tool_version),
+            public_key = self.public_key, signature = self.signature)
+    }
+}
+
+impl Display for SignedCertificate {
+    fn fmt (&self, fmt: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
+        writeln!(fmt, "CERTIFICATE OF ERASURE")?;
+        writeln!(fmt, "======================")?;
+        writeln!(fmt, "{}", self.certificate.record)?;
+        writeln!(fmt, "Operator:   {}", self.certificate.operator)?;
+        writeln!(fmt, "Host:       {}", self.certificate.host)?;
+        writeln!(fmt, "Issued:     {}", self.certificate.issued)?;
+        writeln!(fmt, "Public key: {}", self.public_key)?;
+        write!(fmt, "Signature:  {}", self.signature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{
+        defs::tests::disk,
+        record::PassRecord,
+        tests::Scratch
+    };
+
+    /// a certificate for a wipe whose pass took a time that is one of the
+    /// floats serde_json does not read back exactly
+    fn certificate() -> Certificate {
+        let mut record = WipeRecord::new(&disk("/dev/sdz", Some("S1"), None), "test".to_string(), None);
+        let mut pass = PassRecord::new(1, "0x00".to_string());
+        pass.seconds = 24.802349926564794;
+        pass.verified = Some(true);
+        record.passes.push(pass);
+        record.finish();
+        Certificate { record, operator: "tester".to_string(), host: "host".to_string(), issued: "2024-01-01T00:02:00+00:00".to_string() }
+    }
+
+    /// saves `cert` as `file`, leaving only the JSON behind
+    fn saved(cert: &SignedCertificate, file: &Scratch) {
+        let written = cert.save(&file.name()).expect("Failed to save");
+        for extra in written.iter().filter(|f| **f != file.name()) {
+            let _ = std::fs::remove_file(extra);
+        }
+    }
+
+    #[test]
+    fn saved_certificate_verifies_after_loading() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let file = Scratch::new("cert.json", 0);
+        saved(&certificate().sign(&key).expect("Failed to sign"), &file);
+
+        let mut loaded = SignedCertificate::load(&file.name()).expect("Failed to load");
+        loaded.verify(Some(&public_key_hex(&key))).expect("Genuine certificate did not verify");
+        assert_eq!(loaded.certificate.operator, "tester");
+
+        // the payload is what counts, so changing it breaks the signature
+        loaded.payload = loaded.payload.replace("tester", "someone");
+        assert_eq!(loaded.verify(None).err().map(|e| e.kind()), Some("invalid"));
+    }
+
+    #[test]
+    fn certificates_from_another_key_or_without_a_payload_are_refused() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let other = SigningKey::from_bytes(&[8; 32]);
+        let cert = certificate().sign(&key).expect("Failed to sign");
+        assert_eq!(cert.verify(Some(&public_key_hex(&other))).err().map(|e| e.kind()), Some("invalid"));
+
+        let file = Scratch::new("cert-unsigned.json", 0);
+        let mut json = serde_json::to_value(&cert).unwrap();
+        json.as_object_mut().unwrap().remove("payload");
+        std::fs::write(&file.path, json.to_string()).expect("Failed to write certificate");
+        assert_eq!(SignedCertificate::load(&file.name()).err().map(|e| e.kind()), Some("invalid"));
+    }
+}
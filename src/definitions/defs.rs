@@ -1,67 +1,88 @@
 use std::{
-    io::{
-        self,
-        BufRead,
-    },
     process::Command,
-    str::{
-        from_utf8,
-        pattern::Pattern
-    },
     fmt::Display,
     path::Path,
-    fs::File,
-    
+    os::unix::fs::MetadataExt
+};
+use super::sysfs::{
+    SYS_BLOCK,
+    SYSFS_SECTOR,
+    read_attr,
+    read_u64,
+    read_flag,
+    read_vpd_serial,
+    transport,
+    udev_property,
+    list_dir,
+    class_dir
+};
+use serde::Serialize;
+use super::probe::{
+    FsInfo,
+    probe
+};
+use super::safety::{
+    Hazard,
+    Usage,
+    loops_attached_to,
+    partitions_of
 };
+use super::error::Error;
 
 ///////// HELPER FUNCTIONS ///////////
-/// helper function for reading lines from a file
-fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
-where P: AsRef<Path>, {
-    let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
-}
-
-/// checks to see if the partition is currently mounted
-fn is_mounted(id: String) -> bool {
-    if let Ok(proc_mnts) = read_lines("/proc/mounts"){
-        for line in proc_mnts {
-            // check if the line begins with our path
-            if let Ok(l) = line {
-                if id.is_prefix_of(&l[..]){
-                    // it is mounted
-                    return true;
-                }
-            }
-        }
+/// the loop devices attached to the image at `path`, as hazards
+fn loop_hazards(path: &str) -> Vec<Hazard> {
+    loops_attached_to(path).into_iter()
+        .map(|loop_device| Hazard::LoopBacking { device: path.to_string(), loop_device })
+        .collect()
+}
+
+/// formats a byte count the way fdisk does, as a value and binary unit
+fn human_size(bytes: u64) -> (f64, String) {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
     }
-    
-    // it is not mounted anywhere
-    false
+
+    ((size * 100.0).round() / 100.0, UNITS[unit].to_string())
 }
 
 
 /// prints all of the top level directories of each partition on `disk`
-pub fn print_top_levels(disk: &DiskData) -> Result<(), String>{
-    let mut ctr = 1;
-    for partition in disk.partitions.iter(){
-        println!("\tPartition #{}", ctr);
-        let status = match try_mount(partition.id.clone()){
-            Ok(a) => a,
-            Err(e) => return Err(e)
+pub fn print_top_levels(disk: &DiskData) -> Result<(), Error>{
+    for (idx, partition) in disk.partitions.iter().enumerate(){
+        say!("\tPartition #{}", idx+1);
+
+        // no point trying to mount things mount does not understand,
+        // like encrypted volumes, swap or raid members
+        match &partition.fs {
+            Some(fs) if !fs.is_mountable() => {
+                say!("\t    Holds {}, not mounting", fs);
+                continue;
+            },
+            None => {
+                say!("\t    Unknown partition type");
+                continue;
+            },
+            _ => ()
         };
+        let status = try_mount(partition.id.clone())?;
         
         // we ignore partitions that return 1, as they are unknown and 
         // therefore not mounted to `/tmp/mnt`
         if status == 0 {
-            let paths = std::fs::read_dir("/tmp/mnt").unwrap();
-            for path in paths {
-                println!("\t    {:?}", path.unwrap().file_name());
+            let paths = match std::fs::read_dir("/tmp/mnt") {
+                Ok(a) => a,
+                Err(e) => return Err(Error::from_io(&e, format!("Failed to list {}: {}", partition.id, e)))
+            };
+            for path in paths.map_while(Result::ok) {
+                say!("\t    {:?}", path.file_name());
             }
             unmount()?;
         }
-        ctr += 1;
-        
     }
 
     Ok(())
@@ -69,56 +90,87 @@ pub fn print_top_levels(disk: &DiskData) -> Result<(), String>{
 
 
 /// populated `drives_vec` with the currently unmounted available drives
-pub fn parse_partitions(drives_vec: &mut Vec<DiskData>) -> Result<(), String> {
-    println!("Reading partition information...");
-    // get fdisk's drive listing
-    let system_output = match Command::new("fdisk").arg("-l").output() {
-        Ok(a) => a.stdout,
-        Err(e) => return Err(format!("Failed to execute fdisk: {}", e))
-    };
-                                
-    // loop over each line of the output
-    for line in system_output.split(|c| (c == &('\n' as u8))){
-        let tmp_str = match from_utf8(line){
-            Ok(a) => a,
-            Err(_) => return Err("Failed to convert string".to_string())
-        };
-        // check if the string defines a disk line
-        if String::from("Disk /").is_prefix_of(tmp_str){
-            let diskdat = DiskData::new(tmp_str.to_string());
-            drives_vec.push(diskdat.to_owned())
-        }
-
-        // check if the string defines a partition line
-        for disk in drives_vec.iter_mut(){
-            if disk.path.is_prefix_of(tmp_str){
-                let partition = PartitionData::new(tmp_str.to_string());
-                // disregard any partitions that are mounted
-                match disk.add_partition(partition.clone()){
-                    Ok(_) => (),
-                    Err(e) => panic!("Failed to add partition to structure: {}", e)
-                };
-                
-            }
+pub fn parse_partitions(drives_vec: &mut Vec<DiskData>) -> Result<(), Error> {
+    // work out what is using what once, rather than for every disk
+    let usage = Usage::scan();
+
+    // every whole disk the kernel knows about has an entry here
+    for name in list_dir(Path::new(SYS_BLOCK)).map_err(Error::Discovery)? {
+        // empty devices (unattached loop devices, card readers with no 
+        // card in them, etc) are of no use to us
+        if let Some(disk) = DiskData::from_sysfs(&name, &usage) {
+            drives_vec.push(disk);
         }
-
-        // note we disregard all other lines at the moment, including sector
-        // information, disklabel types, and other misc information
     }
 
     Ok(())
 }
 
+/// reads the details of every disk on the system
+pub fn list_disks() -> Result<Vec<DiskData>, Error> {
+    let mut drives_vec: Vec<DiskData> = Vec::new();
+    parse_partitions(&mut drives_vec)?;
+    Ok(drives_vec)
+}
+
+
+
+/// finds the disk in `drives_vec` that `spec` refers to, which can be its
+/// path, a link to it (such as `/dev/disk/by-id/...`), its serial or its WWN.
+/// some USB bridges give every disk behind them the same serial, so a serial
+/// or WWN that more than one disk has is refused rather than guessed at
+pub fn find_disk(drives_vec: &[DiskData], spec: &str) -> Result<Option<usize>, Error> {
+    let real = std::fs::canonicalize(spec).ok().map(|p| p.to_string_lossy().into_owned());
+    if let Some(idx) = drives_vec.iter().position(|d| d.path == spec || Some(&d.path) == real.as_ref()) {
+        return Ok(Some(idx));
+    }
+
+    let found: Vec<usize> = drives_vec.iter().enumerate()
+        .filter(|(_, d)| d.serial.as_deref() == Some(spec) || d.wwn.as_deref().map(normalize_wwn) == Some(normalize_wwn(spec)))
+        .map(|(idx, _)| idx)
+        .collect();
+    match found.len() {
+        0 => Ok(None),
+        1 => Ok(Some(found[0])),
+        _ => Err(Error::Invalid(format!("{} matches {} disks, so give the path of the one you mean instead: {}", spec, found.len(),
+            found.iter().map(|idx| format!("{} {}", drives_vec[*idx].path, drives_vec[*idx].identity())).collect::<Vec<String>>().join(", "))))
+    }
+}
+
+/// finds the disk to wipe that `spec` refers to, like `find_disk`, but also
+/// takes the path of a regular file as a disk image, adding it to `drives_vec`
+pub fn find_target(drives_vec: &mut Vec<DiskData>, spec: &str) -> Result<usize, Error> {
+    if let Some(idx) = find_disk(drives_vec, spec)? {
+        return Ok(idx);
+    }
+    if !Path::new(spec).is_file() {
+        return Err(Error::Discovery(format!("No disk found matching {}", spec)));
+    }
+    drives_vec.push(DiskData::from_file(spec)?);
+    Ok(drives_vec.len() - 1)
+}
+
+/// strips the different prefixes the kernel and udev put in front of a WWN
+/// (`naa.5000c500...` vs `0x5000c500...`) so they can be compared
+fn normalize_wwn(wwn: &str) -> String {
+    let wwn = wwn.trim().to_lowercase();
+    for prefix in ["naa.", "eui.", "t10.", "0x"].iter() {
+        if let Some(stripped) = wwn.strip_prefix(prefix) {
+            return stripped.to_string();
+        }
+    }
+    wwn
+}
 
 
 /// attempts to mount the partition at `PATH` to `/tmp/mnt`
-pub fn try_mount(path: String) -> Result<u32, String> {
+pub fn try_mount(path: String) -> Result<u32, Error> {
     // make sure the directory `/tmp/mnt` exists, if not create it
     match std::fs::read_dir("/tmp/mnt") {
         Ok(_) => (),
         Err(_) => match std::fs::create_dir("/tmp/mnt"){
             Ok(_) => (),
-            Err(e) => return Err(format!("Failed to mount drive: {}", e))
+            Err(e) => return Err(Error::from_io(&e, format!("Failed to mount drive: {}", e)))
         } 
     }
 
@@ -131,32 +183,32 @@ pub fn try_mount(path: String) -> Result<u32, String> {
                         // 32 means unknown fs, we dont want to die on this, 
                         // so we will just print that we dont know the fs
                         if code != 32 {
-                            return Err(format!("mount failed with {}", a));
+                            return Err(Error::Discovery(format!("mount failed with {}", a)));
                         } else {
-                            println!("\t    Unknown partition type");
+                            say!("\t    Unknown partition type");
                             return Ok(1)
                         }
                     },
-                    None => return Err("mount was terminated by a signal".to_string())
+                    None => return Err(Error::Discovery("mount was terminated by a signal".to_string()))
                 }
             }
         },
-        Err(e) => return Err(format!("Failed to run mount command: {}", e))
+        Err(e) => return Err(Error::from_io(&e, format!("Failed to run mount command: {}", e)))
     };
     
     Ok(0)   
 }
 
 /// unmounts the partition on `/tmp/mnt`
-pub fn unmount() -> Result<(), String> {
+pub fn unmount() -> Result<(), Error> {
     // try to unmount the drive
     match Command::new("umount").arg("/tmp/mnt").status() {
         Ok(a) => {
             if !a.success(){
-                return Err(format!("umount failed with {}", a));
+                return Err(Error::Discovery(format!("umount failed with {}", a)));
             }
         },
-        Err(e) => return Err(format!("Failed to run umount command: {}", e))
+        Err(e) => return Err(Error::from_io(&e, format!("Failed to run umount command: {}", e)))
     };
     
     Ok(())
@@ -169,85 +221,442 @@ pub fn unmount() -> Result<(), String> {
 ///////// STRUCTURE DEFINTIONS //////////
 
 /// define our structure for partition data
-#[derive (Clone)]
+#[derive (Clone, Serialize)]
 pub struct PartitionData{
-    id: String,
-    start: u64,
-    end: u64,
-    size: String,
-    fstype: String,
+    pub id: String,
+    pub start: u64,
+    pub end: u64,
+    pub size: String,
+    pub fs: Option<FsInfo>,
     pub is_mounted: bool
 }
 
 /// define functions for our structures
 impl PartitionData {
-    pub fn new(part_line: String) -> Self {
-        let fmt: &str;
-        if part_line.contains("*") {
-            fmt = "{}\t*\t{}\t{}\t{}\t{}\t{}"
-        } else {
-            fmt = "{}\t{}\t{}\t{}\t{}\t{}";
-        }
-        let (id, start, end, _sectors, size, fstype) = scan_fmt!(&part_line[..], 
-                                                        fmt, 
-                                                        String, u64, u64, u64, 
-                                                        String, String).unwrap();
-        
-        let is_mounted = is_mounted(id.clone());
-
-        PartitionData {id,start,end,size,fstype, is_mounted}
-    }
-
-    pub fn to_string(&self) -> String{
-        format!("{}, Start: {}, End: {}, Size: {}, File system: {}, Mount state: {}",
-            self.id, self.start, self.end, self.size, self.fstype, self.is_mounted
-        )
+    /// reads the partition `name` from sysfs, along with whether `usage`
+    /// has it mounted
+    pub fn from_sysfs(name: &str, usage: &Usage) -> Self {
+        let dir = class_dir(name);
+        let id = format!("/dev/{}", name);
+
+        // sysfs always counts in 512 byte sectors, whatever the disk uses
+        let start = read_u64(&dir, "start").unwrap_or(0);
+        let sectors = read_u64(&dir, "size").unwrap_or(0);
+        let end = (start + sectors).saturating_sub(1);
+        let (size_val, size_unit) = human_size(sectors * SYSFS_SECTOR);
+        let size = format!("{}{}", size_val, size_unit);
+
+        // read the superblock ourselves, falling back to whatever udev
+        // worked out if we do not recognise it
+        let fs = probe(&id, sectors * SYSFS_SECTOR).or_else(|| {
+            udev_property(&dir, "ID_FS_TYPE").map(|fstype| FsInfo {
+                fstype,
+                version: udev_property(&dir, "ID_FS_VERSION"),
+                label: udev_property(&dir, "ID_FS_LABEL"),
+                uuid: udev_property(&dir, "ID_FS_UUID")
+            })
+        });
+        let is_mounted = usage.hazards(&[name]).iter().any(Hazard::is_mount);
+
+        PartitionData {id,start,end,size,fs, is_mounted}
     }
-
 }
 
 impl Display for PartitionData {
     fn fmt (&self, fmt: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
-        write!(fmt, "{}, Start: {}, End: {}, Size: {}, File system: {}, Mount state: {}",
-            self.id, self.start, self.end, self.size, self.fstype, self.is_mounted
-        )
+        write!(fmt, "{}, Start: {}, End: {}, Size: {}, File system: ",
+            self.id, self.start, self.end, self.size
+        )?;
+        match &self.fs {
+            Some(fs) => write!(fmt, "{}", fs)?,
+            None => write!(fmt, "unknown")?
+        };
+        write!(fmt, ", Mount state: {}", self.is_mounted)
     }
 }
 
 
 /// define our structure for disk information
-#[derive (Clone)]
+#[derive (Clone, Serialize)]
 pub struct DiskData {
     pub path: String,
+    pub model: Option<String>,
+    pub vendor: Option<String>,
+    pub serial: Option<String>,
+    pub firmware: Option<String>,
+    pub wwn: Option<String>,
+    pub transport: String,
     pub size: f64,
     pub size_unit: String,
-    pub partitions: Vec<PartitionData>
+    pub bytes: u64,
+    pub removable: bool,
+    pub rotational: bool,
+    pub logical_block_size: u64,
+    pub physical_block_size: u64,
+    pub is_mounted: bool,
+    pub partitions: Vec<PartitionData>,
+    /// everything that is using the disk or its partitions, any of which
+    /// rules out wiping it
+    pub hazards: Vec<Hazard>
 }
 
 impl DiskData {
-    /// creates a new `DiskData` instance
-    fn new(disk_line: String) -> Self {
-        let (path, size, size_unit, _bytes) = scan_fmt!(&disk_line[..], "Disk {}: {} {}, {} bytes", String, f64, String, u64).unwrap();
-        DiskData { path,size,size_unit,partitions: Vec::new() }
+    /// creates a new `DiskData` instance from the disk `name` in sysfs,
+    /// giving nothing if the disk has no media. `usage` says what of the
+    /// system is using it
+    fn from_sysfs(name: &str, usage: &Usage) -> Option<Self> {
+        let dir = class_dir(name);
+        let bytes = read_u64(&dir, "size").unwrap_or(0) * SYSFS_SECTOR;
+        if bytes == 0 {
+            return None;
+        }
+
+        let path = format!("/dev/{}", name);
+        let (size, size_unit) = human_size(bytes);
+
+        // where each of these live depends on the driver, so try the
+        // different spots and let udev fill in any gaps
+        let attr = |names: &[&str], udev_key: &str| {
+            names.iter()
+                .find_map(|n| read_attr(&dir, n))
+                .filter(|v| !v.is_empty() && !v.starts_with("0x"))
+                .or_else(|| udev_property(&dir, udev_key))
+        };
+        let serial = read_attr(&dir, "device/serial")
+            .or_else(|| read_attr(&dir, "serial"))
+            .filter(|v| !v.is_empty())
+            .or_else(|| read_vpd_serial(&dir))
+            .or_else(|| udev_property(&dir, "ID_SERIAL_SHORT"));
+
+        let partitions = partitions_of(name);
+        let mut names: Vec<&str> = vec![name];
+        names.extend(partitions.iter().map(|p| &p[..]));
+        let hazards = usage.hazards(&names);
+
+        let mut disk = DiskData {
+            is_mounted: usage.hazards(&[name]).iter().any(Hazard::is_mount),
+            path,
+            model: attr(&["device/model"], "ID_MODEL"),
+            vendor: attr(&["device/vendor"], "ID_VENDOR"),
+            serial,
+            firmware: attr(&["device/firmware_rev", "device/rev"], "ID_REVISION"),
+            wwn: attr(&["wwid", "device/wwid"], "ID_WWN"),
+            transport: transport(&dir),
+            size,
+            size_unit,
+            bytes,
+            removable: read_flag(&dir, "removable"),
+            rotational: read_flag(&dir, "queue/rotational"),
+            logical_block_size: read_u64(&dir, "queue/logical_block_size").unwrap_or(SYSFS_SECTOR),
+            physical_block_size: read_u64(&dir, "queue/physical_block_size").unwrap_or(SYSFS_SECTOR),
+            partitions: Vec::new(),
+            hazards
+        };
+
+        for partition in partitions {
+            disk.add_partition(PartitionData::from_sysfs(&partition, usage));
+        }
+
+        Some(disk)
+    }
+
+    /// describes the regular file at `path`, such as a VM disk image, as a
+    /// disk so it can be wiped like one. it counts as in use while a loop
+    /// device is attached to it
+    pub fn from_file(path: &str) -> Result<Self, Error> {
+        let real = match std::fs::canonicalize(path) {
+            Ok(a) => a.to_string_lossy().into_owned(),
+            Err(e) => return Err(Error::from_io(&e, format!("Failed to find {}: {}", path, e)))
+        };
+        let meta = match std::fs::metadata(&real) {
+            Ok(a) => a,
+            Err(e) => return Err(Error::from_io(&e, format!("Failed to read the metadata of {}: {}", real, e)))
+        };
+        if !meta.is_file() {
+            return Err(Error::Invalid(format!("{} is not a regular file", real)));
+        }
+        if meta.len() == 0 {
+            return Err(Error::Invalid(format!("{} is empty", real)));
+        }
+        let (size, size_unit) = human_size(meta.len());
+        let hazards = loop_hazards(&real);
+
+        Ok(DiskData {
+            is_mounted: !hazards.is_empty(),
+            path: real,
+            model: Some("Image file".to_string()),
+            vendor: None,
+            serial: None,
+            firmware: None,
+            wwn: None,
+            transport: "file".to_string(),
+            size,
+            size_unit,
+            bytes: meta.len(),
+            removable: false,
+            rotational: false,
+            logical_block_size: SYSFS_SECTOR,
+            physical_block_size: meta.blksize(),
+            partitions: Vec::new(),
+            hazards
+        })
     }
 
     /// adds a partition to the disk structure
-    fn add_partition(&mut self, part: PartitionData ) -> Result<u32,u32> {
+    fn add_partition(&mut self, part: PartitionData) {
         self.partitions.push(part);
-        Ok(0)
     }
 
-    pub fn to_string(&self) -> String {
-        format!("Path: {}, Size: {} {}, # Partitions: {}",
-            self.path, self.size, self.size_unit, self.partitions.len()
-        )
+    /// describes the physical drive the way it is labelled on the
+    /// hardware, so the operator can be sure which one they are wiping
+    pub fn identity(&self) -> String {
+        let mut identity = String::new();
+        if let Some(vendor) = &self.vendor {
+            identity.push_str(&format!("{} ", vendor));
+        }
+        identity.push_str(self.model.as_deref().unwrap_or("Unknown model"));
+        identity.push_str(&format!(" ({}", self.transport));
+        if let Some(serial) = &self.serial {
+            identity.push_str(&format!(", Serial: {}", serial));
+        }
+        if let Some(wwn) = &self.wwn {
+            identity.push_str(&format!(", WWN: {}", wwn));
+        }
+        if let Some(firmware) = &self.firmware {
+            identity.push_str(&format!(", Firmware: {}", firmware));
+        }
+        identity.push(')');
+
+        identity
+    }
+
+    /// the value someone has to give to confirm they really want to destroy
+    /// this disk without being asked. this is the serial where there is one,
+    /// so automation has to name the exact physical drive
+    pub fn confirmation_token(&self) -> &str {
+        self.confirmation_token_among(&[])
+    }
+
+    /// the confirmation token of this disk when `disks` were found alongside
+    /// it. a serial or WWN that another of them also reports would not name
+    /// this one, so the WWN and then the path are asked for instead
+    pub fn confirmation_token_among(&self, disks: &[DiskData]) -> &str {
+        let shared = |same: &dyn Fn(&DiskData) -> bool| disks.iter().any(|d| d.path != self.path && same(d));
+        match (&self.serial, &self.wwn) {
+            (Some(serial), _) if !shared(&|d| d.serial.as_ref() == Some(serial)) => serial,
+            (_, Some(wwn)) if !shared(&|d| d.wwn.as_deref().map(normalize_wwn) == Some(normalize_wwn(wwn))) => wwn,
+            _ => &self.path
+        }
+    }
+
+    /// whether `token` confirms this disk is to be destroyed, out of `disks`.
+    /// when the token is a path, any path that leads to the disk will do
+    pub fn confirmed_by(&self, token: &str, disks: &[DiskData]) -> bool {
+        let expected = self.confirmation_token_among(disks);
+        if token == expected {
+            return true;
+        }
+        expected == self.path
+            && std::fs::canonicalize(token).map(|p| p.to_string_lossy() == self.path).unwrap_or(false)
+    }
+
+    /// a name for files about this disk, made from its confirmation token
+    /// with anything that does not belong in a file name swapped out
+    pub fn file_stem(&self) -> String {
+        let name: String = self.confirmation_token().chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' {c} else {'_'})
+            .collect();
+        name.trim_start_matches('_').to_string()
+    }
+
+    /// looks again at what is using the disk, as it may have been mounted,
+    /// made swap or claimed by something since it was listed
+    pub fn current_hazards(&self) -> Vec<Hazard> {
+        if self.transport == "file" {
+            return loop_hazards(&self.path);
+        }
+        let name = match std::fs::canonicalize(&self.path) {
+            Ok(a) => a.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default(),
+            Err(_) => return Vec::new()
+        };
+        let partitions = partitions_of(&name);
+        let mut names: Vec<&str> = vec![&name];
+        names.extend(partitions.iter().map(|p| &p[..]));
+        Usage::scan().hazards(&names)
+    }
+
+    /// whether anything is using the disk, so that it must not be wiped
+    pub fn in_use(&self) -> bool {
+        !self.in_use_because().is_empty()
+    }
+
+    /// explains everything that is using the disk, one reason per line
+    pub fn in_use_because(&self) -> Vec<String> {
+        let mut reasons: Vec<String> = self.hazards.iter().map(|h| h.to_string()).collect();
+        if reasons.is_empty() && (self.is_mounted || self.partitions.iter().any(|p| p.is_mounted)) {
+            reasons.push(format!("{} is mounted", self.path));
+        }
+        reasons
     }
 }
 
 impl Display for DiskData {
     fn fmt (&self, fmt: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
-        write!(fmt, "Path: {}, Size: {} {}, # Partitions: {}",
-            self.path, self.size, self.size_unit, self.partitions.len()
+        write!(fmt, "Path: {}, Model: {}, Serial: {}, Size: {} {} ({} bytes), # Partitions: {}, {}, {}, {}, Sectors: {}/{}",
+            self.path, self.model.as_deref().unwrap_or("unknown"), self.serial.as_deref().unwrap_or("unknown"),
+            self.size, self.size_unit, self.bytes, self.partitions.len(), self.transport,
+            if self.removable {"Removable"} else {"Fixed"},
+            if self.rotational {"HDD"} else {"SSD"},
+            self.logical_block_size, self.physical_block_size
         )
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use super::super::tests::Scratch;
+
+    /// a disk with nothing on it but where it is and what it is called
+    pub(crate) fn disk(path: &str, serial: Option<&str>, wwn: Option<&str>) -> DiskData {
+        DiskData {
+            path: path.to_string(), model: None, vendor: None, serial: serial.map(|s| s.to_string()), firmware: None,
+            wwn: wwn.map(|s| s.to_string()), transport: "USB".to_string(), size: 1.0, size_unit: "GiB".to_string(),
+            bytes: 1 << 30, removable: true, rotational: false, logical_block_size: 512, physical_block_size: 512,
+            is_mounted: false, partitions: Vec::new(), hazards: Vec::new()
+        }
+    }
+
+    #[test]
+    fn gives_sizes_in_binary_units_to_two_places() {
+        assert_eq!(human_size(500), (500.0, "B".to_string()));
+        assert_eq!(human_size(1536), (1.5, "KiB".to_string()));
+        assert_eq!(human_size(1 << 30), (1.0, "GiB".to_string()));
+        assert_eq!(human_size(1_000_204_886_016), (931.51, "GiB".to_string()));
+    }
+
+    #[test]
+    fn describes_a_drive_the_way_its_label_does() {
+        let mut drive = disk("/dev/sda", Some("WD-WCC4N1"), Some("naa.50014ee2b5c5ed3a"));
+        drive.vendor = Some("ATA".to_string());
+        drive.model = Some("WDC WD10EZEX".to_string());
+        drive.firmware = Some("01.01A01".to_string());
+        drive.transport = "SATA".to_string();
+        assert_eq!(drive.identity(), "ATA WDC WD10EZEX (SATA, Serial: WD-WCC4N1, WWN: naa.50014ee2b5c5ed3a, Firmware: 01.01A01)");
+        assert_eq!(disk("/dev/sdb", None, None).identity(), "Unknown model (USB)");
+    }
+
+    #[test]
+    fn finds_disks_by_path_link_serial_or_wwn() {
+        // a real file, so that a link can lead to it
+        let image = Scratch::new("find-disk", 0);
+        let links = Scratch::dir("find-disk-links");
+        std::os::unix::fs::symlink(&image.path, links.path.join("by-id")).expect("Failed to link image");
+        let disks = vec![disk("/dev/sdx", Some("S1"), None), disk(&image.name(), None, Some("naa.5000C500ABCD"))];
+
+        assert_eq!(find_disk(&disks, "/dev/sdx").ok(), Some(Some(0)));
+        assert_eq!(find_disk(&disks, "S1").ok(), Some(Some(0)));
+        assert_eq!(find_disk(&disks, &links.path.join("by-id").to_string_lossy()).ok(), Some(Some(1)));
+        assert_eq!(find_disk(&disks, "0x5000c500abcd").ok(), Some(Some(1)));
+        assert_eq!(find_disk(&disks, "NAA.5000C500ABCD").ok(), Some(Some(1)));
+        assert_eq!(find_disk(&disks, "S2").ok(), Some(None));
+    }
+
+    #[test]
+    fn refuses_a_serial_that_more_than_one_disk_reports() {
+        let disks = vec![
+            disk("/dev/sdx", Some("BRIDGE0001"), None),
+            disk("/dev/sdy", Some("BRIDGE0001"), None),
+            disk("/dev/sdz", Some("UNIQUE"), Some("naa.5000C500ABCD"))
+        ];
+        assert_eq!(find_disk(&disks, "/dev/sdy").ok(), Some(Some(1)));
+        assert_eq!(find_disk(&disks, "UNIQUE").ok(), Some(Some(2)));
+
+        let error = find_disk(&disks, "BRIDGE0001").expect_err("Shared serial picked a disk");
+        assert_eq!(error.kind(), "invalid");
+        assert!(error.to_string().contains("/dev/sdx") && error.to_string().contains("/dev/sdy"), "{}", error);
+    }
+
+    #[test]
+    fn a_shared_serial_does_not_confirm_a_wipe() {
+        let links = Scratch::dir("confirm-shared");
+        let image = Scratch::new("confirm-shared.img", 4096);
+        std::os::unix::fs::symlink(&image.path, links.path.join("image")).expect("Failed to link image");
+        let disks = vec![
+            disk(&image.name(), Some("BRIDGE0001"), Some("naa.5000C500ABCD")),
+            disk("/dev/sdy", Some("BRIDGE0001"), None),
+            disk("/dev/sdz", Some("UNIQUE"), Some("0x5000c500abcd"))
+        ];
+        assert_eq!(disks[0].confirmation_token_among(&disks), image.name());
+        assert!(!disks[0].confirmed_by("BRIDGE0001", &disks) && !disks[0].confirmed_by("naa.5000C500ABCD", &disks));
+        assert!(disks[0].confirmed_by(&links.path.join("image").to_string_lossy(), &disks));
+        assert!(!disks[1].confirmed_by("BRIDGE0001", &disks) && disks[1].confirmed_by("/dev/sdy", &disks));
+        assert!(disks[2].confirmed_by("UNIQUE", &disks));
+
+        // on its own the serial is enough, and the WWN stands in when only the serial is shared
+        assert!(disks[0].confirmed_by("BRIDGE0001", &disks[..1]));
+        assert_eq!(disks[0].confirmation_token_among(&disks[..2]), "naa.5000C500ABCD");
+    }
+
+    #[test]
+    fn confirms_with_the_serial_then_the_wwn_then_the_path() {
+        assert_eq!(disk("/dev/sdx", Some("S1"), Some("naa.1")).confirmation_token(), "S1");
+        assert_eq!(disk("/dev/sdx", None, Some("naa.1")).confirmation_token(), "naa.1");
+        assert_eq!(disk("/dev/sdx", None, None).confirmation_token(), "/dev/sdx");
+    }
+
+    #[test]
+    fn explains_each_reason_a_disk_is_in_use() {
+        let mut disk = disk("/dev/sdx", None, None);
+        assert!(!disk.in_use());
+        disk.partitions.push(PartitionData { id: "/dev/sdx1".to_string(), start: 2048, end: 4095, size: "1 MiB".to_string(), fs: None, is_mounted: true });
+        assert_eq!(disk.in_use_because(), vec!["/dev/sdx is mounted".to_string()]);
+
+        // what is known about each use says more than that it is mounted
+        disk.hazards.push(Hazard::Swap { device: "/dev/sdx2".to_string() });
+        disk.hazards.push(Hazard::Held { device: "/dev/sdx".to_string(), holder: "md0".to_string(), what: "RAID array".to_string() });
+        assert_eq!(disk.in_use_because(), vec!["/dev/sdx2 is in use as swap".to_string(),
+            "/dev/sdx is part of RAID array (/dev/md0)".to_string()]);
+    }
+
+    #[test]
+    fn names_files_after_the_drive() {
+        assert_eq!(disk("/dev/sdx", Some("WD-1234 5678/A"), None).file_stem(), "WD-1234_5678_A");
+        assert_eq!(disk("/dev/sdx", None, Some("naa.5000c500")).file_stem(), "naa.5000c500");
+        assert_eq!(disk("/dev/sdx", None, None).file_stem(), "dev_sdx");
+    }
+
+    #[test]
+    fn describes_image_files_as_disks() {
+        let image = Scratch::new("image.img", 3 * 1024 * 1024 + 100);
+        let drive = DiskData::from_file(&image.name()).expect("Failed to read image");
+        assert_eq!((drive.bytes, &drive.transport[..], drive.logical_block_size), (3 * 1024 * 1024 + 100, "file", 512));
+        assert_eq!((drive.size, &drive.size_unit[..]), (3.0, "MiB"));
+        assert!(!drive.in_use());
+        assert_eq!(drive.confirmation_token(), image.name());
+    }
+
+    #[test]
+    fn refuses_images_that_are_missing_empty_or_not_files() {
+        assert_eq!(DiskData::from_file("/nonexistent/checked_wipe.img").err().map(|e| e.kind()), Some("io"));
+        assert_eq!(DiskData::from_file("/dev").err().map(|e| e.kind()), Some("invalid"));
+        let empty = Scratch::new("empty.img", 0);
+        assert_eq!(DiskData::from_file(&empty.name()).err().map(|e| e.kind()), Some("invalid"));
+    }
+
+    #[test]
+    fn images_are_confirmed_by_any_path_that_leads_to_them() {
+        let image = Scratch::new("confirm.img", 4096);
+        let links = Scratch::dir("confirm-links");
+        std::os::unix::fs::symlink(&image.path, links.path.join("image")).expect("Failed to link image");
+        let drive = DiskData::from_file(&image.name()).expect("Failed to read image");
+        assert!(drive.confirmed_by(&image.name(), &[]));
+        assert!(drive.confirmed_by(&links.path.join("image").to_string_lossy(), &[]));
+        assert!(!drive.confirmed_by(&links.name(), &[]));
+
+        // a drive with a serial has to be confirmed with it, not its path
+        let serial = disk(&image.name(), Some("S1"), None);
+        assert!(serial.confirmed_by("S1", &[]));
+        assert!(!serial.confirmed_by(&image.name(), &[]));
+    }
+}
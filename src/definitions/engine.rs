@@ -0,0 +1,452 @@
+use std::{
+    alloc::{
+        self,
+        Layout
+    },
+    fs::{
+        File,
+        OpenOptions
+    },
+    ops::{
+        Deref,
+        DerefMut
+    },
+    os::unix::fs::{
+        FileExt,
+        OpenOptionsExt
+    }
+};
+use nix::libc;
+use super::{
+    pattern::Pass,
+    record::LbaRange,
+    error::Error,
+    progress::Tracker,
+    PassOutcome
+};
+
+/// how we talk to the disk while writing and checking it
+#[derive (Clone, Copy)]
+pub struct IoOptions {
+    /// bytes handed to each write, a multiple of the logical block size
+    pub block_size: usize,
+    /// bypass the page cache with `O_DIRECT`, so progress is what reached the
+    /// device and not just what was copied into memory
+    pub direct: bool,
+    /// open with `O_SYNC` so every write waits for the device to have it
+    pub sync: bool,
+    /// how many blocks to keep in flight at once with io_uring. 1 means
+    /// plain synchronous reads and writes
+    pub queue_depth: usize
+}
+
+impl Default for IoOptions {
+    fn default() -> Self {
+        IoOptions {
+            block_size: 1024 * 1024,
+            direct: true,
+            sync: false,
+            queue_depth: if cfg!(feature = "io_uring") {16} else {1}
+        }
+    }
+}
+
+/// parses a size such as `4096`, `512K`, `4M` or `1G` (powers of 1024)
+pub fn parse_size(size: &str) -> Result<usize, Error> {
+    let size = size.trim();
+    let (num, mult) = match size.char_indices().last() {
+        Some((idx, 'k')) | Some((idx, 'K')) => (&size[..idx], 1024),
+        Some((idx, 'm')) | Some((idx, 'M')) => (&size[..idx], 1024 * 1024),
+        Some((idx, 'g')) | Some((idx, 'G')) => (&size[..idx], 1024 * 1024 * 1024),
+        _ => (size, 1)
+    };
+    match num.parse::<usize>() {
+        Ok(0) => Err(Error::Invalid("size must be more than 0".to_string())),
+        Ok(a) => match a.checked_mul(mult) {
+            Some(a) => Ok(a),
+            None => Err(Error::Invalid(format!("{} is too big", size)))
+        },
+        Err(e) => Err(Error::Invalid(format!("invalid size {}: {}", size, e)))
+    }
+}
+
+/// a zeroed heap buffer whose start is aligned for `O_DIRECT`, which wants
+/// the memory as well as the offsets and lengths lined up with the device
+pub struct AlignedBuf {
+    ptr: *mut u8,
+    layout: Layout
+}
+
+impl AlignedBuf {
+    /// allocates `len` bytes aligned to at least `align`. we never go below
+    /// a page, which keeps every device and kernel we have met happy
+    pub fn new(len: usize, align: usize) -> Self {
+        let layout = Layout::from_size_align(len.max(1), align.max(4096).next_power_of_two())
+            .expect("Invalid buffer layout");
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        AlignedBuf { ptr, layout }
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr, self.layout) }
+    }
+}
+
+/// opens `path` for reading, with `O_DIRECT` if `io` asks for it so that we
+/// check what is on the device rather than what is still in the page cache
+pub fn open_for_read(path: &str, io: &IoOptions) -> Result<File, Error> {
+    if io.direct {
+        match OpenOptions::new().read(true).custom_flags(libc::O_DIRECT).open(path) {
+            Ok(a) => return Ok(a),
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => (),
+            Err(e) => return Err(Error::from_io(&e, format!("Failed to open {} for reading: {}", path, e)))
+        }
+    }
+
+    match File::open(path) {
+        Ok(a) => Ok(a),
+        Err(e) => Err(Error::from_io(&e, format!("Failed to open {} for reading: {}", path, e)))
+    }
+}
+
+/// opens `path` for writing as `io` asks. not every target supports
+/// `O_DIRECT` (tmpfs for one), so if it is refused we fall back to going
+/// through the page cache and say so in the second value
+pub fn open_for_write(path: &str, io: &IoOptions) -> Result<(File, bool), Error> {
+    let mut flags = 0;
+    if io.sync {
+        flags |= libc::O_SYNC;
+    }
+
+    if io.direct {
+        match OpenOptions::new().write(true).custom_flags(flags | libc::O_DIRECT).open(path) {
+            Ok(a) => return Ok((a, true)),
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => (),
+            Err(e) => return Err(Error::from_io(&e, format!("Failed to open {} for writing: {}", path, e)))
+        }
+    }
+
+    match OpenOptions::new().write(true).custom_flags(flags).open(path) {
+        Ok(a) => Ok((a, false)),
+        Err(e) => Err(Error::from_io(&e, format!("Failed to open {} for writing: {}", path, e)))
+    }
+}
+
+
+/// something that can write a pass over part of a disk, or read it back to
+/// check it, one block of `IoOptions::block_size` at a time
+pub trait Backend {
+    /// writes `pass` over `start..end` of `file`, advancing `progress` by the
+    /// size of each block as it lands. what was written, and what could not
+    /// be, is added to `outcome`. gives how far it got, which is short of
+    /// `end` if `progress` was cancelled, a checkpoint fell due after the
+    /// first block, or the backend itself failed part way, but always on a
+    /// block boundary with everything before it done
+    fn write(&mut self, file: &File, pass: &Pass, start: u64, end: u64, outcome: &mut PassOutcome, progress: &Tracker) -> u64;
+
+    /// reads `start..end` of `file` back, adding every sector that is not
+    /// what `pass` wrote there (or could not be read at all) to `mismatches`.
+    /// stops early if `progress` is cancelled
+    fn check(&mut self, file: &File, pass: &Pass, start: u64, end: u64, mismatches: &mut Vec<LbaRange>, progress: &Tracker);
+}
+
+/// the plain backend, with one read or write at a time
+pub struct SyncBackend {
+    buf: AlignedBuf,
+    expected: Vec<u8>,
+    block_size: usize,
+    sector: usize
+}
+
+impl SyncBackend {
+    pub fn new(block_size: usize, align: usize) -> Self {
+        SyncBackend {
+            buf: AlignedBuf::new(block_size, align),
+            expected: vec![0; block_size],
+            block_size,
+            sector: align
+        }
+    }
+}
+
+impl Backend for SyncBackend {
+    fn write(&mut self, file: &File, pass: &Pass, start: u64, end: u64, outcome: &mut PassOutcome, progress: &Tracker) -> u64 {
+        let mut offset = start;
+        while offset < end && !progress.cancelled() {
+            let len = std::cmp::min(self.block_size as u64, end - offset) as usize;
+
+            // generate this block's data. for fixed passes this is just a memset
+            let data = &mut self.buf[..len];
+            pass.fill(data, offset);
+            match file.write_all_at(data, offset) {
+                Ok(_) => outcome.bytes_written += len as u64,
+                Err(e) => rewrite_in_pieces(file, data, offset, self.sector, e, outcome, progress)
+            };
+            offset += len as u64;
+            progress.advance(len as u64);
+            if progress.checkpoint_due() {
+                break;
+            }
+        }
+        offset
+    }
+
+    fn check(&mut self, file: &File, pass: &Pass, start: u64, end: u64, mismatches: &mut Vec<LbaRange>, progress: &Tracker) {
+        let mut offset = start;
+        while offset < end && !progress.cancelled() {
+            let len = std::cmp::min(self.block_size as u64, end - offset) as usize;
+            match file.read_exact_at(&mut self.buf[..len], offset) {
+                Ok(_) => {
+                    pass.fill(&mut self.expected[..len], offset);
+                    mismatched_sectors(&self.buf[..len], &self.expected[..len], offset, self.sector, mismatches);
+                },
+                Err(e) => {
+                    progress.error(offset, format!("Read error: {}", e));
+                    unreadable_sectors(offset, len, self.sector, mismatches);
+                }
+            }
+            offset += len as u64;
+            progress.advance(len as u64);
+        }
+    }
+}
+
+/// after writing `data` at `offset` failed with `error`, tries again in
+/// halves, and halves of those, down to single sectors, so that only the
+/// sectors that really cannot be written are lost. those are skipped and
+/// added to the outcome's bad ranges
+pub fn rewrite_in_pieces(file: &File, data: &[u8], offset: u64, sector: usize, error: std::io::Error, outcome: &mut PassOutcome, progress: &Tracker) {
+    progress.error(offset, format!("Write error: {}, retrying in smaller pieces", error));
+    let sector = sector.max(1);
+    let before = outcome.bad_ranges.len();
+    retry_halves(file, data, offset, sector, outcome);
+
+    let lost: u64 = outcome.bad_ranges[before..].iter().map(|r| r.blocks()).sum();
+    if lost > 0 {
+        progress.error(offset, format!("Skipped {} unwritable sector(s)", lost));
+        outcome.errors.push(format!("write error at offset {}: {} ({} sector(s) could not be written)", offset, error, lost));
+    }
+}
+
+/// writes the two halves of `data`, which failed as a whole, splitting
+/// further whichever of them fails too
+fn retry_halves(file: &File, data: &[u8], offset: u64, sector: usize, outcome: &mut PassOutcome) {
+    if data.len() <= sector {
+        outcome.bad_ranges.push(LbaRange::single(offset / sector as u64));
+        return;
+    }
+
+    // keep both halves on sector boundaries, as direct I/O needs
+    let half = std::cmp::max(data.len() / sector / 2, 1) * sector;
+    for (piece, at) in [(&data[..half], offset), (&data[half..], offset + half as u64)] {
+        match file.write_all_at(piece, at) {
+            Ok(_) => outcome.bytes_written += piece.len() as u64,
+            Err(_) => retry_halves(file, piece, at, sector, outcome)
+        }
+    }
+}
+
+/// adds the sectors where `read`, found at `offset` of the disk, is not
+/// `expected` to `out`
+pub fn mismatched_sectors(read: &[u8], expected: &[u8], offset: u64, sector: usize, out: &mut Vec<LbaRange>) {
+    if read == expected {
+        return;
+    }
+    let sector = sector.max(1);
+    let first = offset / sector as u64;
+    for (i, (a, b)) in read.chunks(sector).zip(expected.chunks(sector)).enumerate() {
+        if a != b {
+            push_sectors(out, first + i as u64, first + i as u64);
+        }
+    }
+}
+
+/// adds every sector of the `len` bytes at `offset`, which could not be read, to `out`
+pub fn unreadable_sectors(offset: u64, len: usize, sector: usize, out: &mut Vec<LbaRange>) {
+    let sector = sector.max(1) as u64;
+    push_sectors(out, offset / sector, (offset + len.max(1) as u64 - 1) / sector);
+}
+
+/// adds sectors `first..=last` to `out`, growing the last range if they follow on from it
+fn push_sectors(out: &mut Vec<LbaRange>, first: u64, last: u64) {
+    match out.last_mut() {
+        Some(prev) if prev.last.saturating_add(1) == first => prev.last = last,
+        _ => out.push(LbaRange { first, last })
+    }
+}
+
+/// checks that we can use io_uring here, giving why not if we cannot
+pub fn uring_support() -> Result<(), Error> {
+    #[cfg(feature = "io_uring")]
+    {
+        super::uring::Ring::new(1).map(|_| ()).map_err(|message| Error::Io { offset: None, message })
+    }
+    #[cfg(not(feature = "io_uring"))]
+    {
+        Err(Error::Invalid("checked_wipe was built without the io_uring feature".to_string()))
+    }
+}
+
+/// picks the backend `io` asks for, aligning its buffers to `align`. if
+/// io_uring cannot be set up we quietly use the plain backend instead
+pub fn open_backend(io: &IoOptions, align: usize) -> Box<dyn Backend> {
+    #[cfg(feature = "io_uring")]
+    {
+        if io.queue_depth > 1 {
+            if let Ok(a) = super::uring::UringBackend::new(io.queue_depth, io.block_size, align) {
+                return Box::new(a);
+            }
+        }
+    }
+
+    Box::new(SyncBackend::new(io.block_size, align))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use super::super::tests::{
+        quiet,
+        Scratch
+    };
+
+    /// everything `backend` finds wrong with `start..end` of `file`, as LBA ranges
+    pub(crate) fn mismatches(backend: &mut dyn Backend, file: &File, pass: &Pass, start: u64, end: u64) -> Vec<String> {
+        let mut found = Vec::new();
+        backend.check(file, pass, start, end, &mut found, &quiet());
+        LbaRange::merge(&mut found);
+        found.iter().map(|r| r.to_string()).collect()
+    }
+
+    /// writes `pass` over the first `size` bytes of `file` with `backend`
+    fn written(backend: &mut dyn Backend, file: &File, pass: &Pass, size: u64) {
+        let mut outcome = PassOutcome { bytes_written: 0, errors: Vec::new(), bad_ranges: Vec::new(), stopped_at: None };
+        backend.write(file, pass, 0, size, &mut outcome, &quiet());
+        assert_eq!(outcome.bytes_written, size);
+        assert!(outcome.errors.is_empty() && outcome.bad_ranges.is_empty(), "{:?}", outcome.errors);
+    }
+
+    #[test]
+    fn parses_sizes_in_powers_of_1024() {
+        assert_eq!(parse_size("4096").ok(), Some(4096));
+        assert_eq!(parse_size(" 512K").ok(), Some(512 * 1024));
+        assert_eq!(parse_size("4m").ok(), Some(4 * 1024 * 1024));
+        assert_eq!(parse_size("1G").ok(), Some(1024 * 1024 * 1024));
+        assert!(["0", "", "4X", "-1M"].iter().all(|s| parse_size(s).is_err()));
+    }
+
+    #[test]
+    fn buffers_are_zeroed_and_lined_up_for_direct_io() {
+        for (len, align) in [(4096, 512), (65536 + 512, 4096), (1, 0), (1 << 20, 8192)].iter() {
+            let buf = AlignedBuf::new(*len, *align);
+            assert_eq!(buf.len(), (*len).max(1));
+            assert_eq!(buf.as_ptr() as usize % (*align).max(4096), 0);
+            assert!(buf.iter().all(|b| *b == 0));
+        }
+    }
+
+    #[test]
+    fn opens_files_for_writing_with_or_without_direct_io() {
+        let file = Scratch::new("engine-open", 4096);
+        for direct in [true, false].iter() {
+            let io = IoOptions { direct: *direct, ..IoOptions::default() };
+            let (_, got_direct) = open_for_write(&file.name(), &io).expect("Failed to open scratch file");
+            assert!(*direct || !got_direct);
+            open_for_read(&file.name(), &io).expect("Failed to open scratch file");
+        }
+        assert!(open_for_write("/nonexistent/disk", &IoOptions::default()).is_err());
+        assert!(open_for_read("/nonexistent/disk", &IoOptions::default()).is_err());
+    }
+
+    #[test]
+    fn check_finds_a_bad_byte_in_the_trailing_partial_block() {
+        let size = 65536 + 999;
+        let scratch = Scratch::new("engine-partial", size);
+        let pass = Pass::Fixed(0x5A);
+        let mut backend = SyncBackend::new(65536, 512);
+        written(&mut backend, &scratch.file, &pass, size);
+        assert!(mismatches(&mut backend, &scratch.file, &pass, 0, size).is_empty());
+
+        scratch.file.write_all_at(&[0], size - 1).expect("Failed to corrupt scratch file");
+        assert_eq!(mismatches(&mut backend, &scratch.file, &pass, 0, size), vec![((size - 1) / 512).to_string()]);
+        // a check that stops before the damage does not see it
+        assert!(mismatches(&mut backend, &scratch.file, &pass, 0, 65536).is_empty());
+    }
+
+    #[test]
+    fn check_finds_every_bad_sector_in_one_scan() {
+        let size = 4 * 65536;
+        let scratch = Scratch::new("engine-scan", size);
+        let pass = Pass::Fixed(0xFF);
+        let mut backend = SyncBackend::new(65536, 512);
+        written(&mut backend, &scratch.file, &pass, size);
+
+        // a lone sector, a run that crosses a block boundary, and the very last byte
+        scratch.file.write_all_at(&[0; 3], 512 * 7 + 100).expect("Failed to corrupt scratch file");
+        scratch.file.write_all_at(&[0; 2048], 65536 - 1024).expect("Failed to corrupt scratch file");
+        scratch.file.write_all_at(&[0], size - 1).expect("Failed to corrupt scratch file");
+        assert_eq!(mismatches(&mut backend, &scratch.file, &pass, 0, size), vec!["7", "126-129", "511"]);
+    }
+
+    #[test]
+    fn counts_mismatched_and_unreadable_sectors() {
+        let mut found = Vec::new();
+        let expected = [0u8; 2048];
+        let mut read = expected;
+        mismatched_sectors(&read, &expected, 4096, 512, &mut found);
+        assert!(found.is_empty());
+
+        read[600] = 1;
+        read[1100] = 1;
+        mismatched_sectors(&read, &expected, 4096, 512, &mut found);
+        // the tail of a read that could not be done follows straight on
+        unreadable_sectors(4096 + 1536, 1000, 512, &mut found);
+        assert_eq!(found.iter().map(|r| r.to_string()).collect::<Vec<String>>(), vec!["9-12"]);
+    }
+
+    #[test]
+    fn writes_that_keep_failing_are_split_down_to_single_sectors() {
+        let scratch = Scratch::new("engine-readonly", 4096);
+        let readonly = File::open(&scratch.path).expect("Failed to open scratch file");
+        let mut outcome = PassOutcome { bytes_written: 0, errors: Vec::new(), bad_ranges: Vec::new(), stopped_at: None };
+        let error = std::io::Error::from_raw_os_error(libc::EIO);
+        rewrite_in_pieces(&readonly, &[0xFF; 2048], 1024, 512, error, &mut outcome, &quiet());
+
+        assert_eq!(outcome.bytes_written, 0);
+        assert_eq!(outcome.bad_ranges.iter().map(|r| r.to_string()).collect::<Vec<String>>(), vec!["2", "3", "4", "5"]);
+        assert_eq!(outcome.errors.len(), 1);
+        assert!(outcome.errors[0].contains("4 sector(s) could not be written"), "{:?}", outcome.errors);
+    }
+
+    #[test]
+    fn random_passes_are_checked_against_their_keystream() {
+        let size = 2 * 65536 + 4096;
+        let scratch = Scratch::new("engine-random", size);
+        let seed = [4; super::super::pattern::SEED_LEN];
+        let mut backend = SyncBackend::new(65536, 512);
+        written(&mut backend, &scratch.file, &Pass::random(seed, 2), size);
+
+        assert!(mismatches(&mut backend, &scratch.file, &Pass::random(seed, 2), 0, size).is_empty());
+        // another stream, or another seed, is not what was written
+        assert_eq!(mismatches(&mut backend, &scratch.file, &Pass::random(seed, 3), 0, size), vec!["0-263"]);
+        assert_eq!(mismatches(&mut backend, &scratch.file, &Pass::random([5; 32], 2), 65536, size), vec!["128-263"]);
+    }
+}
@@ -0,0 +1,116 @@
+use std::fmt::Display;
+use super::record::LbaRange;
+
+/// what went wrong, in enough detail for a program to react to it
+#[derive (Clone)]
+pub enum Error {
+    /// the disks could not be found or described
+    Discovery(String),
+    /// we are not allowed to touch something, usually because we are not root
+    Permission(String),
+    /// the disk is mounted or otherwise in use
+    Busy(String),
+    /// reading or writing failed, with where on the disk if that is known
+    Io {
+        offset: Option<u64>,
+        message: String
+    },
+    /// the sectors in `ranges` of the disk at `path` do not hold what was written there
+    Mismatch {
+        path: String,
+        ranges: Vec<LbaRange>
+    },
+    /// the options given do not make sense
+    Invalid(String),
+    /// the user, or a signal, stopped us
+    Aborted,
+    /// something that should never happen did, such as a wipe thread panicking
+    Internal(String)
+}
+
+impl Error {
+    /// sorts a failed system call into the right kind of error, with
+    /// `message` saying what we were trying to do
+    pub fn from_io(error: &std::io::Error, message: String) -> Self {
+        match (error.kind(), error.raw_os_error()) {
+            (std::io::ErrorKind::PermissionDenied, _) => Error::Permission(message),
+            (_, Some(nix::libc::EBUSY)) => Error::Busy(message),
+            _ => Error::Io { offset: None, message }
+        }
+    }
+
+    /// a short name for the kind of error, for machine readable output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Discovery(_) => "discovery",
+            Error::Permission(_) => "permission",
+            Error::Busy(_) => "busy",
+            Error::Io { .. } => "io",
+            Error::Mismatch { .. } => "mismatch",
+            Error::Invalid(_) => "invalid",
+            Error::Aborted => "aborted",
+            Error::Internal(_) => "internal"
+        }
+    }
+
+    /// the exit code the command line gives for the error, so scripts can
+    /// tell what went wrong without reading the message
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Internal(_) => 1,
+            Error::Invalid(_) => 2,
+            Error::Discovery(_) => 3,
+            Error::Permission(_) => 4,
+            Error::Busy(_) => 5,
+            Error::Io { .. } => 6,
+            Error::Mismatch { .. } => 7,
+            Error::Aborted => 130
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt (&self, fmt: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
+        match self {
+            Error::Discovery(message) | Error::Permission(message) | Error::Busy(message)
+                | Error::Invalid(message) | Error::Internal(message) => write!(fmt, "{}", message),
+            Error::Io { message, .. } => write!(fmt, "{}", message),
+            Error::Mismatch { path, ranges } if ranges.is_empty() => write!(fmt, "{} could not be checked", path),
+            Error::Mismatch { path, ranges } => write!(fmt, "{} of {} did not hold what was written", LbaRange::describe(ranges), path),
+            Error::Aborted => write!(fmt, "Stopped before finishing")
+        }
+    }
+}
+
+impl std::fmt::Debug for Error {
+    fn fmt (&self, fmt: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
+        write!(fmt, "{}", self)
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sorts_failed_system_calls_by_what_went_wrong() {
+        let error = |e: std::io::Error| Error::from_io(&e, "Failed".to_string());
+        let denied = error(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        assert_eq!((denied.kind(), denied.exit_code()), ("permission", 4));
+        let busy = error(std::io::Error::from_raw_os_error(nix::libc::EBUSY));
+        assert_eq!((busy.kind(), busy.exit_code()), ("busy", 5));
+        let io = error(std::io::Error::from_raw_os_error(nix::libc::EIO));
+        assert_eq!((io.kind(), io.exit_code(), io.to_string()), ("io", 6, "Failed".to_string()));
+    }
+
+    #[test]
+    fn says_which_sectors_did_not_match() {
+        let error = Error::Mismatch { path: "/dev/sdx".to_string(), ranges: vec![LbaRange { first: 8, last: 9 }] };
+        assert_eq!((error.kind(), error.exit_code()), ("mismatch", 7));
+        assert_eq!(error.to_string(), "2 sector(s) at LBA 8-9 of /dev/sdx did not hold what was written");
+        let error = Error::Mismatch { path: "/dev/sdx".to_string(), ranges: Vec::new() };
+        assert_eq!(error.to_string(), "/dev/sdx could not be checked");
+    }
+}
@@ -0,0 +1,211 @@
+use std::io::Write;
+use serde::{
+    Serialize,
+    Deserialize
+};
+use super::{
+    defs::DiskData,
+    methods::WipePass,
+    record::WipeRecord,
+    error::Error
+};
+
+/// where journals go unless told otherwise. this has to live somewhere other
+/// than the disk being wiped, so we keep it with the rest of the system state
+pub const JOURNAL_DIR: &str = "/var/lib/checked_wipe";
+
+/// where `path` really leads, or `path` itself if it leads nowhere
+fn canonical(path: &str) -> String {
+    match std::fs::canonicalize(path) {
+        Ok(a) => a.to_string_lossy().into_owned(),
+        Err(_) => path.to_string()
+    }
+}
+
+/// a checkpoint of a wipe in progress, saved as it goes so an interrupted
+/// wipe can carry on where it left off instead of starting over
+#[derive (Clone, Serialize, Deserialize)]
+pub struct Journal {
+    /// the record of the wipe so far, with the disk, method and finished passes
+    pub record: WipeRecord,
+    /// every pass of the wipe, so a resumed wipe writes exactly the same data
+    pub plan: Vec<WipePass>,
+    /// the index in `plan` of the pass that was running
+    pub pass: usize,
+    /// how much of that pass was written and synced to the disk
+    pub offset: u64,
+    pub updated: String,
+    #[serde(skip)]
+    path: String
+}
+
+impl Journal {
+    /// starts a journal at `path` for the wipe described by `record`
+    pub fn new(path: String, record: WipeRecord, plan: Vec<WipePass>) -> Self {
+        Journal {
+            record,
+            plan,
+            pass: 0,
+            offset: 0,
+            updated: chrono::offset::Local::now().to_rfc3339(),
+            path
+        }
+    }
+
+    /// where the journal for `disk` goes by default, named after its serial
+    /// (or WWN, or path) so wipes of different disks do not collide
+    pub fn default_path(disk: &DiskData) -> String {
+        format!("{}/{}.journal", JOURNAL_DIR, disk.file_stem())
+    }
+
+    /// the file this journal is saved to
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// what to look the disk up by when resuming. the serial and WWN belong to
+    /// the drive itself, so they still find it if it comes back under a new path
+    pub fn disk_spec(&self) -> &str {
+        let disk = &self.record.disk;
+        disk.serial.as_deref()
+            .or(disk.wwn.as_deref())
+            .unwrap_or(&disk.path)
+    }
+
+    /// whether `disk` is the same drive this journal was started on. a drive
+    /// that reported no serial or WWN has nothing else to tell it from
+    /// another of the same size, so it has to be at the same path as well
+    pub fn matches(&self, disk: &DiskData) -> bool {
+        let journal = &self.record.disk;
+        let same_drive = if journal.serial.is_none() && journal.wwn.is_none() {
+            canonical(&journal.path) == canonical(&disk.path)
+        } else {
+            journal.serial == disk.serial && journal.wwn == disk.wwn
+        };
+        same_drive && journal.bytes == disk.bytes
+    }
+
+    /// records that pass `pass` has been durably written up to `offset` and saves
+    pub fn checkpoint(&mut self, pass: usize, offset: u64) -> Result<(), Error> {
+        self.pass = pass;
+        self.offset = offset;
+        self.updated = chrono::offset::Local::now().to_rfc3339();
+        self.save()
+    }
+
+    /// writes the journal out. it goes to a temporary file first and is then
+    /// renamed over the old one, so a crash never leaves a half written journal
+    pub fn save(&self) -> Result<(), Error> {
+        let json = match serde_json::to_string_pretty(self) {
+            Ok(a) => a,
+            Err(e) => return Err(Error::Internal(format!("Failed to serialize journal: {}", e)))
+        };
+        if let Some(dir) = std::path::Path::new(&self.path).parent() {
+            if let Err(e) = std::fs::create_dir_all(dir) {
+                return Err(Error::from_io(&e, format!("Failed to create {}: {}", dir.display(), e)));
+            }
+        }
+
+        let tmp = format!("{}.tmp", self.path);
+        let mut file = match std::fs::File::create(&tmp) {
+            Ok(a) => a,
+            Err(e) => return Err(Error::from_io(&e, format!("Failed to write journal {}: {}", tmp, e)))
+        };
+        if let Err(e) = file.write_all(json.as_bytes()).and_then(|_| file.sync_all()) {
+            return Err(Error::from_io(&e, format!("Failed to write journal {}: {}", tmp, e)));
+        }
+        match std::fs::rename(&tmp, &self.path) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::from_io(&e, format!("Failed to write journal {}: {}", self.path, e)))
+        }
+    }
+
+    /// reads a journal previously saved to `path`
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let json = match std::fs::read_to_string(path) {
+            Ok(a) => a,
+            Err(e) => return Err(Error::from_io(&e, format!("Failed to read journal {}: {}", path, e)))
+        };
+        let mut journal: Journal = match serde_json::from_str(&json) {
+            Ok(a) => a,
+            Err(e) => return Err(Error::Invalid(format!("Failed to parse journal {}: {}", path, e)))
+        };
+        if journal.pass > journal.plan.len() {
+            return Err(Error::Invalid(format!("Journal {} is at pass {} of a {} pass wipe", path, journal.pass + 1, journal.plan.len())));
+        }
+        journal.path = path.to_string();
+
+        Ok(journal)
+    }
+
+    /// deletes the journal once the wipe it tracks is over
+    pub fn remove(&self) -> Result<(), Error> {
+        match std::fs::remove_file(&self.path) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::from_io(&e, format!("Failed to remove journal {}: {}", self.path, e)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{
+        defs::tests::disk,
+        methods::simple_plan,
+        tests::Scratch
+    };
+
+    #[test]
+    fn loads_the_checkpoint_it_saved_for_the_same_drive() {
+        let drive = disk("/dev/sdx", Some("SERIAL1"), None);
+        let file = Scratch::new("checkpoint.journal", 0);
+        let mut journal = Journal::new(file.name(), WipeRecord::new(&drive, "test".to_string(), None), simple_plan(3, Some([1; 32])));
+        journal.checkpoint(1, 65536).expect("Failed to save journal");
+
+        let saved = Journal::load(&file.name()).expect("Failed to load journal");
+        assert_eq!((saved.pass, saved.offset, saved.plan.len(), saved.path()), (1, 65536, 3, &file.name()[..]));
+        assert_eq!(saved.disk_spec(), "SERIAL1");
+        assert!(saved.matches(&drive));
+        // the same path is not enough if the drive in it has changed
+        assert!(!saved.matches(&disk("/dev/sdx", Some("SERIAL2"), None)));
+
+        saved.remove().expect("Failed to remove journal");
+        assert_eq!(Journal::load(&file.name()).err().map(|e| e.kind()), Some("io"));
+    }
+
+    #[test]
+    fn drives_without_a_serial_or_wwn_are_told_apart_by_their_path() {
+        let image = Scratch::new("anonymous.img", 4096);
+        let links = Scratch::dir("anonymous-links");
+        std::os::unix::fs::symlink(&image.path, links.path.join("image")).expect("Failed to link image");
+        let drive = disk(&image.name(), None, None);
+        let journal = Journal::new(String::new(), WipeRecord::new(&drive, "test".to_string(), None), simple_plan(1, None));
+
+        assert!(journal.matches(&drive));
+        assert!(journal.matches(&disk(&links.path.join("image").to_string_lossy(), None, None)));
+        // another disk of the same size is not the one the wipe was started on
+        assert!(!journal.matches(&disk("/dev/sdy", None, None)));
+        let mut smaller = drive.clone();
+        smaller.bytes -= 512;
+        assert!(!journal.matches(&smaller));
+    }
+
+    #[test]
+    fn refuses_journals_that_do_not_make_sense() {
+        let file = Scratch::holding("broken.journal", b"{\"pass\": 1");
+        assert_eq!(Journal::load(&file.name()).err().map(|e| e.kind()), Some("invalid"));
+
+        // further along than the wipe has passes
+        let mut journal = Journal::new(file.name(), WipeRecord::new(&disk("/dev/sdx", None, None), "test".to_string(), None), simple_plan(1, None));
+        journal.pass = 2;
+        journal.save().expect("Failed to save journal");
+        assert_eq!(Journal::load(&file.name()).err().map(|e| e.kind()), Some("invalid"));
+    }
+
+    #[test]
+    fn default_journals_are_named_after_the_drive() {
+        assert_eq!(Journal::default_path(&disk("/dev/sdx", Some("WD-1234 5678"), None)), format!("{}/WD-1234_5678.journal", JOURNAL_DIR));
+        assert_eq!(Journal::default_path(&disk("/dev/sdx", None, None)), format!("{}/dev_sdx.journal", JOURNAL_DIR));
+    }
+}
@@ -0,0 +1,263 @@
+use super::{
+    pattern::{
+        Pass,
+        SEED_LEN,
+        parse_pattern,
+        load_pattern_file
+    },
+    error::Error
+};
+use serde::{
+    Serialize,
+    Deserialize
+};
+
+/// describes what a pass writes before it is tied to a particular seed
+#[derive (Clone, Copy)]
+pub enum PassKind {
+    /// a single repeated byte
+    Fixed(u8),
+    /// a repeating multi-byte pattern
+    Pattern(&'static [u8]),
+    /// seeded pseudo-random data
+    Random,
+    /// the complement of the previous pass
+    Complement
+}
+
+/// a single pass of a wipe method, and whether the method wants it verified
+#[derive (Clone, Copy)]
+pub struct PassDef {
+    pub kind: PassKind,
+    pub verify: bool
+}
+
+/// a named wipe standard
+pub struct Method {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub passes: &'static [PassDef]
+}
+
+/// a pass ready to be run against a disk
+#[derive (Clone, Serialize, Deserialize)]
+pub struct WipePass {
+    pub pass: Pass,
+    pub verify: bool
+}
+
+const fn fixed(byte: u8) -> PassDef {
+    PassDef { kind: PassKind::Fixed(byte), verify: false }
+}
+
+const fn pattern(bytes: &'static [u8]) -> PassDef {
+    PassDef { kind: PassKind::Pattern(bytes), verify: false }
+}
+
+const RANDOM: PassDef = PassDef { kind: PassKind::Random, verify: false };
+
+/// the same pass, read back afterwards to check it
+const fn verified(def: PassDef) -> PassDef {
+    PassDef { kind: def.kind, verify: true }
+}
+
+/// all of the wipe standards we know about
+pub const METHODS: &[Method] = &[
+    Method {
+        name: "dod",
+        description: "DoD 5220.22-M: zeros, ones, random, verify",
+        passes: &[
+            fixed(0x00),
+            PassDef { kind: PassKind::Complement, verify: false },
+            PassDef { kind: PassKind::Random, verify: true }
+        ]
+    },
+    Method {
+        name: "gutmann",
+        description: "Gutmann: 4 random, 27 MFM/RLL patterns, 4 random, verify",
+        passes: &[
+            RANDOM, RANDOM, RANDOM, RANDOM,
+            fixed(0x55), fixed(0xAA),
+            pattern(&[0x92, 0x49, 0x24]), pattern(&[0x49, 0x24, 0x92]), pattern(&[0x24, 0x92, 0x49]),
+            fixed(0x00), fixed(0x11), fixed(0x22), fixed(0x33),
+            fixed(0x44), fixed(0x55), fixed(0x66), fixed(0x77),
+            fixed(0x88), fixed(0x99), fixed(0xAA), fixed(0xBB),
+            fixed(0xCC), fixed(0xDD), fixed(0xEE), fixed(0xFF),
+            pattern(&[0x92, 0x49, 0x24]), pattern(&[0x49, 0x24, 0x92]), pattern(&[0x24, 0x92, 0x49]),
+            pattern(&[0x6D, 0xB6, 0xDB]), pattern(&[0xB6, 0xDB, 0x6D]), pattern(&[0xDB, 0x6D, 0xB6]),
+            RANDOM, RANDOM, RANDOM, verified(RANDOM)
+        ]
+    },
+    Method {
+        name: "schneier",
+        description: "Schneier: ones, zeros, 5 random, verify",
+        passes: &[
+            fixed(0xFF), fixed(0x00),
+            RANDOM, RANDOM, RANDOM, RANDOM, verified(RANDOM)
+        ]
+    },
+    Method {
+        name: "nist-clear",
+        description: "NIST 800-88 Clear: zeros, verify",
+        passes: &[
+            PassDef { kind: PassKind::Fixed(0x00), verify: true }
+        ]
+    }
+];
+
+/// the names of all known methods, for the command line
+pub const METHOD_NAMES: &[&str] = &method_names();
+
+/// takes the name of each of `METHODS`, so the two lists cannot disagree
+const fn method_names() -> [&'static str; METHODS.len()] {
+    let mut names = [""; METHODS.len()];
+    let mut i = 0;
+    while i < METHODS.len() {
+        names[i] = METHODS[i].name;
+        i += 1;
+    }
+    names
+}
+
+
+impl Method {
+    /// turns the method's pass definitions into passes using `seed` for
+    /// the random ones. each random pass gets its own stream
+    pub fn plan(&self, seed: [u8; SEED_LEN]) -> Result<Vec<WipePass>, Error> {
+        let mut plan: Vec<WipePass> = Vec::new();
+        for (i, def) in self.passes.iter().enumerate() {
+            let pass = match def.kind {
+                PassKind::Fixed(byte) => Pass::Fixed(byte),
+                PassKind::Pattern(bytes) => Pass::Pattern(bytes.to_vec()),
+                PassKind::Random => Pass::random(seed, i as u64),
+                PassKind::Complement => match plan.last() {
+                    Some(prev) => Pass::Complement(Box::new(prev.pass.clone())),
+                    None => return Err(Error::Invalid(format!("method {} starts with a complement pass", self.name)))
+                }
+            };
+            plan.push(WipePass { pass, verify: def.verify });
+        }
+
+        Ok(plan)
+    }
+}
+
+
+/// looks up a wipe method by name
+pub fn find_method(name: &str) -> Option<&'static Method> {
+    METHODS.iter().find(|m| m.name == name)
+}
+
+/// builds the plain `loops` pass plan, zeros unless `seed` is given, in
+/// which case every pass is random. only the final pass is verified
+pub fn simple_plan(loops: usize, seed: Option<[u8; SEED_LEN]>) -> Vec<WipePass> {
+    let mut plan: Vec<WipePass> = Vec::new();
+    for i in 0..loops {
+        let pass = match seed {
+            Some(s) => Pass::random(s, i as u64),
+            None => Pass::Fixed(0)
+        };
+        plan.push(WipePass { pass, verify: i + 1 == loops });
+    }
+
+    plan
+}
+
+/// builds a plan from a comma separated list of passes given by the user.
+/// each entry is `random`, `complement` (of the previous pass), a hex byte
+/// or pattern such as `0xFF` or `0x55AA`, or `@<file>` to repeat the contents
+/// of a pattern file. only the final pass is verified
+pub fn custom_plan(spec: &str, seed: [u8; SEED_LEN]) -> Result<Vec<WipePass>, Error> {
+    let mut plan: Vec<WipePass> = Vec::new();
+    for (i, entry) in spec.split(',').map(|e| e.trim()).enumerate() {
+        let pass = if entry == "random" {
+            Pass::random(seed, i as u64)
+        } else if entry == "complement" {
+            match plan.last() {
+                Some(prev) => Pass::Complement(Box::new(prev.pass.clone())),
+                None => return Err(Error::Invalid("the first pass cannot be a complement".to_string()))
+            }
+        } else if let Some(path) = entry.strip_prefix('@') {
+            Pass::Pattern(load_pattern_file(path)?)
+        } else {
+            let pattern = parse_pattern(entry)?;
+            if pattern.len() == 1 {
+                Pass::Fixed(pattern[0])
+            } else {
+                Pass::Pattern(pattern)
+            }
+        };
+        plan.push(WipePass { pass, verify: false });
+    }
+
+    if let Some(last) = plan.last_mut() {
+        last.verify = true;
+    }
+
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tests::Scratch;
+
+    /// what each pass of `plan` writes, and whether it gets checked
+    fn described(plan: &[WipePass]) -> Vec<(String, bool)> {
+        plan.iter().map(|p| (p.pass.to_string(), p.verify)).collect()
+    }
+
+    #[test]
+    fn plans_each_pass_of_a_method_with_its_own_stream() {
+        let dod = find_method("dod").expect("dod is a known method");
+        let plan = dod.plan([1; SEED_LEN]).expect("Failed to plan dod");
+        assert_eq!(described(&plan), vec![("zeros".to_string(), false),
+            ("complement of zeros".to_string(), false), ("random (stream 2)".to_string(), true)]);
+        let mut buf = [0; 8];
+        plan[1].pass.fill(&mut buf, 12345);
+        assert_eq!(buf, [0xFF; 8]);
+
+        let gutmann = find_method("gutmann").expect("gutmann is a known method").plan([1; SEED_LEN])
+            .expect("Failed to plan gutmann");
+        assert_eq!(gutmann.len(), 35);
+        assert_eq!(gutmann[34].pass.to_string(), "random (stream 34)");
+        assert!(find_method("shred").is_none());
+    }
+
+    #[test]
+    fn simple_plans_only_check_their_last_pass() {
+        assert_eq!(described(&simple_plan(2, None)), vec![("zeros".to_string(), false), ("zeros".to_string(), true)]);
+        assert_eq!(described(&simple_plan(1, Some([0; SEED_LEN]))), vec![("random (stream 0)".to_string(), true)]);
+    }
+
+    #[test]
+    fn custom_plans_take_bytes_patterns_random_complements_and_files() {
+        let file = Scratch::holding("pattern", &[0xDE, 0xAD, 0xBE]);
+        let plan = custom_plan(&format!("0x00, 0x55AA,random,complement,@{}", file.name()), [2; SEED_LEN])
+            .expect("Failed to plan passes");
+        assert_eq!(described(&plan), vec![("zeros".to_string(), false), ("0x55AA".to_string(), false),
+            ("random (stream 2)".to_string(), false), ("complement of random (stream 2)".to_string(), false),
+            ("0xDEADBE".to_string(), true)]);
+        assert!(plan[3].pass.uses_seed() && !plan[4].pass.uses_seed());
+
+        let empty = Scratch::new("empty-pattern", 0);
+        for spec in ["complement,0x00", "0xZZ", "55", "0x123", "", "@/nonexistent/pattern"].iter() {
+            assert!(custom_plan(spec, [2; SEED_LEN]).is_err(), "{} was accepted", spec);
+        }
+        assert!(custom_plan(&format!("@{}", empty.name()), [2; SEED_LEN]).is_err());
+    }
+
+    #[test]
+    fn every_method_is_named_and_checks_its_final_pass() {
+        assert_eq!(METHOD_NAMES, METHODS.iter().map(|m| m.name).collect::<Vec<&str>>().as_slice());
+        for method in METHODS.iter() {
+            let plan = method.plan([1; SEED_LEN]).unwrap_or_else(|e| panic!("{} did not plan: {}", method.name, e));
+            assert_eq!(plan.len(), method.passes.len());
+            assert!(plan.last().map(|p| p.verify).unwrap_or(false), "{} never verifies its final pass", method.name);
+            assert!(find_method(method.name).is_some());
+        }
+
+        let broken = Method { name: "broken", description: "", passes: &[PassDef { kind: PassKind::Complement, verify: true }] };
+        assert_eq!(broken.plan([1; SEED_LEN]).err().map(|e| e.kind()), Some("invalid"));
+    }
+}
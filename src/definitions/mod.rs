@@ -1,208 +1,759 @@
 use std::{
-    io::{
-        prelude::*,
+    fs::File,
+    ops::Range,
+    os::unix::{
+        fs::FileTypeExt,
+        io::AsRawFd
     },
-    os::unix::io::AsRawFd
-};
-use indicatif::{
-    ProgressBar,
-    ProgressStyle
+    sync::atomic::{
+        AtomicU8,
+        Ordering
+    },
+    time::{
+        Duration,
+        Instant
+    }
 };
-use nix;
+use indicatif::ProgressBar;
 
+mod sysfs;
+mod probe;
+mod safety;
+pub use safety::Hazard;
 mod defs;
 pub use defs::{
     DiskData,
+    find_disk,
+    find_target,
+    list_disks,
     parse_partitions,
     print_top_levels
 };
+mod pattern;
+pub use pattern::{
+    Pass,
+    new_seed,
+    parse_seed,
+    seed_to_hex,
+    SEED_LEN
+};
+mod record;
+pub use record::{
+    WipeRecord,
+    PassRecord,
+    DiskIdentity,
+    SampleRecord,
+    LbaRange
+};
+mod sample;
+pub use sample::{
+    VerifyMode,
+    SamplePlan
+};
+mod certificate;
+pub use certificate::{
+    Certificate,
+    SignedCertificate,
+    current_operator,
+    load_or_create_key,
+    public_key_hex,
+    DEFAULT_KEY_PATH
+};
+mod engine;
+use engine::{
+    Backend,
+    open_backend,
+    open_for_read,
+    open_for_write
+};
+#[cfg(feature = "io_uring")]
+mod uring;
+pub use engine::{
+    IoOptions,
+    parse_size,
+    uring_support
+};
+mod error;
+pub use error::Error;
+mod journal;
+pub use journal::{
+    Journal,
+    JOURNAL_DIR
+};
+mod methods;
+pub use methods::{
+    Method,
+    WipePass,
+    METHODS,
+    METHOD_NAMES,
+    find_method,
+    simple_plan,
+    custom_plan
+};
+mod cancel;
+pub use cancel::CancelToken;
+mod progress;
+pub use progress::{
+    Event,
+    Stage,
+    Level,
+    ProgressSink,
+    TerminalSink,
+    JsonLinesSink,
+    SilentSink
+};
+use progress::Tracker;
+mod wiper;
+pub use wiper::{
+    Wiper,
+    WipeHandle
+};
+
+/// where messages and progress meant for a person go
+#[derive (Clone, Copy, PartialEq)]
+pub enum Output {
+    /// nowhere, which is what a program embedding the library gets unless it asks
+    Silent = 0,
+    /// stdout, with colors and progress bars
+    Terminal = 1,
+    /// stderr without colors or progress bars, so stdout is left for machine
+    /// readable output
+    Machine = 2
+}
+
+static OUTPUT: AtomicU8 = AtomicU8::new(Output::Silent as u8);
+
+/// picks where messages meant for a person go
+pub fn set_output(output: Output) {
+    OUTPUT.store(output as u8, Ordering::Relaxed);
+    if output == Output::Machine {
+        colored::control::set_override(false);
+    }
+}
+
+/// where messages meant for a person go
+pub fn output() -> Output {
+    match OUTPUT.load(Ordering::Relaxed) {
+        1 => Output::Terminal,
+        2 => Output::Machine,
+        _ => Output::Silent
+    }
+}
+
+/// whether we are producing machine readable output
+pub fn machine_output() -> bool {
+    output() == Output::Machine
+}
+
+/// creates a progress bar for a disk, or a hidden one unless we are drawing
+/// on a terminal. a `TerminalSink` sizes and styles it afresh for each pass
+pub fn new_bar() -> ProgressBar {
+    if output() != Output::Terminal {
+        return ProgressBar::hidden();
+    }
+
+    ProgressBar::new(0)
+}
+
+/// what happened while writing a single pass
+pub struct PassOutcome {
+    pub bytes_written: u64,
+    pub errors: Vec<String>,
+    /// sectors that could not be written and were skipped
+    pub bad_ranges: Vec<LbaRange>,
+    /// where writing stopped, if the pass was cancelled before the end. all
+    /// of it before there has been synced
+    pub stopped_at: Option<u64>
+}
+
+/// the size of the disk at `path`. block devices are asked with the
+/// BLKGETSIZE64 ioctl, while image files go by their metadata
+fn get_drive_size(path: &str) -> Result<u64, Error> {
+    let fs = match File::open(path) {
+        Ok(a) => a,
+        Err(e) => return Err(Error::from_io(&e, format!("Failed to open {} for reading: {}", path, e)))
+    };
+    let meta = match fs.metadata() {
+        Ok(a) => a,
+        Err(e) => return Err(Error::from_io(&e, format!("Failed to read the metadata of {}: {}", path, e)))
+    };
+    if meta.is_file() {
+        return Ok(meta.len());
+    }
+    if !meta.file_type().is_block_device() {
+        return Err(Error::Invalid(format!("{} is neither a block device nor a regular file", path)));
+    }
 
-fn get_drive_size(path: String) -> usize {
     // prep the IOCTL call
-    let fs = std::fs::File::open(path).expect("Failed to open disk for reading");
     const SPI_IOC_MAGIC: u8 = 0x12;
     const SPI_IOC_TYPE_MODE: u8 = 114;
     nix::ioctl_read!(blkgetsize64, SPI_IOC_MAGIC, SPI_IOC_TYPE_MODE, u64);
     let mut fssize: u64 = 0;
     
     // run ioctl(path, BLKGETSIZE64, out)
-    let out = unsafe { blkgetsize64(fs.as_raw_fd(), &mut fssize) };
-    assert_eq!{out.unwrap(), 0};
+    match unsafe { blkgetsize64(fs.as_raw_fd(), &mut fssize) } {
+        Ok(_) => Ok(fssize),
+        Err(e) => Err(Error::Io { offset: None, message: format!("Failed to get the size of {}: {}", path, e) })
+    }
+}
 
-    fssize as usize 
+/// `io` as it has to be for a disk of `size` bytes. direct I/O can only move
+/// whole sectors, so an image file that ends part way through one has to go
+/// through the page cache instead
+fn io_for(disk: &DiskData, size: u64, io: &IoOptions) -> IoOptions {
+    let mut io = *io;
+    if io.direct && !size.is_multiple_of(disk.logical_block_size.max(1)) {
+        io.direct = false;
+    }
+    io
 }
 
 
-/// zeros the drive referred to by `disk
-pub fn zero_drive(disk: &DiskData) -> Result<(), String> {
-    // first get the file's size
-    let fsize = get_drive_size(disk.path.clone()); 
-    let write_loop_ctr = fsize / (1024*1024*1024);
-    let final_write = fsize % 1024;
+/// how much of a pass gets written between syncs, each of which is followed
+/// by a checkpoint: a stretch ends after `bytes` or once `interval` has gone
+/// by, whichever comes first, so a slow disk still gets checkpointed often
+#[derive (Clone, Copy)]
+struct CheckpointLimits {
+    bytes: u64,
+    interval: Duration
+}
+
+const CHECKPOINT: CheckpointLimits = CheckpointLimits { bytes: 1024 * 1024 * 1024, interval: Duration::from_secs(30) };
+
+/// overwrites the drive referred to by `disk` with the data defined by `pass`,
+/// starting `start` bytes in and writing the way `io` asks, with progress
+/// sent to `progress`. `checkpoint` is called with the offset every time
+/// everything before it has been synced. if `cancel` is cancelled the pass
+/// stops at the next block, is synced and checkpointed, and says where it stopped
+pub fn overwrite_drive(disk: &DiskData, pass: &Pass, start: u64, io: &IoOptions, progress: &dyn ProgressSink, cancel: &CancelToken, checkpoint: &mut dyn FnMut(u64)) -> Result<PassOutcome, Error> {
+    // first get the file's size, and make sure we can line writes up with its blocks
+    let fsize = get_drive_size(&disk.path)?;
+    let block = disk.logical_block_size.max(1);
+    if !(io.block_size as u64).is_multiple_of(block) {
+        return Err(Error::Invalid(format!("Block size {} is not a multiple of the logical block size of {} ({})",
+            io.block_size, disk.path, block)));
+    }
+    if !start.is_multiple_of(block) {
+        return Err(Error::Invalid(format!("Offset {} is not a multiple of the logical block size of {} ({})",
+            start, disk.path, block)));
+    }
 
     // open the file and prep variables
-    let mut drive_handle = std::fs::File::create(disk.path.clone()).expect("Failed to open disk for writing");
-    let write_buf: [u8; 1024*1024] = [0;1024*1024];
-    
-    // initialize a progress bar
-    let bar = ProgressBar::new(fsize as u64);
-    bar.set_style(ProgressStyle::default_bar()
-                .template("[{elapsed_precise}] [{bar:40.green/red}] {pos:>7}/{len:7} bytes")
-                .progress_chars("##-"));
-    
-    // loop until disk is fully written
-    for _ in 0..write_loop_ctr as u64 {
-        for _ in 0..1024 {
-            match drive_handle.write_all(&write_buf){
-                Ok(_) => (),
-                Err(e) => println!("[-] Hit write error: {}", e)
-            };
-            
-            // increment the progress bar
-            bar.inc(1024 * 1024);
-        }
-        
-        // need to flush the drive file so we dont pretend we 
-        // are writing faster than we actually are
-        drive_handle.flush().unwrap(); 
+    let io = &io_for(disk, fsize, io);
+    let (drive_handle, direct) = open_for_write(&disk.path, io)?;
+    if io.direct && !direct {
+        progress.event(&Event::Message { disk: disk.path.clone(), level: Level::Warning,
+            text: "[ ] Direct I/O is not supported, writing through the page cache instead".to_string() });
     }
+    let mut backend = open_backend(io, block as usize);
+    let tracker = Tracker::new(progress, cancel, &disk.path, Stage::Writing, fsize, start);
+    Ok(write_pass(&drive_handle, backend.as_mut(), pass, start..fsize, CHECKPOINT, &tracker, checkpoint))
+}
 
-    // write whatever last bytes need to be written
-    for _ in 0..final_write {
-        match drive_handle.write(&[0]) {
-            Ok(_) => (),
-            Err(e) => println!("[-] Hit write error: {}", e)
+/// writes `pass` over `range` of `file` a stretch at a time, as `limits`
+/// says, syncing after each (and at the end of the pass) so we never claim to
+/// have written more than the device really has. the pass fails unless every
+/// byte of the range was written, or up to where it was cancelled
+fn write_pass(file: &File, backend: &mut dyn Backend, pass: &Pass, range: Range<u64>, limits: CheckpointLimits, tracker: &Tracker, checkpoint: &mut dyn FnMut(u64)) -> PassOutcome {
+    let mut outcome = PassOutcome { bytes_written: 0, errors: Vec::new(), bad_ranges: Vec::new(), stopped_at: None };
+    let mut offset = range.start;
+    while offset < range.end {
+        let end = std::cmp::min(offset + limits.bytes, range.end);
+        tracker.checkpoint_by(Some(Instant::now() + limits.interval));
+        let reached = backend.write(file, pass, offset, end, &mut outcome, tracker);
+        match file.sync_data() {
+            Ok(_) => checkpoint(reached),
+            Err(e) => {
+                tracker.error(reached, format!("Sync error: {}", e));
+                outcome.errors.push(format!("sync error at offset {}: {}", reached, e));
+            }
+        }
+        // short of the end means it was cancelled, it was time for a
+        // checkpoint, or the backend gave up and has said why in `outcome`.
+        // only a checkpoint that got somewhere carries on from `reached`
+        if reached < end {
+            if tracker.cancelled() {
+                outcome.stopped_at = Some(reached);
+                break;
+            }
+            if reached == offset || !tracker.checkpoint_due() {
+                break;
+            }
         }
-        bar.inc(1);
+        offset = reached;
     }
+    tracker.checkpoint_by(None);
+    tracker.report();
+    LbaRange::merge(&mut outcome.bad_ranges);
 
-    bar.finish();
-    
-    Ok(())
+    let expected = outcome.stopped_at.unwrap_or(range.end).saturating_sub(range.start);
+    if outcome.bytes_written != expected {
+        outcome.errors.push(format!("wrote {} of the {} bytes from offset {} to {}",
+            outcome.bytes_written, expected, range.start, range.end));
+    }
+    outcome
 }
 
-/// zeroes a disk from a given offset
-pub fn zero_check_from(disk: &DiskData, offset: usize) -> Result<(), String> {
-    // first get the file's size
-    let fsize = get_drive_size(disk.path.clone()); 
-    let write_loop_ctr = fsize / (1024*1024*1024);
-    let final_write = fsize % 1024;
+/// the bytes of a disk of `size` bytes covered by `ranges` of `sector` byte sectors
+fn sector_bytes(ranges: &[LbaRange], sector: u64, size: u64) -> Vec<(u64, u64)> {
+    ranges.iter()
+        .map(|r| (r.first * sector, std::cmp::min((r.last + 1) * sector, size)))
+        .filter(|(start, end)| start < end)
+        .collect()
+}
 
-    // open the file and prep variables
-    let mut drive_handle = std::fs::File::create(disk.path.clone()).expect("Failed to open disk for writing");
-    // seek to the proper spot in the file
-    drive_handle.seek(std::io::SeekFrom::Start(offset as u64)).unwrap();
-    let write_buf: [u8; 1024*1024] = [0;1024*1024];
-    
-    // initialize a progress bar
-    let bar = ProgressBar::new(fsize as u64);
-    bar.set_style(ProgressStyle::default_bar()
-                .template("[{elapsed_precise}] [{bar:40.green/red}] {pos:>7}/{len:7} bytes")
-                .progress_chars("##-"));
-    
-    // loop until disk is fully written
-    for _ in offset..write_loop_ctr {
-        for _ in 0..1024 {
-            match drive_handle.write_all(&write_buf){
-                Ok(_) => (),
-                Err(e) => println!("[-] Hit write error: {}", e)
-            };
-            
-            // increment the progress bar
-            bar.inc(1024 * 1024);
+/// writes `pass` over just the sectors in `ranges` again, with progress sent
+/// to `progress`. gives `Error::Aborted` if `cancel` stops it part way
+pub fn rewrite_ranges(disk: &DiskData, pass: &Pass, ranges: &[LbaRange], io: &IoOptions, progress: &dyn ProgressSink, cancel: &CancelToken) -> Result<PassOutcome, Error> {
+    let fsize = get_drive_size(&disk.path)?;
+    let block = disk.logical_block_size.max(1);
+    let (drive_handle, _) = open_for_write(&disk.path, &io_for(disk, fsize, io))?;
+    let mut backend = open_backend(io, block as usize);
+    let ranges = sector_bytes(ranges, block, fsize);
+    let mut outcome = PassOutcome { bytes_written: 0, errors: Vec::new(), bad_ranges: Vec::new(), stopped_at: None };
+
+    let tracker = Tracker::new(progress, cancel, &disk.path, Stage::Rewriting, ranges.iter().map(|(start, end)| end - start).sum(), 0);
+    for (start, end) in ranges {
+        backend.write(&drive_handle, pass, start, end, &mut outcome, &tracker);
+    }
+    if let Err(e) = drive_handle.sync_data() {
+        outcome.errors.push(format!("sync error after rewriting: {}", e));
+    }
+    tracker.report();
+    LbaRange::merge(&mut outcome.bad_ranges);
+    if cancel.is_cancelled() {
+        return Err(Error::Aborted);
+    }
+
+    Ok(outcome)
+}
+
+/// reads the drive back, all of it or just the parts picked by `sample`, and
+/// gives every run of sectors that does not hold what `pass` wrote there.
+/// gives `Error::Aborted` if `cancel` stops it part way
+pub fn find_mismatches(disk: &DiskData, pass: &Pass, sample: Option<&SamplePlan>, io: &IoOptions, progress: &dyn ProgressSink, cancel: &CancelToken) -> Result<Vec<LbaRange>, Error> {
+    let fsize = get_drive_size(&disk.path)?;
+    let io = &io_for(disk, fsize, io);
+    match sample {
+        Some(plan) => check_bytes(disk, pass, &plan.ranges, io, Stage::Verifying, progress, cancel),
+        None => check_bytes(disk, pass, &[(0, fsize)], io, Stage::Verifying, progress, cancel)
+    }
+}
+
+/// reads just the sectors in `ranges` back, giving those that still do not
+/// hold what `pass` wrote there. gives `Error::Aborted` if `cancel` stops it part way
+pub fn check_ranges(disk: &DiskData, pass: &Pass, ranges: &[LbaRange], io: &IoOptions, progress: &dyn ProgressSink, cancel: &CancelToken) -> Result<Vec<LbaRange>, Error> {
+    let fsize = get_drive_size(&disk.path)?;
+    check_bytes(disk, pass, &sector_bytes(ranges, disk.logical_block_size.max(1), fsize), &io_for(disk, fsize, io), Stage::Rechecking, progress, cancel)
+}
+
+/// reads the byte `ranges` of the drive back as part of `stage`, giving the
+/// sectors among them that are not what `pass` wrote there, sorted and merged
+fn check_bytes(disk: &DiskData, pass: &Pass, ranges: &[(u64, u64)], io: &IoOptions, stage: Stage, progress: &dyn ProgressSink, cancel: &CancelToken) -> Result<Vec<LbaRange>, Error> {
+    let fs = open_for_read(&disk.path, io)?;
+    let mut backend = open_backend(io, disk.logical_block_size.max(1) as usize);
+    let mut mismatches = Vec::new();
+
+    let tracker = Tracker::new(progress, cancel, &disk.path, stage, ranges.iter().map(|(start, end)| end - start).sum(), 0);
+    for (start, end) in ranges {
+        backend.check(&fs, pass, *start, *end, &mut mismatches, &tracker);
+    }
+    tracker.report();
+    // what was read so far says nothing about the rest
+    if cancel.is_cancelled() {
+        return Err(Error::Aborted);
+    }
+    LbaRange::merge(&mut mismatches);
+    if !mismatches.is_empty() {
+        progress.event(&Event::Mismatch { disk: disk.path.clone(), ranges: mismatches.clone() });
+    }
+
+    Ok(mismatches)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        fs::{
+            File,
+            OpenOptions
+        },
+        os::unix::fs::FileExt,
+        path::PathBuf
+    };
+    use super::*;
+    use super::engine::SyncBackend;
+    use super::progress::tests::Collector;
+
+    /// a scratch file for a test, removed again when dropped
+    pub(crate) struct Scratch {
+        pub path: PathBuf,
+        /// the file, open for reading and writing
+        pub file: File
+    }
+
+    impl Scratch {
+        /// a scratch file of `size` zeros
+        pub(crate) fn new(name: &str, size: u64) -> Self {
+            let path = std::env::temp_dir().join(format!("checked_wipe-{}-{}", std::process::id(), name));
+            let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path)
+                .expect("Failed to create scratch file");
+            file.set_len(size).expect("Failed to size scratch file");
+            Scratch { path, file }
+        }
+
+        /// a scratch file holding `bytes`
+        pub(crate) fn holding(name: &str, bytes: &[u8]) -> Self {
+            let scratch = Scratch::new(name, 0);
+            std::fs::write(&scratch.path, bytes).expect("Failed to write scratch file");
+            scratch
+        }
+
+        /// a scratch directory, empty to begin with
+        pub(crate) fn dir(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("checked_wipe-{}-{}", std::process::id(), name));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).expect("Failed to create scratch directory");
+            let file = File::open(&path).expect("Failed to open scratch directory");
+            Scratch { path, file }
+        }
+
+        /// everything in the file
+        pub(crate) fn contents(&self) -> Vec<u8> {
+            std::fs::read(&self.path).expect("Failed to read scratch file")
+        }
+
+        /// where the file is, as disks and files are named on the command line
+        pub(crate) fn name(&self) -> String {
+            self.path.to_string_lossy().into_owned()
         }
-        
-        // need to flush the drive file so we dont pretend we 
-        // are writing faster than we actually are
-        drive_handle.flush().unwrap(); 
     }
 
-    // write whatever last bytes need to be written
-    for _ in 0..final_write {
-        match drive_handle.write(&[0]) {
-            Ok(_) => (),
-            Err(e) => println!("[-] Hit write error: {}", e)
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = if self.path.is_dir() {
+                std::fs::remove_dir_all(&self.path)
+            } else {
+                std::fs::remove_file(&self.path)
+            };
         }
-        bar.inc(1);
     }
 
-    bar.finish();
-    
-    Ok(())
-} 
+    #[test]
+    fn sampled_checks_only_find_damage_in_the_blocks_they_read() {
+        let size = 64 * 1024 * 1024;
+        let scratch = Scratch::new("sampled-check", size);
+        let drive = super::defs::tests::disk(&scratch.name(), None, None);
+        let io = IoOptions { queue_depth: 1, ..IoOptions::default() };
+        let plan = SamplePlan::new(size, 4096, 1.0, [5; super::pattern::SEED_LEN], 1);
+        let found = |ranges: &[(u64, u64)]| check_bytes(&drive, &Pass::Fixed(0), ranges, &io, Stage::Verifying, &SilentSink, &CancelToken::new())
+            .expect("Failed to check scratch file");
+        assert!(found(&plan.ranges).is_empty());
 
+        // damage between the sampled blocks goes unseen, damage in one does not
+        let gap = plan.ranges[0].1;
+        let (start, _) = plan.ranges[1];
+        scratch.file.write_all_at(&[1], gap).expect("Failed to corrupt scratch file");
+        assert!(found(&plan.ranges).is_empty());
+        scratch.file.write_all_at(&[1], start + 10).expect("Failed to corrupt scratch file");
+        assert!(found(&plan.ranges) == vec![LbaRange::single(start / 512)]);
+        assert!(found(&[(0, size)]) == vec![LbaRange::single(gap / 512), LbaRange::single(start / 512)]);
+    }
 
+    #[test]
+    fn rechecks_and_rewrites_just_the_sectors_asked_for() {
+        let size = 10 * 512 + 100;
+        let ranges = [LbaRange { first: 1, last: 2 }, LbaRange::single(10), LbaRange::single(20)];
+        // the last sector is cut short by the end of the disk, and past it there is nothing
+        assert_eq!(sector_bytes(&ranges, 512, size), vec![(512, 1536), (5120, size)]);
+    }
 
-/// checks to see if a drive was really zeroed out
-pub fn assert_check(disk: &DiskData) -> Result<(), usize> {
-    // first get the file's size
-    let fsize = get_drive_size(disk.path.clone()); 
-    let mut fs = std::fs::File::open(disk.path.clone()).unwrap();
-    let checker: [u8; 1024] = [0; 1024];
-    let mut buff: [u8; 1024] = [0; 1024];
+    /// follows a stage without telling anyone, or ever being cancelled
+    pub(crate) fn quiet() -> Tracker<'static> {
+        Tracker::new(&SilentSink, Box::leak(Box::new(CancelToken::new())), "scratch", Stage::Writing, 0, 0)
+    }
 
-    // initialize a progress bar
-    let bar = ProgressBar::new(fsize as u64);
-    bar.set_style(ProgressStyle::default_bar()
-                .template("[{elapsed_precise}] [{bar:40.cyan/yellow}] {pos:>7}/{len:7} bytes")
-                .progress_chars("##-"));
-    
+    /// what `pass` should have left over `range` of a disk
+    pub(crate) fn expected(pass: &Pass, range: Range<u64>) -> Vec<u8> {
+        let mut buf = vec![0; (range.end - range.start) as usize];
+        pass.fill(&mut buf, range.start);
+        buf
+    }
 
-    // assert that all read bytes should be zeros
-    for _ in 0..(fsize/1024) {
-        fs.read(&mut buff[..]).unwrap();
-        if checker != buff{
-            // check if its an EOF character thats throwing us off
-            if bar.length() == fsize as u64 {
-                bar.finish();
-                return Ok(());
-            }
+    #[test]
+    fn writes_every_byte_of_an_odd_sized_file() {
+        // 3 whole blocks, a partial one and a few stray bytes past the last sector
+        let size = 3 * 65536 + 4096 + 77;
+        let scratch = Scratch::new("odd", size);
+        let pass = Pass::Fixed(0xA5);
+        let mut checkpoints = Vec::new();
+        let outcome = write_pass(&scratch.file, &mut SyncBackend::new(65536, 512), &pass, 0..size,
+            CheckpointLimits { bytes: 100000, ..CHECKPOINT }, &quiet(), &mut |offset| checkpoints.push(offset));
 
-            let offset = bar.length() as usize;
-            bar.abandon();
-            println!("[-] Drive was not properly zeroed (non-zero found at offset {})", bar.length());
-            return Err(offset);
-        }
-        bar.inc(1024);
-    }   
-    bar.finish();
-    
-    Ok(())
-}
-
-/// checks if drive was zeroed from offset
-pub fn assert_check_from(disk: &DiskData, offset: usize) -> Result<(), String> {
-    // first get the file's size
-    let fsize = get_drive_size(disk.path.clone()); 
-    let mut fs = std::fs::File::open(disk.path.clone()).unwrap();
-    fs.seek(std::io::SeekFrom::Start(offset as u64)).unwrap();
-    let checker: [u8; 1024] = [0; 1024];
-    let mut buff: [u8; 1024] = [0; 1024];
-
-    // initialize a progress bar
-    let bar = ProgressBar::new(fsize as u64);
-    bar.set_style(ProgressStyle::default_bar()
-                .template("[{elapsed_precise}] [{bar:40.cyan/yellow}] {pos:>7}/{len:7} bytes")
-                .progress_chars("##-"));
-    
+        assert!(outcome.errors.is_empty(), "{:?}", outcome.errors);
+        assert_eq!(outcome.bytes_written, size);
+        assert_eq!(scratch.contents(), expected(&pass, 0..size));
+        assert_eq!(checkpoints, vec![100000, 200000, size]);
+    }
+
+    #[test]
+    fn checkpoints_a_slow_pass_before_it_gets_through_the_bytes() {
+        // a disk so slow that the time is up after every block
+        let size = 3 * 65536 + 100;
+        let scratch = Scratch::new("slow", size);
+        let pass = Pass::Fixed(0x3C);
+        let limits = CheckpointLimits { interval: Duration::ZERO, ..CHECKPOINT };
+        let mut checkpoints = Vec::new();
+        let outcome = write_pass(&scratch.file, &mut SyncBackend::new(65536, 512), &pass, 0..size,
+            limits, &quiet(), &mut |offset| checkpoints.push(offset));
+        assert!(outcome.errors.is_empty(), "{:?}", outcome.errors);
+        assert_eq!(checkpoints, vec![65536, 2 * 65536, 3 * 65536, size]);
+        assert_eq!(scratch.contents(), expected(&pass, 0..size));
+    }
+
+    #[test]
+    fn writes_random_data_smaller_than_a_block() {
+        let size = 4093;
+        let scratch = Scratch::new("tiny", size);
+        let pass = Pass::random([7; SEED_LEN], 3);
+        let outcome = write_pass(&scratch.file, &mut SyncBackend::new(65536, 512), &pass, 0..size,
+            CHECKPOINT, &quiet(), &mut |_| ());
+
+        assert!(outcome.errors.is_empty(), "{:?}", outcome.errors);
+        assert_eq!(outcome.bytes_written, size);
+        assert_eq!(scratch.contents(), expected(&pass, 0..size));
+    }
+
+    #[test]
+    fn resumes_at_an_offset_without_touching_what_came_before() {
+        let size = 2 * 65536 + 1234;
+        let start = 65536 + 512;
+        let scratch = Scratch::new("resume", size);
+        let pass = Pass::Pattern(vec![0x12, 0x34, 0x56]);
+        let outcome = write_pass(&scratch.file, &mut SyncBackend::new(65536, 512), &pass, start..size,
+            CHECKPOINT, &quiet(), &mut |_| ());
+
+        assert!(outcome.errors.is_empty(), "{:?}", outcome.errors);
+        assert_eq!(outcome.bytes_written, size - start);
+        let contents = scratch.contents();
+        assert!(contents[..start as usize].iter().all(|b| *b == 0));
+        assert_eq!(&contents[start as usize..], &expected(&pass, start..size)[..]);
+    }
 
-    // assert that all read bytes should be zeros
-    for _ in offset..(fsize/1024) {
-        fs.read(&mut buff[..]).unwrap();
-        if checker != buff{
-            // check if its an EOF character thats throwing us off
-            if bar.length() == fsize as u64 {
-                bar.finish();
-                return Ok(());
+    #[test]
+    fn fails_the_pass_when_bytes_go_missing() {
+        let size = 8 * 512 + 100;
+        let scratch = Scratch::new("readonly", size);
+        let readonly = File::open(&scratch.path).expect("Failed to open scratch file");
+        let outcome = write_pass(&readonly, &mut SyncBackend::new(4096, 512), &Pass::Fixed(0xFF), 0..size,
+            CHECKPOINT, &quiet(), &mut |_| ());
+
+        assert_eq!(outcome.bytes_written, 0);
+        assert!(outcome.errors.iter().any(|e| e.contains(&format!("wrote 0 of the {} bytes", size))), "{:?}", outcome.errors);
+        assert_eq!(outcome.bad_ranges.len(), 1);
+        assert_eq!((outcome.bad_ranges[0].first, outcome.bad_ranges[0].last), (0, 8));
+    }
+
+    #[test]
+    fn wipes_and_checks_an_image_file_end_to_end() {
+        // sparse, and ending part way through a sector
+        let size = 3 * 1024 * 1024 + 4321;
+        let scratch = Scratch::new("image", size);
+        let disk = DiskData::from_file(&scratch.name()).expect("Failed to read image");
+        assert_eq!(disk.bytes, size);
+
+        let pass = Pass::random([3; SEED_LEN], 2);
+        let io = IoOptions { block_size: 1024 * 1024, ..IoOptions::default() };
+        let outcome = overwrite_drive(&disk, &pass, 0, &io, &SilentSink, &CancelToken::new(), &mut |_| ()).expect("Failed to wipe image");
+        assert!(outcome.errors.is_empty(), "{:?}", outcome.errors);
+        assert_eq!(outcome.bytes_written, size);
+        assert_eq!(scratch.contents(), expected(&pass, 0..size));
+
+        let (bar, cancel) = (SilentSink, CancelToken::new());
+        assert!(find_mismatches(&disk, &pass, None, &io, &bar, &cancel).expect("Failed to check image").is_empty());
+        scratch.file.write_all_at(&[0; 600], 1024 * 1024 - 100).expect("Failed to corrupt scratch file");
+        let bad = find_mismatches(&disk, &pass, None, &io, &bar, &cancel).expect("Failed to check image");
+        assert_eq!(bad.iter().map(|r| r.to_string()).collect::<Vec<String>>(), vec!["2047-2048"]);
+
+        rewrite_ranges(&disk, &pass, &bad, &io, &bar, &cancel).expect("Failed to rewrite image");
+        assert!(check_ranges(&disk, &pass, &bad, &io, &bar, &cancel).expect("Failed to check image").is_empty());
+        assert_eq!(scratch.contents(), expected(&pass, 0..size));
+    }
+
+    #[test]
+    fn wiper_runs_a_method_over_an_image_file() {
+        let size = 2 * 1024 * 1024;
+        let scratch = Scratch::new("wiper", size);
+        let journals = Scratch::dir("wiper-journals");
+        let journal = journals.path.join("wiper.journal");
+        let disk = DiskData::from_file(&scratch.name()).expect("Failed to read image");
+        let seed = [5; SEED_LEN];
+
+        let record = Wiper::new(disk)
+            .method(find_method("dod").expect("dod is a known method"))
+            .seed(seed)
+            .block_size(256 * 1024)
+            .journal(journal.to_string_lossy().into_owned())
+            .start()
+            .and_then(|handle| handle.join())
+            .expect("Failed to wipe image");
+        assert_eq!(record.status(), "success");
+        assert_eq!(record.seed, Some(seed_to_hex(&seed)));
+        assert_eq!(record.passes.len(), 3);
+        assert_eq!(record.passes[2].verified, Some(true));
+        assert!(!journal.exists());
+        assert_eq!(scratch.contents(), expected(&Pass::random(seed, 2), 0..size));
+    }
+
+    #[test]
+    fn wiper_reports_each_pass_and_mismatch_to_its_sink() {
+        let size = 1024 * 1024;
+        let scratch = Scratch::new("events", size);
+        let journals = Scratch::dir("events-journals");
+        let disk = DiskData::from_file(&scratch.name()).expect("Failed to read image");
+        let collector = std::sync::Arc::new(Collector::new());
+
+        let record = Wiper::new(disk.clone())
+            .passes(custom_plan("0x00,0xFF", [0; SEED_LEN]).expect("Failed to plan passes"), "test".to_string())
+            .journal(journals.path.join("events.journal").to_string_lossy().into_owned())
+            .progress(collector.clone())
+            .run()
+            .expect("Failed to wipe image");
+        assert_eq!(record.status(), "success");
+
+        let events = collector.0.lock().unwrap();
+        let kinds: Vec<&str> = events.iter().map(|e| e["event"].as_str().unwrap())
+            .filter(|kind| !["message", "progress"].contains(kind)).collect();
+        assert_eq!(kinds, vec!["pass_started", "stage_started", "pass_finished",
+            "pass_started", "stage_started", "stage_started", "pass_finished", "finished"]);
+        let stages: Vec<&str> = events.iter().filter_map(|e| e["stage"].as_str()).collect();
+        assert_eq!(stages, vec!["writing", "writing", "verifying"]);
+        assert!(events.iter().all(|e| e["disk"] == disk.path.as_str()));
+        assert_eq!(events.last().unwrap()["result"], "success");
+
+        // the last progress of each stage covers all of it
+        let last = events.iter().rev().find(|e| e["event"] == "progress").unwrap();
+        assert_eq!((last["done"].as_u64(), last["total"].as_u64()), (Some(size), Some(size)));
+        drop(events);
+
+        // a sector that no longer holds the pass is reported on its own
+        scratch.file.write_all_at(&[0], 4096).expect("Failed to corrupt scratch file");
+        let checker = Collector::new();
+        find_mismatches(&disk, &Pass::Fixed(0xFF), None, &IoOptions::default(), &checker, &CancelToken::new()).expect("Failed to check image");
+        let events = checker.0.lock().unwrap();
+        let mismatch = events.iter().find(|e| e["event"] == "mismatch").expect("No mismatch was reported");
+        assert_eq!(mismatch["ranges"], serde_json::json!([{"first": 8, "last": 8}]));
+    }
+
+    /// cancels its token as soon as pass number `pass` starts
+    struct CancelAt(usize, CancelToken);
+
+    impl ProgressSink for CancelAt {
+        fn event(&self, event: &Event) {
+            if let Event::PassStarted { pass, .. } = event {
+                if *pass == self.0 {
+                    self.1.cancel();
+                }
             }
+        }
+    }
+
+    #[test]
+    fn cancelled_wipe_keeps_its_journal_and_reports_how_far_it_got() {
+        let size = 1024 * 1024;
+        let scratch = Scratch::new("cancel", size);
+        let journals = Scratch::dir("cancel-journals");
+        let journal = journals.path.join("cancel.journal");
+        let disk = DiskData::from_file(&scratch.name()).expect("Failed to read image");
+        let cancel = CancelToken::new();
 
-            bar.abandon();
-            println!("[-] Drive was not properly zeroed (non-zero found at offset {})", bar.length());
-            return Err("Zero assertion failed".to_string());
+        let record = Wiper::new(disk)
+            .passes(custom_plan("0xFF,0x00,0xFF", [0; SEED_LEN]).expect("Failed to plan passes"), "test".to_string())
+            .no_verify()
+            .journal(journal.to_string_lossy().into_owned())
+            .progress(std::sync::Arc::new(CancelAt(2, cancel.clone())))
+            .cancel_with(cancel)
+            .run()
+            .expect("Failed to wipe image");
+        assert_eq!(record.status(), "incomplete");
+        assert_eq!(record.error().map(|e| e.exit_code()), Some(130));
+        assert_eq!(record.passes.len(), 2);
+        assert_eq!((record.passes[0].bytes_written, record.passes[0].stopped_at), (size, None));
+        assert_eq!((record.passes[1].bytes_written, record.passes[1].stopped_at), (0, Some(0)));
+        assert!(record.passes.iter().all(|p| p.errors.is_empty()));
+        assert_eq!(scratch.contents(), vec![0xFF; size as usize]);
+
+        // the journal is left to resume from, without the pass that was cut short
+        let saved = Journal::load(&journal.to_string_lossy()).expect("Journal was not kept");
+        assert_eq!((saved.pass, saved.offset, saved.record.passes.len()), (1, 0, 1));
+    }
+
+    /// writes over one byte of the disk when a check begins, as if the
+    /// media had lost it, so the check must read that byte to notice
+    struct CorruptOnCheck(PathBuf, u64);
+
+    impl ProgressSink for CorruptOnCheck {
+        fn event(&self, event: &Event) {
+            if let Event::StageStarted { stage: Stage::Verifying, .. } = event {
+                let file = OpenOptions::new().write(true).open(&self.0).expect("Failed to open scratch file");
+                file.write_all_at(&[0x5A], self.1).expect("Failed to corrupt scratch file");
+            }
         }
-        bar.inc(1024);
-    }   
-    bar.finish();
+    }
 
-    Ok(())
-}
\ No newline at end of file
+    #[test]
+    fn verify_samples_the_same_blocks_as_the_wipe_it_checks() {
+        let (size, block) = (40 * 1024 * 1024, 65536);
+        let scratch = Scratch::new("sample", size);
+        let journals = Scratch::dir("sample-journals");
+        let disk = DiskData::from_file(&scratch.name()).expect("Failed to read image");
+        let seed = [3; SEED_LEN];
+        let mode = VerifyMode::Sample(90.0);
+
+        // `verify` checks the final pass of the plan, and samples it with the
+        // stream for that pass number. pick a block that only it would read
+        let plans = (mode.plan(size, block, seed, 2).unwrap(), mode.plan(size, block, seed, 0).unwrap());
+        let reads = |plan: &SamplePlan, offset: u64| plan.ranges.iter().any(|(start, end)| *start <= offset && offset < *end);
+        let offset = (0..size).step_by(block as usize)
+            .find(|offset| reads(&plans.0, *offset) && !reads(&plans.1, *offset))
+            .expect("Every stream picked the same blocks");
+
+        let record = Wiper::new(disk)
+            .passes(custom_plan("0x00,0xFF", [0; SEED_LEN]).expect("Failed to plan passes"), "test".to_string())
+            .block_size(block as usize)
+            .verify(mode)
+            .verify_seed(seed)
+            .journal(journals.path.join("sample.journal").to_string_lossy().into_owned())
+            .progress(std::sync::Arc::new(CorruptOnCheck(scratch.path.clone(), offset)))
+            .run()
+            .expect("Failed to wipe image");
+
+        // the wipe read the block too, found it bad and wrote it again
+        let sample = record.passes[1].sample.as_ref().expect("Pass was not sampled");
+        assert_eq!((&sample.seed[..], sample.stream), (&seed_to_hex(&seed)[..], 2));
+        assert_eq!(record.passes[1].rewritten.iter().map(|r| r.to_string()).collect::<Vec<String>>(), vec![(offset / 512).to_string()]);
+        assert_eq!(serde_json::to_value(plans.0.record()).unwrap(), serde_json::to_value(sample).unwrap());
+        assert_eq!(scratch.contents(), vec![0xFF; size as usize]);
+    }
+
+    #[test]
+    fn passes_that_could_not_be_written_are_not_checked() {
+        let scratch = Scratch::new("vanished", 1024 * 1024);
+        let journals = Scratch::dir("vanished-journals");
+        let disk = DiskData::from_file(&scratch.name()).expect("Failed to read image");
+        std::fs::remove_file(&scratch.path).expect("Failed to remove scratch file");
+        let collector = std::sync::Arc::new(Collector::new());
+
+        let record = Wiper::new(disk)
+            .passes(simple_plan(1, None), "test".to_string())
+            .journal(journals.path.join("vanished.journal").to_string_lossy().into_owned())
+            .progress(collector.clone())
+            .run()
+            .expect("Failed to run wipe");
+        assert_eq!(record.status(), "completed with errors");
+        assert_eq!((record.passes[0].verified, record.passes[0].errors.len()), (None, 1));
+        assert!(record.passes[0].rewritten.is_empty());
+        let events = collector.0.lock().unwrap();
+        assert!(events.iter().all(|e| e["stage"] != "verifying"));
+    }
+}
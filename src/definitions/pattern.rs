@@ -0,0 +1,229 @@
+use std::fmt::Display;
+use rand_chacha::ChaCha20Rng;
+use rand_core::{
+    OsRng,
+    RngCore,
+    SeedableRng
+};
+use serde::{
+    Serialize,
+    Deserialize
+};
+use super::error::Error;
+
+/// number of bytes in a random pass seed (a ChaCha20 key)
+pub const SEED_LEN: usize = 32;
+
+/// defines what gets written to the disk during a single overwrite pass
+#[derive (Clone, Serialize, Deserialize)]
+pub enum Pass {
+    /// every byte of the disk is set to the same value
+    Fixed(u8),
+    /// a multi-byte pattern repeated across the whole disk
+    Pattern(Vec<u8>),
+    /// the ChaCha20 keystream for `seed`, with each pass getting its own `stream`
+    Random {
+        seed: [u8; SEED_LEN],
+        stream: u64
+    },
+    /// the bitwise complement of whatever another pass writes
+    Complement(Box<Pass>)
+}
+
+impl Pass {
+    /// creates the random pass for pass number `pass_num` keyed by `seed`
+    pub fn random(seed: [u8; SEED_LEN], pass_num: u64) -> Self {
+        Pass::Random { seed, stream: pass_num }
+    }
+
+    /// whether the data written by this pass comes from the random stream
+    pub fn uses_seed(&self) -> bool {
+        match self {
+            Pass::Random { .. } => true,
+            Pass::Complement(pass) => pass.uses_seed(),
+            _ => false
+        }
+    }
+
+    /// the seed the pass's random data comes from, if it has any
+    pub fn seed(&self) -> Option<[u8; SEED_LEN]> {
+        match self {
+            Pass::Random { seed, .. } => Some(*seed),
+            Pass::Complement(pass) => pass.seed(),
+            _ => None
+        }
+    }
+
+    /// fills `buf` with the data this pass writes starting at byte `offset` of the disk.
+    /// the output only depends on the offset, so any region of a pass can be
+    /// regenerated later without storing what was written
+    pub fn fill(&self, buf: &mut [u8], offset: u64) {
+        match self {
+            Pass::Fixed(byte) => buf.fill(*byte),
+            Pass::Pattern(pattern) => {
+                // line the pattern up with where this buffer lands on the disk
+                let start = (offset % pattern.len() as u64) as usize;
+                for (byte, p) in buf.iter_mut().zip(pattern.iter().cycle().skip(start)) {
+                    *byte = *p;
+                }
+            },
+            Pass::Random { seed, stream } => {
+                let mut rng = ChaCha20Rng::from_seed(*seed);
+                rng.set_stream(*stream);
+
+                // the keystream is addressed in 32-bit words, so we have to
+                // throw away the start of the first word if we are not aligned
+                rng.set_word_pos((offset / 4) as u128);
+                let skip = (offset % 4) as usize;
+                let mut start = 0;
+                if skip != 0 {
+                    let mut word = [0u8; 4];
+                    rng.fill_bytes(&mut word);
+                    start = std::cmp::min(4 - skip, buf.len());
+                    buf[..start].copy_from_slice(&word[skip..skip + start]);
+                }
+                rng.fill_bytes(&mut buf[start..]);
+            },
+            Pass::Complement(pass) => {
+                pass.fill(buf, offset);
+                for byte in buf.iter_mut() {
+                    *byte = !*byte;
+                }
+            }
+        }
+    }
+}
+
+impl Display for Pass {
+    fn fmt (&self, fmt: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
+        match self {
+            Pass::Fixed(0) => write!(fmt, "zeros"),
+            Pass::Fixed(byte) => write!(fmt, "0x{:02X}", byte),
+            Pass::Pattern(pattern) => {
+                write!(fmt, "0x")?;
+                for byte in pattern.iter() {
+                    write!(fmt, "{:02X}", byte)?;
+                }
+                Ok(())
+            },
+            Pass::Random { stream, .. } => write!(fmt, "random (stream {})", stream),
+            Pass::Complement(pass) => write!(fmt, "complement of {}", pass)
+        }
+    }
+}
+
+
+/// generates a new seed for random passes from the operating system
+pub fn new_seed() -> [u8; SEED_LEN] {
+    let mut seed = [0u8; SEED_LEN];
+    OsRng.fill_bytes(&mut seed);
+    seed
+}
+
+/// parses a seed given as a hex string
+pub fn parse_seed(hex: &str) -> Result<[u8; SEED_LEN], Error> {
+    let hex = hex.trim();
+    if !hex.is_ascii() || hex.len() != SEED_LEN * 2 {
+        return Err(Error::Invalid(format!("seed must be {} hex characters long", SEED_LEN * 2)));
+    }
+
+    let mut seed = [0u8; SEED_LEN];
+    for (i, byte) in seed.iter_mut().enumerate() {
+        *byte = match u8::from_str_radix(&hex[i*2..i*2 + 2], 16) {
+            Ok(a) => a,
+            Err(e) => return Err(Error::Invalid(format!("invalid seed: {}", e)))
+        };
+    }
+
+    Ok(seed)
+}
+
+/// parses a pattern given as hex, such as `0x55AA`, into its bytes
+pub fn parse_pattern(hex: &str) -> Result<Vec<u8>, Error> {
+    let digits = match hex.trim().strip_prefix("0x") {
+        Some(a) => a,
+        None => return Err(Error::Invalid(format!("pattern `{}` must start with 0x", hex)))
+    };
+    if digits.is_empty() || !digits.is_ascii() || digits.len() % 2 != 0 {
+        return Err(Error::Invalid(format!("pattern `{}` must be a whole number of hex bytes", hex)));
+    }
+
+    let mut pattern: Vec<u8> = Vec::new();
+    for i in (0..digits.len()).step_by(2) {
+        match u8::from_str_radix(&digits[i..i + 2], 16) {
+            Ok(a) => pattern.push(a),
+            Err(e) => return Err(Error::Invalid(format!("invalid pattern `{}`: {}", hex, e)))
+        };
+    }
+
+    Ok(pattern)
+}
+
+/// reads a repeating pattern from the raw bytes of the file at `path`
+pub fn load_pattern_file(path: &str) -> Result<Vec<u8>, Error> {
+    let pattern = match std::fs::read(path) {
+        Ok(a) => a,
+        Err(e) => return Err(Error::from_io(&e, format!("failed to read pattern file {}: {}", path, e)))
+    };
+    if pattern.is_empty() {
+        return Err(Error::Invalid(format!("pattern file {} is empty", path)));
+    }
+
+    Ok(pattern)
+}
+
+/// formats a seed as a hex string so it can be passed back in later
+pub fn seed_to_hex(seed: &[u8; SEED_LEN]) -> String {
+    seed.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `len` bytes of what `pass` writes from `offset` on
+    fn filled(pass: &Pass, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        pass.fill(&mut buf, offset);
+        buf
+    }
+
+    #[test]
+    fn random_data_only_depends_on_seed_stream_and_offset() {
+        let pass = Pass::random([7; SEED_LEN], 1);
+        let whole = filled(&pass, 0, 4096);
+        // any piece of the pass comes out the same, on a word boundary or not
+        for &(offset, len) in [(0, 1), (1, 2), (3, 10), (5, 3), (1000, 1001), (4093, 3)].iter() {
+            assert_eq!(filled(&pass, offset, len), &whole[offset as usize..offset as usize + len]);
+        }
+        assert_ne!(filled(&Pass::random([7; SEED_LEN], 2), 0, 64), &whole[..64]);
+        assert_ne!(filled(&Pass::random([8; SEED_LEN], 1), 0, 64), &whole[..64]);
+        assert_eq!(filled(&Pass::Fixed(0xA5), 3, 5), vec![0xA5; 5]);
+    }
+
+    #[test]
+    fn seeds_round_trip_through_hex() {
+        let mut seed = [0; SEED_LEN];
+        for (i, byte) in seed.iter_mut().enumerate() {
+            *byte = (i * 37) as u8;
+        }
+        let hex = seed_to_hex(&seed);
+        assert_eq!(hex.len(), SEED_LEN * 2);
+        assert_eq!(parse_seed(&hex).ok(), Some(seed));
+        assert_eq!(parse_seed(&format!(" {}\n", hex.to_uppercase())).ok(), Some(seed));
+        assert!(parse_seed("abc").is_err());
+        assert!(parse_seed(&"zz".repeat(SEED_LEN)).is_err());
+    }
+
+    #[test]
+    fn patterns_line_up_with_the_disk_and_complements_invert_them() {
+        let pattern = Pass::Pattern(vec![0x92, 0x49, 0x24]);
+        assert_eq!(filled(&pattern, 0, 7), vec![0x92, 0x49, 0x24, 0x92, 0x49, 0x24, 0x92]);
+        assert_eq!(filled(&pattern, 4, 4), vec![0x49, 0x24, 0x92, 0x49]);
+        assert_eq!(pattern.to_string(), "0x924924");
+
+        let complement = Pass::Complement(Box::new(pattern));
+        assert_eq!(filled(&complement, 4, 2), vec![0xB6, 0xDB]);
+        assert_eq!(complement.to_string(), "complement of 0x924924");
+    }
+}
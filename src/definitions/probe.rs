@@ -0,0 +1,421 @@
+use std::{
+    fs::File,
+    os::unix::fs::FileExt,
+    fmt::Display
+};
+use serde::Serialize;
+
+/// what we managed to work out about the contents of a partition by
+/// reading its superblock
+#[derive (Clone, Serialize)]
+pub struct FsInfo {
+    /// the type, named the same way blkid names it
+    pub fstype: String,
+    pub version: Option<String>,
+    pub label: Option<String>,
+    pub uuid: Option<String>
+}
+
+impl FsInfo {
+    fn new(fstype: &str, version: Option<String>, label: Option<String>, uuid: Option<String>) -> Self {
+        FsInfo { fstype: fstype.to_string(), version, label, uuid }
+    }
+
+    /// whether this is a filesystem mount can do anything with. containers
+    /// such as LUKS, LVM and raid members hold data but cannot be mounted
+    pub fn is_mountable(&self) -> bool {
+        matches!(&self.fstype[..], "ext2" | "ext3" | "ext4" | "xfs" | "btrfs" | "vfat" | "exfat" | "ntfs")
+    }
+}
+
+impl Display for FsInfo {
+    fn fmt (&self, fmt: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
+        write!(fmt, "{}", self.fstype)?;
+        if let Some(version) = &self.version {
+            write!(fmt, " (v{})", version)?;
+        }
+        if let Some(label) = &self.label {
+            write!(fmt, ", Label: {}", label)?;
+        }
+        if let Some(uuid) = &self.uuid {
+            write!(fmt, ", UUID: {}", uuid)?;
+        }
+        Ok(())
+    }
+}
+
+
+/// reads `len` bytes at `offset`, giving nothing if the device is too short
+fn read_at(fs: &File, offset: u64, len: usize) -> Option<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    match fs.read_exact_at(&mut buf, offset) {
+        Ok(_) => Some(buf),
+        Err(_) => None
+    }
+}
+
+fn le16(buf: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([buf[off], buf[off + 1]])
+}
+
+fn le32(buf: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes([buf[off], buf[off + 1], buf[off + 2], buf[off + 3]])
+}
+
+fn be16(buf: &[u8], off: usize) -> u16 {
+    u16::from_be_bytes([buf[off], buf[off + 1]])
+}
+
+/// turns a fixed size, nul or space padded label field into a string
+fn label(raw: &[u8]) -> Option<String> {
+    let end = raw.iter().position(|b| *b == 0).unwrap_or(raw.len());
+    let label = String::from_utf8_lossy(&raw[..end]).trim().to_string();
+    if label.is_empty() {
+        None
+    } else {
+        Some(label)
+    }
+}
+
+/// formats a 16 byte binary uuid the usual 8-4-4-4-12 way
+fn uuid(raw: &[u8]) -> Option<String> {
+    if raw.iter().all(|b| *b == 0) {
+        return None;
+    }
+    let hex: String = raw.iter().map(|b| format!("{:02x}", b)).collect();
+    Some(format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32]))
+}
+
+
+/// identifies what lives on the device at `path` (of `size` bytes) by looking
+/// for the superblocks of the filesystems and containers we know about.
+/// containers are checked first, as they can hide a filesystem signature
+/// further into the device
+pub fn probe(path: &str, size: u64) -> Option<FsInfo> {
+    let fs = File::open(path).ok()?;
+    let head = read_at(&fs, 0, 4096)?;
+
+    probe_luks(&head)
+        .or_else(|| probe_lvm(&head))
+        .or_else(|| probe_mdraid(&fs, size))
+        .or_else(|| probe_btrfs(&fs))
+        .or_else(|| probe_xfs(&head))
+        .or_else(|| probe_ext(&fs))
+        .or_else(|| probe_swap(&fs))
+        .or_else(|| probe_ntfs(&head))
+        .or_else(|| probe_exfat(&head))
+        .or_else(|| probe_fat(&head))
+}
+
+fn probe_luks(head: &[u8]) -> Option<FsInfo> {
+    if &head[0..6] != b"LUKS\xba\xbe" {
+        return None;
+    }
+
+    // LUKS2 added a label in the gap LUKS1 used for the cipher name
+    let version = be16(head, 6);
+    let name = if version == 2 { label(&head[24..72]) } else { None };
+    Some(FsInfo::new("crypto_LUKS", Some(version.to_string()), name, label(&head[168..208])))
+}
+
+fn probe_lvm(head: &[u8]) -> Option<FsInfo> {
+    // the label can be in any of the first four sectors
+    for sector in 0..4 {
+        let lbl = &head[sector * 512..(sector + 1) * 512];
+        if &lbl[0..8] != b"LABELONE" || &lbl[24..32] != b"LVM2 001" {
+            continue;
+        }
+
+        // the pv header follows the label, starting with the uuid. LVM
+        // prints these in groups of 6-4-4-4-4-4-6
+        let start = le32(lbl, 20) as usize;
+        let raw = lbl.get(start..start + 32)?;
+        let raw = String::from_utf8_lossy(raw).to_string();
+        let mut groups: Vec<&str> = Vec::new();
+        let mut idx = 0;
+        for len in [6, 4, 4, 4, 4, 4, 6].iter() {
+            groups.push(raw.get(idx..idx + len)?);
+            idx += len;
+        }
+        return Some(FsInfo::new("LVM2_member", None, None, Some(groups.join("-"))));
+    }
+
+    None
+}
+
+fn probe_mdraid(fs: &File, size: u64) -> Option<FsInfo> {
+    const MD_MAGIC: u32 = 0xa92b4efc;
+
+    // version 1.1 sits at the start, 1.2 4K in and 1.0 near the end
+    let end_v1 = ((size / 512).saturating_sub(16) & !7) * 512;
+    for (offset, version) in [(0, "1.1"), (4096, "1.2"), (end_v1, "1.0")].iter() {
+        if let Some(sb) = read_at(fs, *offset, 256) {
+            if le32(&sb, 0) == MD_MAGIC && le32(&sb, 4) == 1 {
+                return Some(FsInfo::new("linux_raid_member", Some(version.to_string()),
+                    label(&sb[32..64]), uuid(&sb[16..32])));
+            }
+        }
+    }
+
+    // version 0.90 sits in the last 64K aligned block of the device
+    let end_v090 = (size & !0xFFFF).saturating_sub(0x10000);
+    if let Some(sb) = read_at(fs, end_v090, 64) {
+        if le32(&sb, 0) == MD_MAGIC && le32(&sb, 4) == 0 {
+            let mut raw: Vec<u8> = Vec::new();
+            for off in [20, 52, 56, 60].iter() {
+                raw.extend_from_slice(&le32(&sb, *off).to_be_bytes());
+            }
+            return Some(FsInfo::new("linux_raid_member", Some("0.90".to_string()), None, uuid(&raw)));
+        }
+    }
+
+    None
+}
+
+fn probe_btrfs(fs: &File) -> Option<FsInfo> {
+    let sb = read_at(fs, 0x10000, 0x300)?;
+    if &sb[0x40..0x48] != b"_BHRfS_M" {
+        return None;
+    }
+
+    Some(FsInfo::new("btrfs", None, label(&sb[0x12B..0x22B]), uuid(&sb[0x20..0x30])))
+}
+
+fn probe_xfs(head: &[u8]) -> Option<FsInfo> {
+    if &head[0..4] != b"XFSB" {
+        return None;
+    }
+
+    Some(FsInfo::new("xfs", None, label(&head[108..120]), uuid(&head[32..48])))
+}
+
+fn probe_ext(fs: &File) -> Option<FsInfo> {
+    let sb = read_at(fs, 1024, 1024)?;
+    if le16(&sb, 0x38) != 0xEF53 {
+        return None;
+    }
+
+    // ext2/3/4 share a superblock, so tell them apart by their features
+    let compat = le32(&sb, 0x5C);
+    let incompat = le32(&sb, 0x60);
+    let ro_compat = le32(&sb, 0x64);
+    let fstype = if incompat & 0x2C0 != 0 || ro_compat & 0x478 != 0 {
+        // extents, 64bit, flex_bg or huge_file, gdt/metadata csums, etc
+        "ext4"
+    } else if compat & 0x4 != 0 {
+        // has a journal
+        "ext3"
+    } else {
+        "ext2"
+    };
+
+    Some(FsInfo::new(fstype, None, label(&sb[0x78..0x88]), uuid(&sb[0x68..0x78])))
+}
+
+fn probe_swap(fs: &File) -> Option<FsInfo> {
+    // the signature sits at the end of the first page, whatever size that was
+    for page in [4096u64, 8192, 16384, 65536].iter() {
+        let sig = match read_at(fs, page - 10, 10) {
+            Some(a) => a,
+            None => continue
+        };
+        if &sig[..] == b"SWAPSPACE2" || &sig[..] == b"SWAP-SPACE" {
+            let hdr = read_at(fs, 1024, 44)?;
+            return Some(FsInfo::new("swap", None, label(&hdr[28..44]), uuid(&hdr[12..28])));
+        }
+    }
+
+    None
+}
+
+fn probe_ntfs(head: &[u8]) -> Option<FsInfo> {
+    if &head[3..11] != b"NTFS    " {
+        return None;
+    }
+
+    // ntfs has no real uuid, just a 64 bit serial number. the label lives
+    // in the $Volume file, which we do not go digging for
+    let serial: String = head[0x48..0x50].iter().rev().map(|b| format!("{:02X}", b)).collect();
+    Some(FsInfo::new("ntfs", None, None, Some(serial)))
+}
+
+fn probe_exfat(head: &[u8]) -> Option<FsInfo> {
+    if &head[3..11] != b"EXFAT   " {
+        return None;
+    }
+
+    // like ntfs, the label is in the root directory rather than the boot sector
+    let serial = le32(head, 0x64);
+    Some(FsInfo::new("exfat", None, None, Some(format!("{:04X}-{:04X}", serial >> 16, serial & 0xFFFF))))
+}
+
+fn probe_fat(head: &[u8]) -> Option<FsInfo> {
+    if le16(head, 0x1FE) != 0xAA55 {
+        return None;
+    }
+
+    // FAT32 moved the extended boot record further into the sector
+    let (ebr, version) = if &head[0x52..0x57] == b"FAT32" {
+        (0x40, "32")
+    } else if &head[0x36..0x3A] == b"FAT1" {
+        (0x24, if &head[0x36..0x3B] == b"FAT12" { "12" } else { "16" })
+    } else {
+        return None;
+    };
+
+    let serial = le32(head, ebr + 3);
+    let name = label(&head[ebr + 7..ebr + 18]).filter(|l| l != "NO NAME");
+    Some(FsInfo::new("vfat", Some(version.to_string()), name,
+        Some(format!("{:04X}-{:04X}", serial >> 16, serial & 0xFFFF))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tests::Scratch;
+
+    /// probes an image holding `bytes`, as though it were a device
+    fn probed(name: &str, bytes: &[u8]) -> Option<FsInfo> {
+        let image = Scratch::holding(&format!("probe-{}", name), bytes);
+        probe(&image.name(), bytes.len() as u64)
+    }
+
+    /// type, version, label and uuid, as blkid would print them
+    type Found = (String, Option<String>, Option<String>, Option<String>);
+
+    fn found(info: Option<FsInfo>) -> Option<Found> {
+        info.map(|i| (i.fstype, i.version, i.label, i.uuid))
+    }
+
+    fn some(s: &str) -> Option<String> {
+        Some(s.to_string())
+    }
+
+    /// a uuid as it sits on disk, and as it gets printed
+    const UUID: [u8; 16] = [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77];
+    const UUID_STR: &str = "01234567-89ab-cdef-0011-223344556677";
+
+    fn put(image: &mut [u8], offset: usize, bytes: &[u8]) {
+        image[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    #[test]
+    fn tells_ext2_3_and_4_apart_by_their_features() {
+        let mut image = vec![0u8; 8192];
+        put(&mut image, 1024 + 0x38, &0xEF53u16.to_le_bytes());
+        put(&mut image, 1024 + 0x68, &UUID);
+        put(&mut image, 1024 + 0x78, b"rootfs");
+        assert_eq!(found(probed("ext2", &image)), Some(("ext2".to_string(), None, some("rootfs"), some(UUID_STR))));
+
+        put(&mut image, 1024 + 0x5C, &0x4u32.to_le_bytes());
+        assert_eq!(found(probed("ext3", &image)).map(|f| f.0), some("ext3"));
+
+        // extents
+        put(&mut image, 1024 + 0x60, &0x40u32.to_le_bytes());
+        assert_eq!(found(probed("ext4", &image)).map(|f| f.0), some("ext4"));
+    }
+
+    #[test]
+    fn finds_xfs_and_btrfs() {
+        let mut image = vec![0u8; 8192];
+        put(&mut image, 0, b"XFSB");
+        put(&mut image, 32, &UUID);
+        put(&mut image, 108, b"data\0\0\0\0\0\0\0\0");
+        assert_eq!(found(probed("xfs", &image)), Some(("xfs".to_string(), None, some("data"), some(UUID_STR))));
+
+        let mut image = vec![0u8; 0x10000 + 0x1000];
+        put(&mut image, 0x10000 + 0x20, &UUID);
+        put(&mut image, 0x10000 + 0x40, b"_BHRfS_M");
+        put(&mut image, 0x10000 + 0x12B, b"pool");
+        assert_eq!(found(probed("btrfs", &image)), Some(("btrfs".to_string(), None, some("pool"), some(UUID_STR))));
+    }
+
+    #[test]
+    fn finds_fat_with_its_serial_and_ignores_no_name() {
+        let mut image = vec![0u8; 4096];
+        put(&mut image, 0x1FE, &[0x55, 0xAA]);
+        put(&mut image, 0x52, b"FAT32   ");
+        put(&mut image, 0x43, &0x1234ABCDu32.to_le_bytes());
+        put(&mut image, 0x47, b"EFI        ");
+        assert_eq!(found(probed("fat32", &image)), Some(("vfat".to_string(), some("32"), some("EFI"), some("1234-ABCD"))));
+
+        let mut image = vec![0u8; 4096];
+        put(&mut image, 0x1FE, &[0x55, 0xAA]);
+        put(&mut image, 0x36, b"FAT16   ");
+        put(&mut image, 0x27, &0x00C0FFEEu32.to_le_bytes());
+        put(&mut image, 0x2B, b"NO NAME    ");
+        assert_eq!(found(probed("fat16", &image)), Some(("vfat".to_string(), some("16"), None, some("00C0-FFEE"))));
+    }
+
+    #[test]
+    fn finds_luks2_and_lvm2_containers() {
+        let mut image = vec![0u8; 8192];
+        put(&mut image, 0, b"LUKS\xba\xbe");
+        put(&mut image, 6, &2u16.to_be_bytes());
+        put(&mut image, 24, b"secret");
+        put(&mut image, 168, UUID_STR.as_bytes());
+        assert_eq!(found(probed("luks2", &image)), Some(("crypto_LUKS".to_string(), some("2"), some("secret"), some(UUID_STR))));
+
+        // the label in the second sector, with the pv header straight after it
+        let mut image = vec![0u8; 8192];
+        put(&mut image, 512, b"LABELONE");
+        put(&mut image, 512 + 20, &32u32.to_le_bytes());
+        put(&mut image, 512 + 24, b"LVM2 001");
+        put(&mut image, 512 + 32, b"abcdefGHIJklmnOPQRstuvWXYZ012345");
+        assert_eq!(found(probed("lvm2", &image)),
+            Some(("LVM2_member".to_string(), None, None, some("abcdef-GHIJ-klmn-OPQR-stuv-WXYZ-012345"))));
+    }
+
+    #[test]
+    fn finds_md_superblocks_at_either_end() {
+        let size = 1024 * 1024;
+        let mut image = vec![0u8; size];
+        put(&mut image, 4096, &0xa92b4efcu32.to_le_bytes());
+        put(&mut image, 4096 + 4, &1u32.to_le_bytes());
+        put(&mut image, 4096 + 16, &UUID);
+        put(&mut image, 4096 + 32, b"host:0");
+        assert_eq!(found(probed("md12", &image)), Some(("linux_raid_member".to_string(), some("1.2"), some("host:0"), some(UUID_STR))));
+
+        // 0.90 keeps its uuid in four words, the first apart from the rest
+        let mut image = vec![0u8; size];
+        let sb = size - 0x10000;
+        put(&mut image, sb, &0xa92b4efcu32.to_le_bytes());
+        for (off, word) in [(20, 0x01234567u32), (52, 0x89abcdef), (56, 0x00112233), (60, 0x44556677)].iter() {
+            put(&mut image, sb + off, &word.to_le_bytes());
+        }
+        assert_eq!(found(probed("md090", &image)), Some(("linux_raid_member".to_string(), some("0.90"), None, some(UUID_STR))));
+    }
+
+    #[test]
+    fn finds_swap_whatever_its_page_size() {
+        for page in [4096usize, 65536].iter() {
+            let mut image = vec![0u8; *page];
+            put(&mut image, page - 10, b"SWAPSPACE2");
+            put(&mut image, 1024 + 12, &UUID);
+            put(&mut image, 1024 + 28, b"swap0");
+            assert_eq!(found(probed("swap", &image)), Some(("swap".to_string(), None, some("swap0"), some(UUID_STR))));
+        }
+    }
+
+    #[test]
+    fn truncated_images_are_unknown_rather_than_a_panic() {
+        // shorter than the first 4K everything gets read from
+        let mut image = vec![0u8; 2048];
+        put(&mut image, 0, b"LUKS\xba\xbe");
+        put(&mut image, 1024 + 0x38, &0xEF53u16.to_le_bytes());
+        assert!(probed("short", &image).is_none());
+        assert!(probed("empty", &[]).is_none());
+
+        // cut off part way through the btrfs superblock
+        let mut image = vec![0u8; 0x10000 + 0x100];
+        put(&mut image, 0x10000 + 0x40, b"_BHRfS_M");
+        assert!(probed("btrfs-cut", &image).is_none());
+
+        // an LVM label whose pv header would run off the end of its sector
+        let mut image = vec![0u8; 4096];
+        put(&mut image, 0, b"LABELONE");
+        put(&mut image, 20, &500u32.to_le_bytes());
+        put(&mut image, 24, b"LVM2 001");
+        assert!(probed("lvm-cut", &image).is_none());
+    }
+}
@@ -0,0 +1,317 @@
+use std::{
+    cell::Cell,
+    time::{
+        Duration,
+        Instant
+    }
+};
+use colored::Colorize;
+use indicatif::{
+    ProgressBar,
+    ProgressStyle
+};
+use serde::Serialize;
+use super::{
+    cancel::CancelToken,
+    record::LbaRange
+};
+
+/// how often `Progress` events go out while a stage is running
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+/// what is being done to the disk
+#[derive (Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// writing a pass over the disk
+    Writing,
+    /// reading the disk back to check a pass
+    Verifying,
+    /// writing a pass again over sectors that did not check out
+    Rewriting,
+    /// checking those sectors again
+    Rechecking
+}
+
+/// how much a message matters, which the terminal shows as its color
+#[derive (Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    Info,
+    Success,
+    Warning,
+    Failure
+}
+
+/// something that happened during a wipe or a check. every event names the
+/// disk it is about, so one sink can follow several wipes at once
+#[derive (Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// pass number `pass` (counting from 1) of `passes`, writing `pattern`, has begun
+    PassStarted {
+        disk: String,
+        pass: usize,
+        passes: usize,
+        pattern: String
+    },
+    /// a stage covering `total` bytes has begun, with `done` of them already done
+    StageStarted {
+        disk: String,
+        stage: Stage,
+        total: u64,
+        done: u64
+    },
+    /// how far the current stage has got, and how fast it is going
+    Progress {
+        disk: String,
+        done: u64,
+        total: u64,
+        bytes_per_second: f64
+    },
+    /// reading or writing failed `offset` bytes into the disk
+    Error {
+        disk: String,
+        offset: u64,
+        message: String
+    },
+    /// a check found sectors that do not hold what was written there
+    Mismatch {
+        disk: String,
+        ranges: Vec<LbaRange>
+    },
+    /// pass number `pass` is over, checked or not
+    PassFinished {
+        disk: String,
+        pass: usize,
+        bytes_written: u64,
+        seconds: f64,
+        verified: Option<bool>,
+        errors: usize
+    },
+    /// anything else worth telling someone
+    Message {
+        disk: String,
+        level: Level,
+        text: String
+    },
+    /// the wipe was cancelled before pass `pass` got past `offset`, with
+    /// `bytes_written` of it written, so the disk is only partly wiped
+    Stopped {
+        disk: String,
+        pass: usize,
+        offset: u64,
+        bytes_written: u64
+    },
+    /// the wipe is over, with its result as given by `WipeRecord::status`
+    Finished {
+        disk: String,
+        result: String
+    }
+}
+
+/// somewhere for events to go. a wipe may run on a thread of its own, so
+/// sinks have to be happy being called from one
+pub trait ProgressSink: Send + Sync {
+    fn event(&self, event: &Event);
+}
+
+/// throws every event away
+pub struct SilentSink;
+
+impl ProgressSink for SilentSink {
+    fn event(&self, _event: &Event) {}
+}
+
+/// writes every event to stderr as a line of JSON, for other programs to follow
+pub struct JsonLinesSink;
+
+impl ProgressSink for JsonLinesSink {
+    fn event(&self, event: &Event) {
+        if let Ok(line) = serde_json::to_string(event) {
+            eprintln!("{}", line);
+        }
+    }
+}
+
+/// draws progress on a bar and prints messages above it. a hidden bar sends
+/// the messages through `say!` instead, so they still get seen
+pub struct TerminalSink {
+    bar: ProgressBar
+}
+
+impl TerminalSink {
+    /// shows progress on `bar`, such as one added to a `MultiProgress`
+    pub fn new(bar: ProgressBar) -> Self {
+        TerminalSink { bar }
+    }
+
+    /// prints a message about `disk` above the bar, so several wipes can
+    /// report at once without scribbling over each other
+    fn note(&self, disk: &str, msg: String) {
+        if self.bar.is_hidden() {
+            say!("[{}] {}", disk, msg);
+        } else {
+            self.bar.println(format!("[{}] {}", disk, msg));
+        }
+    }
+}
+
+impl ProgressSink for TerminalSink {
+    fn event(&self, event: &Event) {
+        let bar = &self.bar;
+        match event {
+            Event::PassStarted { pass, passes, pattern, .. } => bar.set_message(format!("pass {}/{} ({})", pass, passes, pattern)),
+            Event::StageStarted { disk, stage, total, done } => {
+                let colors = match stage {
+                    Stage::Writing | Stage::Rewriting => "green/red",
+                    Stage::Verifying | Stage::Rechecking => "cyan/yellow"
+                };
+                bar.set_prefix(disk.clone());
+                bar.set_style(ProgressStyle::default_bar()
+                            .template(&format!("{{prefix}} [{{elapsed_precise}}] [{{bar:40.{}}}] {{pos:>7}}/{{len:7}} bytes {{msg}}", colors))
+                            .progress_chars("##-"));
+                bar.set_length(*total);
+                bar.set_position(*done);
+                bar.reset_elapsed();
+            },
+            Event::Progress { done, .. } => bar.set_position(*done),
+            Event::Error { disk, offset, message } => self.note(disk, format!("{} {} (at offset {})", "[-]".red().bold(), message, offset)),
+            Event::Mismatch { disk, ranges } => self.note(disk, format!("[-] {} did not hold the pass", LbaRange::describe(ranges))),
+            Event::PassFinished { .. } => (),
+            Event::Stopped { disk, pass, offset, bytes_written } => self.note(disk, format!("{} during pass #{} at offset {} ({} bytes of it written). The disk is only partly wiped",
+                "[-] Stopped".red().bold(), pass, offset, bytes_written)),
+            Event::Message { disk, level, text } => self.note(disk, match level {
+                Level::Info => text.normal(),
+                Level::Success => text.green(),
+                Level::Warning => text.yellow(),
+                Level::Failure => text.red().bold()
+            }.to_string()),
+            // a wipe that was stopped leaves its bar where it got to
+            Event::Finished { result, .. } if result == "incomplete" || result == "FAILED" => bar.abandon_with_message(result.clone()),
+            Event::Finished { result, .. } => bar.finish_with_message(result.clone())
+        }
+    }
+}
+
+/// follows one stage over one disk, sending `Progress` events to a sink
+/// every so often (and once more when it is done) rather than for every block.
+/// it also carries the token that says whether to stop
+pub struct Tracker<'a> {
+    sink: &'a dyn ProgressSink,
+    cancel: &'a CancelToken,
+    disk: &'a str,
+    total: u64,
+    done: Cell<u64>,
+    /// how much was done before this run began, which does not count towards the speed
+    resumed: u64,
+    started: Instant,
+    reported: Cell<Instant>,
+    /// when the stretch being written should stop so it can be checkpointed
+    checkpoint_at: Cell<Option<Instant>>
+}
+
+impl<'a> Tracker<'a> {
+    /// starts `stage` over `total` bytes of `disk`, `done` of which are
+    /// already done, to be stopped early if `cancel` says so
+    pub fn new(sink: &'a dyn ProgressSink, cancel: &'a CancelToken, disk: &'a str, stage: Stage, total: u64, done: u64) -> Self {
+        sink.event(&Event::StageStarted { disk: disk.to_string(), stage, total, done });
+        let now = Instant::now();
+        Tracker { sink, cancel, disk, total, done: Cell::new(done), resumed: done, started: now, reported: Cell::new(now), checkpoint_at: Cell::new(None) }
+    }
+
+    /// counts `bytes` more as done
+    pub fn advance(&self, bytes: u64) {
+        self.done.set(self.done.get() + bytes);
+        if self.reported.get().elapsed() >= PROGRESS_INTERVAL {
+            self.report();
+        }
+    }
+
+    /// whether to stop at the next block
+    pub fn cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// asks for writing to stop at the first block after `at`, for a
+    /// checkpoint, or never if `at` is `None`
+    pub fn checkpoint_by(&self, at: Option<Instant>) {
+        self.checkpoint_at.set(at);
+    }
+
+    /// whether it is time to stop writing for a checkpoint
+    pub fn checkpoint_due(&self) -> bool {
+        matches!(self.checkpoint_at.get(), Some(at) if Instant::now() >= at)
+    }
+
+    /// reports a read or write that failed `offset` bytes in
+    pub fn error(&self, offset: u64, message: String) {
+        self.sink.event(&Event::Error { disk: self.disk.to_string(), offset, message });
+    }
+
+    /// sends how far the stage has got
+    pub fn report(&self) {
+        let seconds = self.started.elapsed().as_secs_f64();
+        let bytes_per_second = if seconds > 0.0 {(self.done.get() - self.resumed) as f64 / seconds} else {0.0};
+        self.reported.set(Instant::now());
+        self.sink.event(&Event::Progress { disk: self.disk.to_string(), done: self.done.get(), total: self.total, bytes_per_second });
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Mutex;
+    use super::*;
+
+    /// keeps every event it is sent, as its JSON, to be looked at afterwards
+    pub(crate) struct Collector(pub Mutex<Vec<serde_json::Value>>);
+
+    impl Collector {
+        pub(crate) fn new() -> Self {
+            Collector(Mutex::new(Vec::new()))
+        }
+    }
+
+    impl ProgressSink for Collector {
+        fn event(&self, event: &Event) {
+            self.0.lock().unwrap().push(serde_json::to_value(event).unwrap());
+        }
+    }
+
+    #[test]
+    fn tracker_starts_its_stage_before_anything_else_is_heard() {
+        let (collector, cancel) = (Collector::new(), CancelToken::new());
+        let tracker = Tracker::new(&collector, &cancel, "/dev/sdx", Stage::Rechecking, 1000, 200);
+        // too soon after starting to be worth a progress event
+        tracker.advance(300);
+        tracker.error(4096, "Read error".to_string());
+        tracker.report();
+        assert!(!tracker.cancelled());
+        cancel.clone().cancel();
+        assert!(tracker.cancelled());
+        assert!(!tracker.checkpoint_due());
+        tracker.checkpoint_by(Some(Instant::now()));
+        assert!(tracker.checkpoint_due());
+
+        let events = collector.0.lock().unwrap();
+        assert_eq!(events[0], serde_json::json!({"event": "stage_started", "disk": "/dev/sdx", "stage": "rechecking", "total": 1000, "done": 200}));
+        assert_eq!(events[1], serde_json::json!({"event": "error", "disk": "/dev/sdx", "offset": 4096, "message": "Read error"}));
+        assert_eq!((events[2]["event"].as_str(), events[2]["done"].as_u64(), events[2]["total"].as_u64()),
+            (Some("progress"), Some(500), Some(1000)));
+        assert_eq!(events.len(), 3);
+    }
+
+    #[test]
+    fn tracker_reports_progress_no_more_often_than_it_should() {
+        let (collector, cancel) = (Collector::new(), CancelToken::new());
+        let tracker = Tracker::new(&collector, &cancel, "/dev/sdx", Stage::Writing, 10, 0);
+        tracker.advance(1);
+        std::thread::sleep(PROGRESS_INTERVAL);
+        tracker.advance(1);
+        tracker.advance(1);
+
+        let events = collector.0.lock().unwrap();
+        let done: Vec<u64> = events.iter().filter(|e| e["event"] == "progress").filter_map(|e| e["done"].as_u64()).collect();
+        assert_eq!(done, vec![2]);
+    }
+}
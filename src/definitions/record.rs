@@ -0,0 +1,536 @@
+use std::fmt::Display;
+use serde::{
+    Serialize,
+    Deserialize
+};
+use super::{
+    defs::DiskData,
+    error::Error
+};
+
+/// the details needed to tell which physical drive a record is about
+#[derive (Clone, Serialize, Deserialize)]
+pub struct DiskIdentity {
+    pub path: String,
+    pub vendor: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub wwn: Option<String>,
+    pub firmware: Option<String>,
+    pub transport: String,
+    pub bytes: u64,
+    /// the size of the blocks that `LbaRange`s count in
+    #[serde(default)]
+    pub logical_block_size: u64
+}
+
+impl From<&DiskData> for DiskIdentity {
+    fn from(disk: &DiskData) -> Self {
+        DiskIdentity {
+            path: disk.path.clone(),
+            vendor: disk.vendor.clone(),
+            model: disk.model.clone(),
+            serial: disk.serial.clone(),
+            wwn: disk.wwn.clone(),
+            firmware: disk.firmware.clone(),
+            transport: disk.transport.clone(),
+            bytes: disk.bytes,
+            logical_block_size: disk.logical_block_size
+        }
+    }
+}
+
+/// a run of logical blocks, `first` to `last` inclusive
+#[derive (Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LbaRange {
+    pub first: u64,
+    pub last: u64
+}
+
+impl LbaRange {
+    /// the range holding just `lba`
+    pub fn single(lba: u64) -> Self {
+        LbaRange { first: lba, last: lba }
+    }
+
+    /// how many blocks the range covers
+    pub fn blocks(&self) -> u64 {
+        self.last - self.first + 1
+    }
+
+    /// sorts `ranges` and joins up any that overlap or sit right next to each other
+    pub fn merge(ranges: &mut Vec<LbaRange>) {
+        ranges.sort_by_key(|r| r.first);
+        let mut merged: Vec<LbaRange> = Vec::new();
+        for range in ranges.drain(..) {
+            match merged.last_mut() {
+                Some(prev) if range.first <= prev.last.saturating_add(1) => prev.last = prev.last.max(range.last),
+                _ => merged.push(range)
+            }
+        }
+        *ranges = merged;
+    }
+
+    /// the number of sectors in `ranges`, and where they are, for messages
+    pub fn describe(ranges: &[LbaRange]) -> String {
+        format!("{} sector(s) at LBA {}", ranges.iter().map(|r| r.blocks()).sum::<u64>(),
+            ranges.iter().map(|r| r.to_string()).collect::<Vec<String>>().join(", "))
+    }
+}
+
+/// how a pass was checked when only a sample of the disk was read back
+#[derive (Clone, Serialize, Deserialize)]
+pub struct SampleRecord {
+    pub requested_percent: f64,
+    /// the seed and stream that picked the blocks, to check the same ones again
+    pub seed: String,
+    pub stream: u64,
+    pub bytes_checked: u64,
+    /// the percentage of the disk that was really read
+    pub coverage: f64
+}
+
+/// what happened during a single pass of a wipe
+#[derive (Clone, Serialize, Deserialize)]
+pub struct PassRecord {
+    pub number: usize,
+    pub pattern: String,
+    #[serde(default)]
+    pub started: String,
+    #[serde(default)]
+    pub finished: String,
+    #[serde(default)]
+    pub seconds: f64,
+    #[serde(default)]
+    pub bytes_written: u64,
+    #[serde(default)]
+    pub errors: Vec<String>,
+    /// blocks that could not be written even one sector at a time, and were skipped
+    #[serde(default)]
+    pub bad_ranges: Vec<LbaRange>,
+    /// where the pass picked back up if it was interrupted and resumed
+    #[serde(default)]
+    pub resumed_from: Option<u64>,
+    /// `None` if the pass was not checked
+    pub verified: Option<bool>,
+    /// how the check was sampled, `None` if every byte was read
+    #[serde(default)]
+    pub sample: Option<SampleRecord>,
+    /// sectors that did not hold the pass when checked, and were written again
+    #[serde(default)]
+    pub rewritten: Vec<LbaRange>,
+    /// where writing stopped, if the wipe was cancelled part way through the pass
+    #[serde(default)]
+    pub stopped_at: Option<u64>
+}
+
+impl PassRecord {
+    /// starts the record for pass `number`, which writes `pattern`
+    pub fn new(number: usize, pattern: String) -> Self {
+        PassRecord {
+            number,
+            pattern,
+            started: chrono::offset::Local::now().to_rfc3339(),
+            finished: String::new(),
+            seconds: 0.0,
+            bytes_written: 0,
+            errors: Vec::new(),
+            bad_ranges: Vec::new(),
+            resumed_from: None,
+            verified: None,
+            sample: None,
+            rewritten: Vec::new(),
+            stopped_at: None
+        }
+    }
+
+    /// marks the pass as over, working out how long it took
+    pub fn finish(&mut self) {
+        let now = chrono::offset::Local::now();
+        if let Ok(started) = chrono::DateTime::parse_from_rfc3339(&self.started) {
+            self.seconds = (now.signed_duration_since(started).num_milliseconds() as f64) / 1000.0;
+        }
+        self.finished = now.to_rfc3339();
+    }
+
+    /// how the pass was checked, in words
+    pub fn verification(&self) -> String {
+        let result = match self.verified {
+            Some(true) => "verified",
+            Some(false) => "FAILED verification",
+            None => return "not verified".to_string()
+        };
+        match &self.sample {
+            Some(sample) => format!("{} by sampling {:.2}% of the disk", result, sample.coverage),
+            None => result.to_string()
+        }
+    }
+}
+
+/// a saved account of a wipe, so it can be looked at again later
+#[derive (Clone, Serialize, Deserialize)]
+pub struct WipeRecord {
+    pub tool_version: String,
+    pub disk: DiskIdentity,
+    pub method: String,
+    /// the seed for any random passes, needed to regenerate their data
+    pub seed: Option<String>,
+    pub started: String,
+    pub finished: Option<String>,
+    pub passes: Vec<PassRecord>,
+    /// whether every pass that was checked passed, `None` if none were
+    #[serde(default)]
+    pub verified: Option<bool>,
+    /// every block that some pass could not write
+    #[serde(default)]
+    pub bad_ranges: Vec<LbaRange>,
+    /// how it went, as given by `status` when the wipe finished
+    #[serde(default)]
+    pub result: String,
+    /// whether the wipe was cancelled before it finished, leaving the disk
+    /// only partly wiped
+    #[serde(default)]
+    pub interrupted: bool
+}
+
+impl WipeRecord {
+    /// starts a record for a wipe of `disk` that is about to begin
+    pub fn new(disk: &DiskData, method: String, seed: Option<String>) -> Self {
+        WipeRecord {
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            disk: DiskIdentity::from(disk),
+            method,
+            seed,
+            started: chrono::offset::Local::now().to_rfc3339(),
+            finished: None,
+            passes: Vec::new(),
+            verified: None,
+            bad_ranges: Vec::new(),
+            result: String::new(),
+            interrupted: false
+        }
+    }
+
+    /// marks the wipe as over and works out the overall verification result
+    pub fn finish(&mut self) {
+        self.finished = Some(chrono::offset::Local::now().to_rfc3339());
+        let checked: Vec<bool> = self.passes.iter().filter_map(|p| p.verified).collect();
+        self.verified = if checked.is_empty() {
+            None
+        } else {
+            Some(checked.iter().all(|v| *v))
+        };
+        self.bad_ranges = self.passes.iter().flat_map(|p| p.bad_ranges.iter().copied()).collect();
+        LbaRange::merge(&mut self.bad_ranges);
+        self.result = self.status().to_string();
+    }
+
+    /// marks the wipe as cancelled part way, which leaves the disk in no
+    /// state in particular
+    pub fn interrupt(&mut self) {
+        self.interrupted = true;
+        self.finish();
+    }
+
+    /// how many blocks could not be written by at least one pass
+    pub fn bad_blocks(&self) -> u64 {
+        self.bad_ranges.iter().map(|r| r.blocks()).sum()
+    }
+
+    /// sums up how the wipe went. a wipe that got to the end but had to skip
+    /// blocks it could not write, or hit other errors, is not a success
+    pub fn status(&self) -> &'static str {
+        if self.interrupted {
+            "incomplete"
+        } else if self.finished.is_none() {
+            "did not finish"
+        } else if self.verified == Some(false) {
+            "FAILED verification"
+        } else if !self.bad_ranges.is_empty() || self.passes.iter().any(|p| !p.errors.is_empty()) {
+            "completed with errors"
+        } else {
+            "success"
+        }
+    }
+
+    /// the error the wipe amounts to, or nothing if it was a success. a wipe
+    /// that failed verification gives the sectors that never held the pass,
+    /// and one that completed with errors, or that failed verification
+    /// without finding any such sectors, its first error
+    pub fn error(&self) -> Option<Error> {
+        match self.status() {
+            "success" => None,
+            "did not finish" | "incomplete" => Some(Error::Aborted),
+            "FAILED verification" if !self.bad_ranges.is_empty() =>
+                Some(Error::Mismatch { path: self.disk.path.clone(), ranges: self.bad_ranges.clone() }),
+            _ => {
+                let offset = self.bad_ranges.first().map(|r| r.first * self.disk.logical_block_size.max(1));
+                let message = match self.passes.iter().flat_map(|p| p.errors.iter()).next() {
+                    Some(a) => a.clone(),
+                    None if self.bad_ranges.is_empty() => format!("{} could not be verified", self.disk.path),
+                    None => format!("{} could not be written", LbaRange::describe(&self.bad_ranges))
+                };
+                Some(Error::Io { offset, message })
+            }
+        }
+    }
+
+    /// the record as JSON
+    pub fn to_json(&self) -> Result<String, Error> {
+        match serde_json::to_string_pretty(self) {
+            Ok(a) => Ok(a),
+            Err(e) => Err(Error::Internal(format!("Failed to serialize wipe record: {}", e)))
+        }
+    }
+
+    /// writes the record to `path` as JSON
+    pub fn save(&self, path: &str) -> Result<(), Error> {
+        match std::fs::write(path, self.to_json()?) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::from_io(&e, format!("Failed to write wipe record to {}: {}", path, e)))
+        }
+    }
+
+    /// reads a record previously saved to `path`
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let json = match std::fs::read_to_string(path) {
+            Ok(a) => a,
+            Err(e) => return Err(Error::from_io(&e, format!("Failed to read wipe record {}: {}", path, e)))
+        };
+        match serde_json::from_str(&json) {
+            Ok(a) => Ok(a),
+            Err(e) => Err(Error::Invalid(format!("Failed to parse wipe record {}: {}", path, e)))
+        }
+    }
+}
+
+impl Display for LbaRange {
+    fn fmt (&self, fmt: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
+        if self.first == self.last {
+            write!(fmt, "{}", self.first)
+        } else {
+            write!(fmt, "{}-{}", self.first, self.last)
+        }
+    }
+}
+
+impl Display for DiskIdentity {
+    fn fmt (&self, fmt: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
+        writeln!(fmt, "Device:     {}", self.path)?;
+        writeln!(fmt, "Vendor:     {}", self.vendor.as_deref().unwrap_or("unknown"))?;
+        writeln!(fmt, "Model:      {}", self.model.as_deref().unwrap_or("unknown"))?;
+        writeln!(fmt, "Serial:     {}", self.serial.as_deref().unwrap_or("unknown"))?;
+        writeln!(fmt, "WWN:        {}", self.wwn.as_deref().unwrap_or("unknown"))?;
+        writeln!(fmt, "Firmware:   {}", self.firmware.as_deref().unwrap_or("unknown"))?;
+        writeln!(fmt, "Transport:  {}", self.transport)?;
+        write!(fmt, "Size:       {} bytes", self.bytes)
+    }
+}
+
+impl Display for WipeRecord {
+    fn fmt (&self, fmt: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
+        writeln!(fmt, "{}", self.disk)?;
+        writeln!(fmt, "Method:     {}", self.method)?;
+        if let Some(seed) = &self.seed {
+            writeln!(fmt, "Seed:       {}", seed)?;
+        }
+        writeln!(fmt, "Started:    {}", self.started)?;
+        writeln!(fmt, "{}   {}", if self.interrupted {"Stopped: "} else {"Finished:"}, self.finished.as_deref().unwrap_or("did not finish"))?;
+        writeln!(fmt, "Tool:       checked_wipe {}", self.tool_version)?;
+        writeln!(fmt, "Passes:")?;
+        for pass in self.passes.iter() {
+            writeln!(fmt, "  #{:<3} {} ({}, {} bytes in {:.1}s)", pass.number, pass.pattern, 
+                pass.verification(), pass.bytes_written, pass.seconds)?;
+            if let Some(offset) = pass.resumed_from {
+                writeln!(fmt, "       resumed at offset {}", offset)?;
+            }
+            if let Some(offset) = pass.stopped_at {
+                writeln!(fmt, "       stopped at offset {} when the wipe was cancelled", offset)?;
+            }
+            if !pass.rewritten.is_empty() {
+                writeln!(fmt, "       rewrote LBA {} after they failed verification",
+                    pass.rewritten.iter().map(|r| r.to_string()).collect::<Vec<String>>().join(", "))?;
+            }
+            if let Some(sample) = &pass.sample {
+                writeln!(fmt, "       sampled {} bytes ({}% asked for), seed {} stream {}", sample.bytes_checked,
+                    sample.requested_percent, sample.seed, sample.stream)?;
+            }
+            for error in pass.errors.iter() {
+                writeln!(fmt, "       {}", error)?;
+            }
+        }
+        if !self.bad_ranges.is_empty() {
+            writeln!(fmt, "Unwritable: {} block(s) of {} bytes, at LBA {}", self.bad_blocks(),
+                self.disk.logical_block_size, self.bad_ranges.iter().map(|r| r.to_string()).collect::<Vec<String>>().join(", "))?;
+        }
+        let verified = match self.verified {
+            Some(true) => "passed",
+            Some(false) => "FAILED",
+            None => "not verified"
+        };
+        writeln!(fmt, "Verified:   {}", verified)?;
+        write!(fmt, "Result:     {}", self.status())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{
+        defs::tests::disk,
+        tests::Scratch
+    };
+
+    /// a record of a wipe of a made up disk, with a pass for each of `verified`
+    fn record(verified: &[Option<bool>]) -> WipeRecord {
+        let mut record = WipeRecord::new(&disk("/dev/sdx", Some("SERIAL1"), None), "test".to_string(), Some("ab".repeat(32)));
+        for (i, verified) in verified.iter().enumerate() {
+            let mut pass = PassRecord::new(i + 1, "zeros".to_string());
+            pass.verified = *verified;
+            pass.finish();
+            record.passes.push(pass);
+        }
+        record.finish();
+        record
+    }
+
+    #[test]
+    fn saved_records_load_back_as_they_were() {
+        let record = record(&[None, Some(true)]);
+        let file = Scratch::new("record.json", 0);
+        record.save(&file.name()).expect("Failed to save record");
+        let loaded = WipeRecord::load(&file.name()).expect("Failed to load record");
+        assert_eq!(serde_json::to_value(&loaded).unwrap(), serde_json::to_value(&record).unwrap());
+        assert_eq!(loaded.disk.serial.as_deref(), Some("SERIAL1"));
+
+        std::fs::write(&file.path, "{\"tool_version\": ").expect("Failed to write record");
+        assert_eq!(WipeRecord::load(&file.name()).err().map(|e| e.kind()), Some("invalid"));
+        assert_eq!(WipeRecord::load("/nonexistent/record.json").err().map(|e| e.kind()), Some("io"));
+    }
+
+    #[test]
+    fn a_wipe_fails_if_any_pass_failed_its_check() {
+        let passed = record(&[None, Some(true)]);
+        assert_eq!(passed.verified, Some(true));
+        assert_eq!((passed.status(), &passed.result[..]), ("success", "success"));
+        assert!(passed.to_string().ends_with("Result:     success"));
+
+        let failed = record(&[Some(false), Some(true)]);
+        assert_eq!(failed.verified, Some(false));
+        assert_eq!(failed.status(), "FAILED verification");
+        assert!(failed.to_string().contains("#1   zeros (FAILED verification, 0 bytes in"), "{}", failed);
+        assert!(failed.to_string().ends_with("Result:     FAILED verification"));
+
+        let unchecked = record(&[None]);
+        assert_eq!(unchecked.verified, None);
+        assert_eq!(unchecked.status(), "success");
+    }
+
+    #[test]
+    fn write_errors_and_unfinished_wipes_are_not_a_success() {
+        let mut errored = record(&[Some(true)]);
+        errored.passes[0].errors.push("write error at offset 0: Input/output error".to_string());
+        errored.finish();
+        assert_eq!(errored.status(), "completed with errors");
+        assert!(errored.to_string().contains("       write error at offset 0"), "{}", errored);
+
+        // blocks that could not be written are gathered up from every pass
+        let mut skipped = record(&[Some(true), Some(true)]);
+        skipped.passes[0].bad_ranges.push(LbaRange { first: 8, last: 9 });
+        skipped.passes[1].bad_ranges.push(LbaRange::single(10));
+        skipped.finish();
+        assert_eq!((skipped.status(), skipped.bad_blocks()), ("completed with errors", 3));
+        assert!(skipped.to_string().contains("Unwritable: 3 block(s) of 512 bytes, at LBA 8-10"), "{}", skipped);
+
+        let mut unfinished = record(&[Some(true)]);
+        unfinished.finished = None;
+        assert_eq!(unfinished.status(), "did not finish");
+    }
+
+    #[test]
+    fn merges_ranges_that_touch_and_describes_them() {
+        let mut ranges = vec![LbaRange { first: 10, last: 12 }, LbaRange::single(3), LbaRange { first: 13, last: 20 },
+            LbaRange::single(5), LbaRange { first: 11, last: 15 }];
+        LbaRange::merge(&mut ranges);
+        assert_eq!(ranges.iter().map(|r| r.to_string()).collect::<Vec<String>>(), vec!["3", "5", "10-20"]);
+        assert_eq!(LbaRange::describe(&ranges), "13 sector(s) at LBA 3, 5, 10-20");
+    }
+
+    #[test]
+    fn records_from_before_pass_timings_still_load() {
+        let mut json = serde_json::to_value(record(&[Some(true)])).unwrap();
+        let pass = json["passes"][0].as_object_mut().unwrap();
+        for field in ["started", "finished", "seconds", "bytes_written", "errors"].iter() {
+            pass.remove(*field);
+        }
+        json.as_object_mut().unwrap().remove("verified");
+        let loaded: WipeRecord = serde_json::from_value(json).expect("Failed to load old record");
+        assert_eq!(loaded.passes[0].verified, Some(true));
+        assert!(loaded.passes[0].errors.is_empty());
+        assert_eq!(loaded.verified, None);
+    }
+
+    #[test]
+    fn says_how_each_pass_was_checked() {
+        let mut pass = PassRecord::new(1, "0x00".to_string());
+        assert_eq!(pass.verification(), "not verified");
+        pass.verified = Some(true);
+        assert_eq!(pass.verification(), "verified");
+        pass.verified = Some(false);
+        pass.sample = Some(SampleRecord { requested_percent: 5.0, seed: "00".to_string(), stream: 1, bytes_checked: 1, coverage: 5.123 });
+        assert_eq!(pass.verification(), "FAILED verification by sampling 5.12% of the disk");
+    }
+
+    #[test]
+    fn notes_sectors_that_were_rewritten_after_a_failed_check() {
+        let mut fixed = record(&[Some(true)]);
+        fixed.passes[0].rewritten = vec![LbaRange { first: 4, last: 7 }, LbaRange::single(100)];
+        fixed.finish();
+        assert_eq!(fixed.status(), "success");
+        assert!(fixed.to_string().contains("       rewrote LBA 4-7, 100 after they failed verification"), "{}", fixed);
+    }
+
+    /// the status of `record` and the exit code of the error it amounts to
+    fn outcome(record: &WipeRecord) -> (&'static str, Option<i32>) {
+        (record.status(), record.error().map(|e| e.exit_code()))
+    }
+
+    #[test]
+    fn status_and_error_follow_how_the_wipe_went() {
+        let mut record = WipeRecord::new(&disk("/dev/sdx", None, None), "test".to_string(), None);
+        record.passes.push(PassRecord::new(1, "0x00".to_string()));
+        assert_eq!(outcome(&record), ("did not finish", Some(130)));
+
+        record.passes[0].verified = Some(true);
+        record.finish();
+        assert_eq!((outcome(&record), record.verified), (("success", None), Some(true)));
+
+        // blocks that could not be written say where the first one was
+        record.passes[0].bad_ranges.push(LbaRange { first: 8, last: 9 });
+        record.finish();
+        assert_eq!(outcome(&record), ("completed with errors", Some(6)));
+        assert!(matches!(record.error(), Some(Error::Io { offset: Some(4096), .. })));
+
+        record.passes.push(PassRecord::new(2, "0xFF".to_string()));
+        record.passes[1].verified = Some(false);
+        record.finish();
+        assert_eq!((outcome(&record), record.verified), (("FAILED verification", Some(7)), Some(false)));
+
+        // being cancelled outweighs everything else
+        record.interrupt();
+        assert_eq!((outcome(&record), &record.result[..]), (("incomplete", Some(130)), "incomplete"));
+        assert!(record.to_string().contains("Stopped:    "), "{}", record);
+    }
+
+    #[test]
+    fn failed_checks_that_found_no_bad_sectors_are_io_errors() {
+        let mut record = record(&[Some(false)]);
+        record.passes[0].errors.push("Read error at offset 8192: Input/output error".to_string());
+        record.finish();
+        assert_eq!(outcome(&record), ("FAILED verification", Some(6)));
+        assert!(matches!(record.error(), Some(Error::Io { offset: None, message }) if message.starts_with("Read error at offset 8192")));
+
+        record.passes[0].errors.clear();
+        assert!(matches!(record.error(), Some(Error::Io { message, .. }) if message == "/dev/sdx could not be verified"));
+    }
+}
@@ -0,0 +1,449 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    os::unix::fs::MetadataExt,
+    path::Path,
+    process::Command
+};
+use nix::sys::stat::{
+    major,
+    minor
+};
+use serde::Serialize;
+use super::sysfs::{
+    SYS_BLOCK,
+    read_attr,
+    list_dir,
+    class_dir
+};
+
+/// where the kernel links every block device by its `major:minor` number
+const SYS_DEV_BLOCK: &str = "/sys/dev/block";
+/// the mount points the running system cannot do without
+const SYSTEM_MOUNTS: [&str; 2] = ["/", "/boot"];
+
+/// a reason a disk must not be wiped. each one names the device (the disk
+/// itself or one of its partitions) that is being used
+#[derive (Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Hazard {
+    /// holds a filesystem the running system needs, `/` or `/boot`, possibly
+    /// through a volume or mapping stacked on top of it
+    System {
+        device: String,
+        at: String
+    },
+    /// holds a mounted filesystem, however the mount names its source
+    Mounted {
+        device: String,
+        at: String
+    },
+    /// is in use as swap
+    Swap {
+        device: String
+    },
+    /// is claimed by another block device, such as an LVM volume, a RAID
+    /// array or a dm-crypt mapping, which `what` describes
+    Held {
+        device: String,
+        holder: String,
+        what: String
+    },
+    /// is named in the device-mapper table of `mapping`
+    Mapped {
+        device: String,
+        mapping: String
+    },
+    /// is a disk image with a loop device attached to it
+    LoopBacking {
+        device: String,
+        loop_device: String
+    }
+}
+
+impl Hazard {
+    /// whether the hazard is the device holding a mounted filesystem
+    pub fn is_mount(&self) -> bool {
+        matches!(self, Hazard::System { .. } | Hazard::Mounted { .. })
+    }
+
+    /// the device the hazard is about
+    pub fn device(&self) -> &str {
+        match self {
+            Hazard::System { device, .. } | Hazard::Mounted { device, .. } | Hazard::Swap { device }
+                | Hazard::Held { device, .. } | Hazard::Mapped { device, .. } | Hazard::LoopBacking { device, .. } => device
+        }
+    }
+}
+
+impl Display for Hazard {
+    fn fmt (&self, fmt: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
+        match self {
+            Hazard::System { device, at } => write!(fmt, "{} holds {}, which the running system needs", device, at),
+            Hazard::Mounted { device, at } => write!(fmt, "{} is mounted at {}", device, at),
+            Hazard::Swap { device } => write!(fmt, "{} is in use as swap", device),
+            Hazard::Held { device, holder, what } => write!(fmt, "{} is part of {} (/dev/{})", device, what, holder),
+            Hazard::Mapped { device, mapping } => write!(fmt, "{} is used by the device-mapper table of {}", device, mapping),
+            Hazard::LoopBacking { device, loop_device } => write!(fmt, "{} is attached to {}", device, loop_device)
+        }
+    }
+}
+
+/// everything on the system that is using a block device, gathered once
+/// so every disk can be checked against the same picture
+pub struct Usage {
+    /// the hazards found, by the kernel name of the device they are about
+    hazards: HashMap<String, Vec<Hazard>>
+}
+
+impl Usage {
+    /// looks through mounts, swap, holders and device-mapper tables for
+    /// anything using a block device. sources that cannot be read (no
+    /// `dmsetup`, say) are skipped, as the others still cover most of it
+    pub fn scan() -> Self {
+        let mut usage = Usage { hazards: HashMap::new() };
+        usage.scan_mounts();
+        usage.scan_swaps();
+        usage.scan_holders();
+        usage.scan_tables();
+        usage
+    }
+
+    /// every hazard on the devices named (by kernel name, such as `sda` and `sda1`)
+    pub fn hazards(&self, names: &[&str]) -> Vec<Hazard> {
+        names.iter()
+            .filter_map(|name| self.hazards.get(*name))
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    /// records `hazard` against the device `name`, once
+    fn add(&mut self, name: &str, hazard: Hazard) {
+        let found = self.hazards.entry(name.to_string()).or_default();
+        if !found.contains(&hazard) {
+            found.push(hazard);
+        }
+    }
+
+    /// goes through the mount table by device number, so bind mounts and
+    /// sources named through `/dev/mapper` or `/dev/disk/by-*` still count.
+    /// whatever is under `/` and `/boot` is followed down through any
+    /// volumes and mappings to the disks that really hold it
+    fn scan_mounts(&mut self) {
+        let mounts = match std::fs::read_to_string("/proc/self/mountinfo") {
+            Ok(a) => a,
+            Err(_) => return
+        };
+        for mount in parse_mountinfo(&mounts) {
+            // filesystems like btrfs give an anonymous device number, so go by the source instead
+            let name = match name_of_dev(&mount.dev).or_else(|| mount.source.as_deref().and_then(name_of_path)) {
+                Some(a) => a,
+                None => continue
+            };
+            if SYSTEM_MOUNTS.contains(&&mount.at[..]) {
+                for lower in stacked_under(&name) {
+                    self.add(&lower, Hazard::System { device: format!("/dev/{}", lower), at: mount.at.clone() });
+                }
+            } else if !self.hazards.get(&name).map(|h| h.iter().any(|h| matches!(h, Hazard::Mounted { .. }))).unwrap_or(false) {
+                // a filesystem mounted in several places only needs naming once
+                self.add(&name, Hazard::Mounted { device: format!("/dev/{}", name), at: mount.at });
+            }
+        }
+    }
+
+    /// goes through the active swap areas. swap files are left out, as the
+    /// filesystem holding them shows up as mounted
+    fn scan_swaps(&mut self) {
+        let swaps = match std::fs::read_to_string("/proc/swaps") {
+            Ok(a) => a,
+            Err(_) => return
+        };
+        for name in parse_swaps(&swaps).iter().filter_map(|path| name_of_path(path)) {
+            self.add(&name, Hazard::Swap { device: format!("/dev/{}", name) });
+        }
+    }
+
+    /// goes through the holders of every disk and partition, which is how
+    /// the kernel shows LVM, mdraid, dm-crypt, multipath and bcache claiming a device
+    fn scan_holders(&mut self) {
+        for disk in list_dir(Path::new(SYS_BLOCK)).unwrap_or_default() {
+            let mut names = vec![disk.clone()];
+            names.extend(partitions_of(&disk));
+            for name in names {
+                for holder in list_dir(&class_dir(&name).join("holders")).unwrap_or_default() {
+                    let what = describe_holder(&holder);
+                    self.add(&name, Hazard::Held { device: format!("/dev/{}", name), holder, what });
+                }
+            }
+        }
+    }
+
+    /// goes through the device-mapper tables, which name the devices they
+    /// are built on by number. this also catches tables that have been loaded
+    /// but not resumed, which do not claim their devices yet
+    fn scan_tables(&mut self) {
+        let output = match Command::new("dmsetup").arg("table").output() {
+            Ok(a) if a.status.success() => a,
+            _ => return
+        };
+        for (mapping, devices) in parse_tables(&String::from_utf8_lossy(&output.stdout)) {
+            for name in devices.iter().filter_map(|dev| name_of_dev(dev).or_else(|| name_of_path(dev))) {
+                // a mapping that already holds the device has said all this
+                let held = self.hazards.get(&name).map(|h| h.iter().any(|h| match h {
+                    Hazard::Held { holder, .. } => read_attr(&class_dir(holder), "dm/name").as_deref() == Some(&mapping[..]),
+                    _ => false
+                })).unwrap_or(false);
+                if !held {
+                    self.add(&name, Hazard::Mapped { device: format!("/dev/{}", name), mapping: mapping.clone() });
+                }
+            }
+        }
+    }
+}
+
+/// a filesystem mounted somewhere, from a line of `/proc/self/mountinfo`
+#[derive (Debug, PartialEq)]
+struct MountEntry {
+    /// the device number (`major:minor`) the filesystem reports, which is
+    /// anonymous (`0:N`) for some, like btrfs and anything not on a disk
+    dev: String,
+    /// what was mounted, as the mount named it
+    source: Option<String>,
+    at: String
+}
+
+/// reads the mount table in the format of `/proc/self/mountinfo`
+fn parse_mountinfo(text: &str) -> Vec<MountEntry> {
+    text.lines().filter_map(|line| {
+        // id parent major:minor root mount-point options [optional...] - type source options
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let source = line.split(" - ").nth(1).and_then(|rest| rest.split_whitespace().nth(1)).map(unescape);
+        Some(MountEntry { dev: fields.get(2)?.to_string(), source, at: unescape(fields.get(4)?) })
+    }).collect()
+}
+
+/// reads the swap partitions out of `/proc/swaps`, leaving out swap files
+fn parse_swaps(text: &str) -> Vec<String> {
+    text.lines().skip(1).filter_map(|line| {
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some(path), Some("partition")) => Some(unescape(path)),
+            _ => None
+        }
+    }).collect()
+}
+
+/// reads the output of `dmsetup table` into each mapping and the devices
+/// its table is built on, by number (`major:minor`) or by path
+fn parse_tables(text: &str) -> Vec<(String, Vec<String>)> {
+    text.lines().filter_map(|line| {
+        let (mapping, table) = line.split_once(": ")?;
+        let devices = table.split_whitespace()
+            .filter(|field| field.starts_with("/dev/") || is_dev_number(field))
+            .map(|field| field.to_string())
+            .collect();
+        Some((mapping.to_string(), devices))
+    }).collect()
+}
+
+/// whether `field` looks like a device number, `major:minor`
+fn is_dev_number(field: &str) -> bool {
+    match field.split_once(':') {
+        Some((maj, min)) => maj.parse::<u64>().is_ok() && min.parse::<u64>().is_ok(),
+        None => false
+    }
+}
+
+/// the loop devices that have the file at `path` attached
+pub fn loops_attached_to(path: &str) -> Vec<String> {
+    list_dir(Path::new(SYS_BLOCK)).unwrap_or_default().into_iter()
+        .filter(|name| name.starts_with("loop"))
+        .filter(|name| read_attr(&class_dir(name), "loop/backing_file").as_deref() == Some(path))
+        .map(|name| format!("/dev/{}", name))
+        .collect()
+}
+
+/// the partitions of the disk `name`, which show up as subdirectories that have a `partition` file
+pub fn partitions_of(name: &str) -> Vec<String> {
+    let dir = class_dir(name);
+    list_dir(&dir).unwrap_or_default().into_iter()
+        .filter(|entry| dir.join(entry).join("partition").exists())
+        .collect()
+}
+
+/// the kernel name of the block device numbered `dev` (`major:minor`)
+fn name_of_dev(dev: &str) -> Option<String> {
+    // major 0 is for anonymous devices, which have no node of their own
+    if !is_dev_number(dev) || dev.starts_with("0:") {
+        return None;
+    }
+    let link = std::fs::read_link(Path::new(SYS_DEV_BLOCK).join(dev)).ok()?;
+    link.file_name().map(|n| n.to_string_lossy().into_owned())
+}
+
+/// the kernel name of the block device node at `path`, by its device number
+fn name_of_path(path: &str) -> Option<String> {
+    if !path.starts_with('/') {
+        return None;
+    }
+    let meta = std::fs::metadata(path).ok()?;
+    if meta.rdev() == 0 {
+        return None;
+    }
+    name_of_dev(&format!("{}:{}", major(meta.rdev()), minor(meta.rdev())))
+}
+
+/// `name` and every device it is built on, down to the disks (or
+/// partitions) at the bottom
+fn stacked_under(name: &str) -> Vec<String> {
+    let mut found = Vec::new();
+    let mut todo = vec![name.to_string()];
+    while let Some(name) = todo.pop() {
+        if !found.contains(&name) {
+            todo.extend(list_dir(&class_dir(&name).join("slaves")).unwrap_or_default());
+            found.push(name);
+        }
+    }
+    found
+}
+
+/// says what sort of device `holder` is, along with its name if it is a
+/// device-mapper device
+fn describe_holder(holder: &str) -> String {
+    let dir = class_dir(holder);
+    let what = holder_kind(holder, &read_attr(&dir, "dm/uuid").unwrap_or_default());
+    match read_attr(&dir, "dm/name") {
+        Some(name) => format!("{} {}", what, name),
+        None => what.to_string()
+    }
+}
+
+/// what sort of device the holder `holder` is, from its name and the uuid
+/// device-mapper gives it, which starts with whatever set it up
+fn holder_kind(holder: &str, uuid: &str) -> &'static str {
+    if holder.starts_with("md") {
+        "RAID array"
+    } else if holder.starts_with("bcache") {
+        "bcache device"
+    } else if uuid.starts_with("LVM-") {
+        "LVM logical volume"
+    } else if uuid.starts_with("CRYPT-") {
+        "dm-crypt mapping"
+    } else if uuid.starts_with("mpath-") {
+        "multipath device"
+    } else if uuid.starts_with("part") {
+        "partition mapping"
+    } else {
+        "device-mapper device"
+    }
+}
+
+/// undoes the octal escapes (`\040` for a space) the kernel puts in paths
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out: Vec<u8> = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = bytes.get(i + 1..i + 4)
+            .filter(|_| bytes[i] == b'\\')
+            .and_then(|oct| std::str::from_utf8(oct).ok())
+            .and_then(|oct| u8::from_str_radix(oct, 8).ok());
+        match byte {
+            Some(a) => {
+                out.push(a);
+                i += 4;
+            },
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescapes_octal_escapes_and_leaves_the_rest() {
+        assert_eq!(unescape("/mnt/my\\040disk"), "/mnt/my disk");
+        assert_eq!(unescape("/a\\011b\\134c\\012"), "/a\tb\\c\n");
+        assert_eq!(unescape("/plain"), "/plain");
+        // anything that is not a full escape is kept as it is
+        assert_eq!(unescape("/odd\\9x\\04"), "/odd\\9x\\04");
+        assert_eq!(unescape("/caf\\303\\251"), "/caf\u{e9}");
+    }
+
+    #[test]
+    fn reads_mounts_with_escaped_paths_and_anonymous_devices() {
+        let text = "22 1 254:0 / / rw,relatime shared:1 - ext4 /dev/vda rw\n\
+            40 22 8:17 / /mnt/my\\040disk rw master:3 shared:9 - xfs /dev/sdb1 rw,attr2\n\
+            41 22 0:45 /@home /home rw,relatime shared:12 - btrfs /dev/mapper/luks\\040root rw,space_cache\n\
+            50 22 0:22 / /proc rw - proc proc rw\n\
+            broken line\n";
+        assert_eq!(parse_mountinfo(text), vec![
+            MountEntry { dev: "254:0".to_string(), source: Some("/dev/vda".to_string()), at: "/".to_string() },
+            MountEntry { dev: "8:17".to_string(), source: Some("/dev/sdb1".to_string()), at: "/mnt/my disk".to_string() },
+            MountEntry { dev: "0:45".to_string(), source: Some("/dev/mapper/luks root".to_string()), at: "/home".to_string() },
+            MountEntry { dev: "0:22".to_string(), source: Some("proc".to_string()), at: "/proc".to_string() }
+        ]);
+
+        // anonymous devices have to be found by their source instead
+        assert_eq!(name_of_dev("0:45"), None);
+        assert_eq!(name_of_path("proc"), None);
+        assert!(!is_dev_number("32:logon") && !is_dev_number("7") && is_dev_number("253:0"));
+    }
+
+    #[test]
+    fn reads_swap_partitions_but_not_swap_files() {
+        let text = "Filename\t\t\t\tType\t\tSize\t\tUsed\t\tPriority\n\
+            /dev/sda2                               partition\t8388604\t\t0\t\t-2\n\
+            /swap\\040file                          file\t\t1048572\t\t0\t\t-3\n\
+            /dev/dm-1                               partition\t2097148\t\t0\t\t-4\n";
+        assert_eq!(parse_swaps(text), vec!["/dev/sda2".to_string(), "/dev/dm-1".to_string()]);
+        assert!(parse_swaps("Filename\tType\tSize\tUsed\tPriority\n").is_empty());
+    }
+
+    #[test]
+    fn reads_the_devices_each_mapping_is_built_on() {
+        let text = "vg-root: 0 104857600 linear 8:2 2048\n\
+            vg-root: 104857600 2097152 linear 8:18 2048\n\
+            luks-1234: 0 976773168 crypt aes-xts-plain64 :64:logon:cryptsetup:1234-d0 0 259:2 32768\n\
+            mirror0: 0 2048 mirror core 1 1024 2 7:1 0 /dev/loop2 0 1 handle_errors\n\
+            No devices found\n";
+        assert_eq!(parse_tables(text), vec![
+            ("vg-root".to_string(), vec!["8:2".to_string()]),
+            ("vg-root".to_string(), vec!["8:18".to_string()]),
+            ("luks-1234".to_string(), vec!["259:2".to_string()]),
+            ("mirror0".to_string(), vec!["7:1".to_string(), "/dev/loop2".to_string()])
+        ]);
+    }
+
+    #[test]
+    fn names_what_holds_a_device() {
+        assert_eq!(holder_kind("md127", ""), "RAID array");
+        assert_eq!(holder_kind("bcache0", ""), "bcache device");
+        assert_eq!(holder_kind("dm-0", "LVM-Abc123"), "LVM logical volume");
+        assert_eq!(holder_kind("dm-1", "CRYPT-LUKS2-1234-luks-1234"), "dm-crypt mapping");
+        assert_eq!(holder_kind("dm-2", "mpath-3600508b"), "multipath device");
+        assert_eq!(holder_kind("dm-3", "part1-mpath-3600508b"), "partition mapping");
+        assert_eq!(holder_kind("dm-4", ""), "device-mapper device");
+    }
+
+    #[test]
+    fn describes_each_hazard_in_words_and_json() {
+        let swap = Hazard::Swap { device: "/dev/sda2".to_string() };
+        assert_eq!(swap.to_string(), "/dev/sda2 is in use as swap");
+        assert_eq!(serde_json::to_value(&swap).unwrap(), serde_json::json!({"kind": "swap", "device": "/dev/sda2"}));
+
+        let system = Hazard::System { device: "/dev/vda".to_string(), at: "/".to_string() };
+        assert_eq!(system.to_string(), "/dev/vda holds /, which the running system needs");
+        assert!(system.is_mount() && !swap.is_mount());
+        let attached = Hazard::LoopBacking { device: "/tmp/disk.img".to_string(), loop_device: "/dev/loop0".to_string() };
+        assert_eq!(serde_json::to_value(&attached).unwrap(),
+            serde_json::json!({"kind": "loop_backing", "device": "/tmp/disk.img", "loop_device": "/dev/loop0"}));
+    }
+}
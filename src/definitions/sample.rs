@@ -0,0 +1,193 @@
+use std::fmt::Display;
+use rand_chacha::ChaCha20Rng;
+use rand_core::{
+    RngCore,
+    SeedableRng
+};
+use serde::{
+    Serialize,
+    Deserialize
+};
+use super::{
+    pattern::{
+        seed_to_hex,
+        SEED_LEN
+    },
+    record::SampleRecord,
+    error::Error
+};
+
+/// how much is always read from each end of the disk when sampling. that is
+/// where partition tables, boot sectors and backup GPT headers live
+pub const SAMPLE_EDGE_BYTES: u64 = 16 * 1024 * 1024;
+
+/// how much of the disk gets read back to check a pass
+#[derive (Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum VerifyMode {
+    /// every byte
+    Full,
+    /// roughly this percentage of the disk, in randomly chosen blocks
+    Sample(f64)
+}
+
+impl VerifyMode {
+    /// parses `full` or `sample:<percent>`, such as `sample:5` or `sample:0.5%`
+    pub fn parse(mode: &str) -> Result<Self, Error> {
+        let mode = mode.trim();
+        if mode == "full" {
+            return Ok(VerifyMode::Full);
+        }
+        let percent = match mode.strip_prefix("sample:") {
+            Some(a) => a.trim_end_matches('%'),
+            None => return Err(Error::Invalid(format!("unknown verification mode {}, expected full or sample:<percent>", mode)))
+        };
+        match percent.parse::<f64>() {
+            Ok(a) if a > 0.0 && a <= 100.0 => Ok(VerifyMode::Sample(a)),
+            Ok(_) => Err(Error::Invalid(format!("sample percentage must be above 0 and at most 100, not {}", percent))),
+            Err(e) => Err(Error::Invalid(format!("invalid sample percentage {}: {}", percent, e)))
+        }
+    }
+
+    /// the blocks of a disk of `size` bytes to read when checking pass
+    /// number `pass` (counting from 1), or nothing if every byte gets read.
+    /// each pass samples with its number as the stream, so a later check of
+    /// the same pass with the same seed reads the same blocks
+    pub fn plan(&self, size: u64, block_size: u64, seed: [u8; SEED_LEN], pass: u64) -> Option<SamplePlan> {
+        match self {
+            VerifyMode::Full => None,
+            VerifyMode::Sample(percent) => Some(SamplePlan::new(size, block_size, *percent, seed, pass))
+        }
+    }
+}
+
+impl Display for VerifyMode {
+    fn fmt (&self, fmt: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
+        match self {
+            VerifyMode::Full => write!(fmt, "full"),
+            VerifyMode::Sample(percent) => write!(fmt, "sample:{}%", percent)
+        }
+    }
+}
+
+/// the parts of a disk picked for a sampled check. the same seed, stream,
+/// size and block size always pick the same blocks, so a check can be repeated
+pub struct SamplePlan {
+    pub percent: f64,
+    pub seed: [u8; SEED_LEN],
+    pub stream: u64,
+    /// the byte ranges to read, sorted and not touching
+    pub ranges: Vec<(u64, u64)>,
+    size: u64
+}
+
+impl SamplePlan {
+    /// picks about `percent` of a disk of `size` bytes to read, in blocks of
+    /// `block_size`. the first and last `SAMPLE_EDGE_BYTES` are always read.
+    /// the rest of the disk is cut into as many equal slices as there are
+    /// blocks left to read, and one block is picked at random from each, so
+    /// the sample reaches every part of the disk
+    pub fn new(size: u64, block_size: u64, percent: f64, seed: [u8; SEED_LEN], stream: u64) -> Self {
+        let block_size = block_size.max(1);
+        let blocks = size.div_ceil(block_size);
+        let edge = SAMPLE_EDGE_BYTES.div_ceil(block_size);
+        let mut plan = SamplePlan { percent, seed, stream, ranges: Vec::new(), size };
+
+        // small disks are read in full
+        if blocks <= edge * 2 {
+            if size > 0 {
+                plan.ranges.push((0, size));
+            }
+            return plan;
+        }
+
+        let middle = blocks - edge * 2;
+        let wanted = (size as f64 * percent / 100.0).ceil() as u64;
+        let picks = wanted.saturating_sub(edge * 2 * block_size).div_ceil(block_size).min(middle);
+
+        let mut rng = ChaCha20Rng::from_seed(seed);
+        rng.set_stream(stream);
+        let mut chosen: Vec<u64> = (0..edge).collect();
+        for i in 0..picks {
+            let lo = edge + (i as u128 * middle as u128 / picks as u128) as u64;
+            let hi = edge + ((i + 1) as u128 * middle as u128 / picks as u128) as u64;
+            chosen.push(lo + rng.next_u64() % (hi - lo));
+        }
+        chosen.extend(blocks - edge..blocks);
+
+        // turn the blocks into byte ranges, joining up neighbours so they are read in one go
+        for block in chosen {
+            let (start, end) = (block * block_size, std::cmp::min((block + 1) * block_size, size));
+            match plan.ranges.last_mut() {
+                Some(last) if last.1 == start => last.1 = end,
+                _ => plan.ranges.push((start, end))
+            }
+        }
+        plan
+    }
+
+    /// how many bytes the plan reads
+    pub fn bytes(&self) -> u64 {
+        self.ranges.iter().map(|(start, end)| end - start).sum()
+    }
+
+    /// the percentage of the disk the plan reads
+    pub fn coverage(&self) -> f64 {
+        if self.size == 0 {
+            return 100.0;
+        }
+        self.bytes() as f64 * 100.0 / self.size as f64
+    }
+
+    /// what to note down about the plan, so the same check can be run again
+    pub fn record(&self) -> SampleRecord {
+        SampleRecord {
+            requested_percent: self.percent,
+            seed: seed_to_hex(&self.seed),
+            stream: self.stream,
+            bytes_checked: self.bytes(),
+            coverage: self.coverage()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_full_and_sampled_modes() {
+        assert!(VerifyMode::parse("full").ok() == Some(VerifyMode::Full));
+        assert!(VerifyMode::parse(" sample:5").ok() == Some(VerifyMode::Sample(5.0)));
+        assert!(VerifyMode::parse("sample:0.5%").ok() == Some(VerifyMode::Sample(0.5)));
+        assert_eq!(VerifyMode::Sample(2.5).to_string(), "sample:2.5%");
+        assert!(["sample:0", "sample:101", "sample:", "sample:x", "half", "5"].iter().all(|m| VerifyMode::parse(m).is_err()));
+    }
+
+    #[test]
+    fn samples_always_cover_both_ends_and_repeat_with_the_same_seed() {
+        let size = 4096 * 1024 * 1024u64 + 1000;
+        let plan = SamplePlan::new(size, 65536, 1.0, [3; SEED_LEN], 2);
+        assert_eq!(plan.ranges.first().map(|r| r.0), Some(0));
+        assert!(plan.ranges[0].1 >= SAMPLE_EDGE_BYTES);
+        assert_eq!(plan.ranges.last().map(|r| r.1), Some(size));
+        assert!(plan.ranges.windows(2).all(|w| w[0].1 < w[1].0), "ranges overlap or touch");
+        assert!((plan.coverage() - 1.0).abs() < 0.01, "coverage {}", plan.coverage());
+
+        let again = SamplePlan::new(size, 65536, 1.0, [3; SEED_LEN], 2);
+        assert!(plan.ranges == again.ranges);
+        let other = SamplePlan::new(size, 65536, 1.0, [3; SEED_LEN], 3);
+        assert!(plan.ranges != other.ranges);
+
+        let record = plan.record();
+        assert_eq!((record.stream, record.bytes_checked, record.seed), (2, plan.bytes(), "03".repeat(SEED_LEN)));
+    }
+
+    #[test]
+    fn small_disks_are_read_in_full() {
+        let plan = SamplePlan::new(20 * 1024 * 1024, 4096, 5.0, [0; SEED_LEN], 1);
+        assert!(plan.ranges == vec![(0, 20 * 1024 * 1024)]);
+        assert_eq!(plan.coverage(), 100.0);
+        assert!(SamplePlan::new(0, 4096, 5.0, [0; SEED_LEN], 1).ranges.is_empty());
+        assert!(VerifyMode::Full.plan(20 * 1024 * 1024, 4096, [0; SEED_LEN], 1).is_none());
+    }
+}
@@ -0,0 +1,186 @@
+use std::path::{
+    Path,
+    PathBuf
+};
+
+/// where the kernel lists every whole disk
+pub const SYS_BLOCK: &str = "/sys/block";
+/// where the kernel lists every block device, partitions included
+pub const SYS_CLASS_BLOCK: &str = "/sys/class/block";
+/// where udev keeps the properties it has worked out for each device
+const UDEV_DATA: &str = "/run/udev/data";
+
+/// the unit sysfs reports sizes and offsets in, no matter the real sector size
+pub const SYSFS_SECTOR: u64 = 512;
+
+
+/// reads a sysfs attribute of the device at `dir`, trimming the trailing newline
+pub fn read_attr(dir: &Path, attr: &str) -> Option<String> {
+    match std::fs::read_to_string(dir.join(attr)) {
+        Ok(a) => Some(a.trim().to_string()),
+        Err(_) => None
+    }
+}
+
+/// reads a numeric sysfs attribute of the device at `dir`
+pub fn read_u64(dir: &Path, attr: &str) -> Option<u64> {
+    read_attr(dir, attr).and_then(|a| a.parse::<u64>().ok())
+}
+
+/// reads a sysfs flag (`0` or `1`) of the device at `dir`
+pub fn read_flag(dir: &Path, attr: &str) -> bool {
+    read_u64(dir, attr) == Some(1)
+}
+
+/// looks up a property (such as `ID_FS_TYPE`) in udev's database for the
+/// device at `dir`. gives nothing if udev is not running
+pub fn udev_property(dir: &Path, key: &str) -> Option<String> {
+    let dev = read_attr(dir, "dev")?;
+    let data = std::fs::read_to_string(Path::new(UDEV_DATA).join(format!("b{}", dev))).ok()?;
+    let prefix = format!("E:{}=", key);
+    data.lines()
+        .find_map(|l| l.strip_prefix(&prefix[..]))
+        .map(|v| v.to_string())
+}
+
+/// reads the unit serial number the kernel cached from the device's vital
+/// product data (SCSI inquiry page 0x80), which is how SATA and SAS disks
+/// expose the serial from their identify data
+pub fn read_vpd_serial(dir: &Path) -> Option<String> {
+    let page = std::fs::read(dir.join("device/vpd_pg80")).ok()?;
+    // 4 byte header, with the length of the serial in the last byte
+    let len = *page.get(3)? as usize;
+    let serial = String::from_utf8_lossy(page.get(4..4 + len)?).trim().to_string();
+    if serial.is_empty() {
+        None
+    } else {
+        Some(serial)
+    }
+}
+
+/// works out how a disk is attached by looking at where it sits in the
+/// kernel's device tree
+pub fn transport(dir: &Path) -> String {
+    let real = match std::fs::canonicalize(dir) {
+        Ok(a) => a.to_string_lossy().into_owned(),
+        Err(_) => return "unknown".to_string()
+    };
+
+    // order matters here, as a USB or SAS disk will still have an scsi
+    // host above it and everything sits somewhere below a pci bus
+    let transport = if real.contains("/usb") {
+        "USB"
+    } else if real.contains("/nvme") {
+        "NVMe"
+    } else if real.contains("/virtio") {
+        "virtio"
+    } else if real.contains("/mmc") {
+        "MMC"
+    } else if real.contains("/ata") {
+        "SATA"
+    } else if real.contains("/end_device-") || real.contains("/sas_") {
+        "SAS"
+    } else if real.contains("/virtual/") {
+        "virtual"
+    } else {
+        "unknown"
+    };
+
+    transport.to_string()
+}
+
+/// lists the names of the entries in a sysfs directory, sorted so that
+/// devices come out in a stable order
+pub fn list_dir(dir: &Path) -> Result<Vec<String>, String> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(a) => a,
+        Err(e) => return Err(format!("Failed to read {}: {}", dir.display(), e))
+    };
+
+    let mut names: Vec<String> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort_by(|a, b| natural_cmp(a, b));
+
+    Ok(names)
+}
+
+/// gives the sysfs directory of any block device by name
+pub fn class_dir(name: &str) -> PathBuf {
+    Path::new(SYS_CLASS_BLOCK).join(name)
+}
+
+/// compares device names so that `sda2` sorts before `sda10`
+fn natural_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    let split = |s: &str| {
+        let idx = s.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+        (s[..idx].to_string(), s[idx..].parse::<u64>().unwrap_or(0))
+    };
+    split(a).cmp(&split(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tests::Scratch;
+
+    #[test]
+    fn reads_trimmed_attributes_numbers_and_flags() {
+        let dev = Scratch::dir("sysfs");
+        std::fs::create_dir_all(dev.path.join("queue")).expect("Failed to create queue directory");
+        for (attr, value) in [("size", "2048\n"), ("removable", "1\n"), ("queue/rotational", "0\n"), ("model", "  Flash Disk \n")].iter() {
+            std::fs::write(dev.path.join(attr), value).expect("Failed to write attribute");
+        }
+
+        assert_eq!(read_attr(&dev.path, "model").as_deref(), Some("Flash Disk"));
+        assert_eq!(read_u64(&dev.path, "size"), Some(2048));
+        assert_eq!(read_u64(&dev.path, "model"), None);
+        assert!(read_flag(&dev.path, "removable") && !read_flag(&dev.path, "queue/rotational"));
+        // attributes a driver does not have just are not there
+        assert_eq!(read_attr(&dev.path, "device/serial"), None);
+        assert!(!read_flag(&dev.path, "missing"));
+    }
+
+    #[test]
+    fn lists_devices_in_natural_order() {
+        let dir = Scratch::dir("sysfs-list");
+        for name in ["sda10", "sdb", "sda2", "nvme0n1", "sda1", "sda"].iter() {
+            std::fs::create_dir(dir.path.join(name)).expect("Failed to create device directory");
+        }
+        assert_eq!(list_dir(&dir.path).expect("Failed to list directory"), vec!["nvme0n1", "sda", "sda1", "sda2", "sda10", "sdb"]);
+        assert!(list_dir(&dir.path.join("missing")).is_err());
+    }
+
+    #[test]
+    fn reads_the_serial_from_vital_product_data() {
+        let dev = Scratch::dir("sysfs-vpd");
+        std::fs::create_dir_all(dev.path.join("device")).expect("Failed to create device directory");
+        let page = |bytes: &[u8]| std::fs::write(dev.path.join("device/vpd_pg80"), bytes).expect("Failed to write vpd page");
+
+        page(b"\x00\x80\x00\x0c  WD-WCC4N1 ");
+        assert_eq!(read_vpd_serial(&dev.path).as_deref(), Some("WD-WCC4N1"));
+        // a page cut short of the length in its header, or with only spaces in it
+        page(b"\x00\x80\x00\x20WD-WC");
+        assert_eq!(read_vpd_serial(&dev.path), None);
+        page(b"\x00\x80\x00\x04    ");
+        assert_eq!(read_vpd_serial(&dev.path), None);
+    }
+
+    #[test]
+    fn works_out_the_transport_from_where_the_disk_sits() {
+        let root = Scratch::dir("sysfs-transport");
+        let attached = |name: &str, under: &str| {
+            let real = root.path.join(under).join(name);
+            std::fs::create_dir_all(&real).expect("Failed to create device directory");
+            let link = root.path.join(name);
+            std::os::unix::fs::symlink(&real, &link).expect("Failed to link device");
+            transport(&link)
+        };
+        assert_eq!(attached("sdb", "devices/pci0000:00/0000:00:14.0/usb2/2-1/2-1:1.0/host6/target6:0:0/6:0:0:0/block"), "USB");
+        assert_eq!(attached("sda", "devices/pci0000:00/0000:00:17.0/ata1/host0/target0:0:0/0:0:0:0/block"), "SATA");
+        assert_eq!(attached("nvme0n1", "devices/pci0000:00/0000:00:1d.0/0000:3d:00.0/nvme/nvme0"), "NVMe");
+        assert_eq!(attached("loop0", "devices/virtual/block"), "virtual");
+        assert_eq!(transport(&root.path.join("missing")), "unknown");
+    }
+}
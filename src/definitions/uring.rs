@@ -0,0 +1,559 @@
+//! a small io_uring backend, built with the `io_uring` feature. it talks to
+//! the kernel directly, as we only need readv and writev at a fixed queue depth
+
+use std::{
+    fs::File,
+    os::unix::io::{
+        AsRawFd,
+        RawFd
+    },
+    sync::atomic::{
+        AtomicU32,
+        Ordering
+    }
+};
+use nix::libc;
+use super::{
+    engine::{
+        AlignedBuf,
+        Backend,
+        mismatched_sectors,
+        rewrite_in_pieces,
+        unreadable_sectors
+    },
+    pattern::Pass,
+    record::LbaRange,
+    progress::Tracker,
+    PassOutcome
+};
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_OP_READV: u8 = 1;
+const IORING_OP_WRITEV: u8 = 2;
+
+#[repr(C)]
+#[derive (Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64
+}
+
+#[repr(C)]
+#[derive (Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64
+}
+
+#[repr(C)]
+#[derive (Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets
+}
+
+/// a submission queue entry, as laid out in `linux/io_uring.h`
+#[repr(C)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64
+}
+
+/// a completion queue entry
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32
+}
+
+/// a region of the ring shared with the kernel
+struct Mmap {
+    ptr: *mut u8,
+    len: usize
+}
+
+impl Mmap {
+    fn new(fd: RawFd, len: usize, offset: libc::off_t) -> Result<Self, String> {
+        let ptr = unsafe {
+            libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE, fd, offset)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(format!("Failed to map io_uring: {}", std::io::Error::last_os_error()));
+        }
+        Ok(Mmap { ptr: ptr as *mut u8, len })
+    }
+
+    /// the value at `offset` bytes into the region
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { self.ptr.add(offset as usize) as *mut T }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len); }
+    }
+}
+
+/// an io_uring instance with its submission and completion queues mapped in
+pub struct Ring {
+    fd: RawFd,
+    sq: Mmap,
+    cq: Mmap,
+    sqes: Mmap,
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+    /// entries queued but not yet handed to the kernel
+    pending: u32
+}
+
+impl Ring {
+    /// sets up a ring that can hold `entries` requests
+    pub fn new(entries: u32) -> Result<Self, String> {
+        let mut params = Params::default();
+        let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, entries, &mut params as *mut Params) };
+        if fd < 0 {
+            return Err(format!("io_uring is not available: {}", std::io::Error::last_os_error()));
+        }
+        let fd = fd as RawFd;
+
+        // close the ring again if any of the mappings fail
+        let map = |len: usize, offset: libc::off_t| Mmap::new(fd, len, offset).inspect_err(|_| {
+            unsafe { libc::close(fd); }
+        });
+        let sq = map(params.sq_off.array as usize + params.sq_entries as usize * 4, IORING_OFF_SQ_RING)?;
+        let cq = map(params.cq_off.cqes as usize + params.cq_entries as usize * std::mem::size_of::<Cqe>(), IORING_OFF_CQ_RING)?;
+        let sqes = map(params.sq_entries as usize * std::mem::size_of::<Sqe>(), IORING_OFF_SQES)?;
+
+        Ok(Ring { fd, sq, cq, sqes, sq_off: params.sq_off, cq_off: params.cq_off, pending: 0 })
+    }
+
+    /// queues a single buffer readv or writev at `offset` of `fd`, tagged with `tag`
+    fn push(&mut self, opcode: u8, fd: RawFd, iov: *const libc::iovec, offset: u64, tag: u64) {
+        unsafe {
+            // we are the only one who moves the tail, so no need to be careful reading it
+            let tail = &*self.sq.at::<AtomicU32>(self.sq_off.tail);
+            let mask = *self.sq.at::<u32>(self.sq_off.ring_mask);
+            let pos = tail.load(Ordering::Relaxed);
+            let idx = pos & mask;
+            self.sqes.at::<Sqe>(idx * std::mem::size_of::<Sqe>() as u32).write(Sqe {
+                opcode,
+                flags: 0,
+                ioprio: 0,
+                fd,
+                off: offset,
+                addr: iov as u64,
+                len: 1,
+                rw_flags: 0,
+                user_data: tag,
+                buf_index: 0,
+                personality: 0,
+                splice_fd_in: 0,
+                addr3: 0,
+                pad: 0
+            });
+            self.sq.at::<u32>(self.sq_off.array + idx * 4).write(idx);
+            tail.store(pos.wrapping_add(1), Ordering::Release);
+        }
+        self.pending += 1;
+    }
+
+    /// hands everything queued to the kernel, waiting for at least `wait`
+    /// requests to complete
+    fn submit(&mut self, wait: u32) -> Result<(), String> {
+        loop {
+            let ret = unsafe {
+                libc::syscall(libc::SYS_io_uring_enter, self.fd, self.pending, wait,
+                    IORING_ENTER_GETEVENTS, std::ptr::null::<libc::c_void>(), 0)
+            };
+            if ret >= 0 {
+                self.pending -= ret as u32;
+                return Ok(());
+            }
+            let e = std::io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::EINTR) {
+                return Err(format!("io_uring_enter failed: {}", e));
+            }
+        }
+    }
+
+    /// takes the next completion off the queue, as its tag and result
+    fn pop(&mut self) -> Option<(u64, i32)> {
+        unsafe {
+            let head = &*self.cq.at::<AtomicU32>(self.cq_off.head);
+            let tail = &*self.cq.at::<AtomicU32>(self.cq_off.tail);
+            let mask = *self.cq.at::<u32>(self.cq_off.ring_mask);
+            let pos = head.load(Ordering::Relaxed);
+            if pos == tail.load(Ordering::Acquire) {
+                return None;
+            }
+            let cqe = &*self.cq.at::<Cqe>(self.cq_off.cqes + (pos & mask) * std::mem::size_of::<Cqe>() as u32);
+            let out = (cqe.user_data, cqe.res);
+            head.store(pos.wrapping_add(1), Ordering::Release);
+            Some(out)
+        }
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd); }
+    }
+}
+
+/// one request's worth of buffer, and where it is headed
+struct Slot {
+    buf: AlignedBuf,
+    iov: libc::iovec,
+    offset: u64,
+    len: usize
+}
+
+/// keeps up to a queue depth of reads or writes in flight through io_uring
+pub struct UringBackend {
+    /// declared before `slots` so that it is dropped, closing its fd, before
+    /// the buffers that requests still in it may be using
+    ring: Ring,
+    slots: Vec<Slot>,
+    expected: Vec<u8>,
+    block_size: usize,
+    sector: usize,
+    /// why the ring could not be set up again after it went wrong, after
+    /// which nothing more can go through it
+    broken: Option<String>
+}
+
+/// a slot for each request that can be in flight at once, with buffers of
+/// `block_size` aligned to `align`
+fn new_slots(queue_depth: usize, block_size: usize, align: usize) -> Vec<Slot> {
+    (0..queue_depth).map(|_| Slot {
+        buf: AlignedBuf::new(block_size, align),
+        iov: libc::iovec { iov_base: std::ptr::null_mut(), iov_len: 0 },
+        offset: 0,
+        len: 0
+    }).collect()
+}
+
+impl UringBackend {
+    pub fn new(queue_depth: usize, block_size: usize, align: usize) -> Result<Self, String> {
+        let ring = Ring::new(queue_depth as u32)?;
+        let slots = new_slots(queue_depth, block_size, align);
+        Ok(UringBackend { ring, slots, expected: vec![0; block_size], block_size, sector: align, broken: None })
+    }
+
+    /// waits for the `inflight` requests still out after `run` went wrong, so
+    /// none of them can turn up later and be taken for a request of the next
+    /// call. if even that fails, the ring is swapped for a new one. the old
+    /// buffers are only dropped once the old ring's fd is closed, as the
+    /// kernel may be using them until then
+    fn drain(&mut self, mut inflight: usize) {
+        while inflight > 0 && self.ring.submit(1).is_ok() {
+            while self.ring.pop().is_some() {
+                inflight = inflight.saturating_sub(1);
+            }
+        }
+        if inflight == 0 {
+            return;
+        }
+
+        let depth = self.slots.len();
+        match Ring::new(depth as u32) {
+            Ok(ring) => {
+                // closes the old ring, and only then lets its buffers go
+                self.ring = ring;
+                self.slots = new_slots(depth, self.block_size, self.sector);
+            },
+            // the old ring and its buffers are kept, and go together when
+            // the backend is dropped
+            Err(e) => self.broken = Some(e)
+        }
+    }
+
+    /// runs `opcode` over `start..end` of `file`, filling each slot with
+    /// `prepare` before it goes out and handing it to `complete` (with the
+    /// kernel's result) when it comes back. stops queueing new requests once
+    /// either gives false, but always waits for what is in flight, so
+    /// everything before the offset it gives has been done. if the ring
+    /// itself fails, the error comes with the offset everything before which
+    /// is known to have been done
+    fn run(&mut self, opcode: u8, file: &File, start: u64, end: u64,
+            prepare: &mut dyn FnMut(&mut [u8], u64) -> bool,
+            complete: &mut dyn FnMut(&[u8], u64, i32) -> bool) -> Result<u64, (String, u64)> {
+        if let Some(e) = &self.broken {
+            return Err((e.clone(), start));
+        }
+        let mut free: Vec<usize> = (0..self.slots.len()).rev().collect();
+        let mut next = start;
+        let mut inflight = 0;
+        let mut stop = false;
+
+        while (next < end && !stop) || inflight > 0 {
+            // top the queue back up
+            while next < end && !stop {
+                let idx = match free.pop() {
+                    Some(a) => a,
+                    None => break
+                };
+                let len = std::cmp::min(self.block_size as u64, end - next) as usize;
+                let slot = &mut self.slots[idx];
+                if !prepare(&mut slot.buf[..len], next) {
+                    free.push(idx);
+                    stop = true;
+                    break;
+                }
+                slot.iov = libc::iovec { iov_base: slot.buf.as_mut_ptr() as *mut libc::c_void, iov_len: len };
+                slot.offset = next;
+                slot.len = len;
+                self.ring.push(opcode, file.as_raw_fd(), &slot.iov, next, idx as u64);
+                next += len as u64;
+                inflight += 1;
+            }
+
+            // stopping before anything went out leaves nothing to wait for
+            if inflight == 0 {
+                break;
+            }
+
+            // wait for at least one to come back, then take everything that has.
+            // requests finish out of order, so only what comes before the
+            // earliest one still out is sure to be done
+            if let Err(e) = self.ring.submit(1) {
+                let done = (0..self.slots.len()).filter(|idx| !free.contains(idx))
+                    .map(|idx| self.slots[idx].offset)
+                    .min()
+                    .unwrap_or(next);
+                self.drain(inflight);
+                return Err((e, done));
+            }
+            while let Some((tag, res)) = self.ring.pop() {
+                let slot = &self.slots[tag as usize];
+                if !complete(&slot.buf[..slot.len], slot.offset, res) {
+                    stop = true;
+                }
+                free.push(tag as usize);
+                inflight -= 1;
+            }
+        }
+
+        Ok(next)
+    }
+}
+
+impl Backend for UringBackend {
+    fn write(&mut self, file: &File, pass: &Pass, start: u64, end: u64, outcome: &mut PassOutcome, progress: &Tracker) -> u64 {
+        let sector = self.sector;
+        let result = self.run(IORING_OP_WRITEV, file, start, end,
+            &mut |buf, offset| {
+                // a checkpoint that falls due still lets the first block out,
+                // so every stretch gets somewhere
+                if progress.cancelled() || (offset > start && progress.checkpoint_due()) {
+                    return false;
+                }
+                pass.fill(buf, offset);
+                true
+            },
+            &mut |buf, offset, res| {
+                // the buffer still holds the block, so a failed (or short)
+                // write can be retried from it one piece at a time
+                if res < 0 {
+                    rewrite_in_pieces(file, buf, offset, sector, std::io::Error::from_raw_os_error(-res), outcome, progress);
+                } else if res as usize != buf.len() {
+                    let e = std::io::Error::new(std::io::ErrorKind::WriteZero, format!("short write of {} of {} bytes", res, buf.len()));
+                    rewrite_in_pieces(file, buf, offset, sector, e, outcome, progress);
+                } else {
+                    outcome.bytes_written += res as u64;
+                }
+                progress.advance(buf.len() as u64);
+                !progress.cancelled()
+            });
+        match result {
+            Ok(reached) => reached,
+            Err((e, done)) => {
+                progress.error(done, e.clone());
+                outcome.errors.push(e);
+                done
+            }
+        }
+    }
+
+    fn check(&mut self, file: &File, pass: &Pass, start: u64, end: u64, mismatches: &mut Vec<LbaRange>, progress: &Tracker) {
+        // blocks finish out of order, so whoever looks at `mismatches` has to sort it
+        let sector = self.sector;
+        let mut expected = std::mem::take(&mut self.expected);
+        let result = self.run(IORING_OP_READV, file, start, end,
+            &mut |_, _| !progress.cancelled(),
+            &mut |buf, offset, res| {
+                if res < 0 || res as usize != buf.len() {
+                    progress.error(offset, if res < 0 {format!("Read error: {}", std::io::Error::from_raw_os_error(-res))} else {format!("Short read of {} of {} bytes", res, buf.len())});
+                    unreadable_sectors(offset, buf.len(), sector, mismatches);
+                } else {
+                    pass.fill(&mut expected[..buf.len()], offset);
+                    mismatched_sectors(buf, &expected[..buf.len()], offset, sector, mismatches);
+                }
+                progress.advance(buf.len() as u64);
+                !progress.cancelled()
+            });
+        self.expected = expected;
+        if let Err((e, done)) = result {
+            // we cannot tell what was read past `done`, so count all of it as bad
+            progress.error(done, e);
+            unreadable_sectors(done, (end - done) as usize, sector, mismatches);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::FileExt;
+    use super::*;
+    use super::super::{
+        engine::tests::mismatches,
+        tests::{
+            expected,
+            quiet,
+            Scratch
+        },
+        write_pass,
+        CheckpointLimits,
+        CHECKPOINT
+    };
+
+    /// a backend to test, or `None` (having said why) where this kernel, or
+    /// the sandbox we run in, will not let us set up a ring
+    fn backend() -> Option<UringBackend> {
+        match UringBackend::new(4, 65536, 512) {
+            Ok(a) => Some(a),
+            Err(e) => {
+                eprintln!("skipping, io_uring is not available here: {}", e);
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn writes_and_checks_every_byte_of_an_odd_sized_file() {
+        let size = 5 * 65536 + 3000;
+        let scratch = Scratch::new("uring-odd", size);
+        let mut backend = match backend() {
+            Some(a) => a,
+            None => return
+        };
+        let pass = Pass::random([9; super::super::pattern::SEED_LEN], 1);
+
+        let mut outcome = PassOutcome { bytes_written: 0, errors: Vec::new(), bad_ranges: Vec::new(), stopped_at: None };
+        backend.write(&scratch.file, &pass, 0, size, &mut outcome, &quiet());
+        assert!(outcome.errors.is_empty(), "{:?}", outcome.errors);
+        assert_eq!(outcome.bytes_written, size);
+        let (mut contents, mut expected) = (vec![0; size as usize], vec![0; size as usize]);
+        scratch.file.read_exact_at(&mut contents, 0).expect("Failed to read scratch file");
+        pass.fill(&mut expected, 0);
+        assert!(contents == expected);
+
+        assert!(mismatches(&mut backend, &scratch.file, &pass, 0, size).is_empty());
+        scratch.file.write_all_at(&[0], 3 * 65536 + 5).expect("Failed to corrupt scratch file");
+        scratch.file.write_all_at(&[0], 65536 + 5).expect("Failed to corrupt scratch file");
+        assert_eq!(mismatches(&mut backend, &scratch.file, &pass, 0, size), vec!["128", "384"]);
+    }
+
+    #[test]
+    fn write_pass_writes_every_byte_through_the_ring() {
+        let size = 5 * 65536 + 3000;
+        let scratch = Scratch::new("uring-pass", size);
+        let mut backend = match backend() {
+            Some(a) => a,
+            None => return
+        };
+        let pass = Pass::random([9; super::super::pattern::SEED_LEN], 1);
+        let outcome = write_pass(&scratch.file, &mut backend, &pass, 0..size,
+            CheckpointLimits { bytes: 3 * 65536, ..CHECKPOINT }, &quiet(), &mut |_| ());
+
+        assert!(outcome.errors.is_empty(), "{:?}", outcome.errors);
+        assert_eq!(outcome.bytes_written, size);
+        assert_eq!(scratch.contents(), expected(&pass, 0..size));
+        assert!(mismatches(&mut backend, &scratch.file, &pass, 0, size).is_empty());
+    }
+
+    #[test]
+    fn checkpoints_a_slow_pass_with_the_ring_still_going() {
+        // a disk so slow that the time is up after every block
+        let size = 3 * 65536 + 100;
+        let scratch = Scratch::new("uring-slow", size);
+        let mut backend = match backend() {
+            Some(a) => a,
+            None => return
+        };
+        let pass = Pass::Fixed(0xC3);
+        let limits = CheckpointLimits { interval: std::time::Duration::ZERO, ..CHECKPOINT };
+        let mut checkpoints = Vec::new();
+        let outcome = write_pass(&scratch.file, &mut backend, &pass, 0..size, limits, &quiet(), &mut |offset| checkpoints.push(offset));
+        assert!(outcome.errors.is_empty(), "{:?}", outcome.errors);
+        assert_eq!(checkpoints, vec![65536, 2 * 65536, 3 * 65536, size]);
+        assert_eq!(scratch.contents(), expected(&pass, 0..size));
+    }
+
+    #[test]
+    fn failed_ring_only_checkpoints_what_was_written_and_recovers() {
+        let size = 6 * 65536;
+        let scratch = Scratch::new("uring-fail", size);
+        let mut backend = match backend() {
+            Some(a) => a,
+            None => return
+        };
+
+        // swap the ring's fd for something that is not a ring, so submitting
+        // fails with a queue full of requests
+        let null = File::open("/dev/null").expect("Failed to open /dev/null");
+        assert!(unsafe { libc::dup2(null.as_raw_fd(), backend.ring.fd) } >= 0);
+        let mut checkpoints = Vec::new();
+        let outcome = write_pass(&scratch.file, &mut backend, &Pass::Fixed(0xFF), 0..size,
+            CheckpointLimits { bytes: size, ..CHECKPOINT }, &quiet(), &mut |offset| checkpoints.push(offset));
+        assert_eq!(checkpoints, vec![0]);
+        assert_eq!((outcome.bytes_written, outcome.stopped_at), (0, None));
+        assert!(outcome.errors.iter().any(|e| e.contains("io_uring_enter failed")), "{:?}", outcome.errors);
+
+        // a new ring takes its place, and nothing left over from the old one gets in the way
+        let mut outcome = PassOutcome { bytes_written: 0, errors: Vec::new(), bad_ranges: Vec::new(), stopped_at: None };
+        assert_eq!(backend.write(&scratch.file, &Pass::Fixed(0xA5), 0, size, &mut outcome, &quiet()), size);
+        assert!(outcome.errors.is_empty(), "{:?}", outcome.errors);
+        assert_eq!(scratch.contents(), vec![0xA5; size as usize]);
+    }
+}
@@ -0,0 +1,534 @@
+use std::{
+    sync::Arc,
+    thread::JoinHandle
+};
+use super::{
+    overwrite_drive,
+    rewrite_ranges,
+    find_mismatches,
+    check_ranges,
+    defs::DiskData,
+    engine::IoOptions,
+    error::Error,
+    journal::Journal,
+    methods::{
+        Method,
+        WipePass,
+        simple_plan
+    },
+    cancel::CancelToken,
+    progress::{
+        Event,
+        Level,
+        ProgressSink,
+        SilentSink
+    },
+    pattern::{
+        Pass,
+        SEED_LEN,
+        new_seed,
+        seed_to_hex
+    },
+    record::{
+        WipeRecord,
+        PassRecord,
+        LbaRange
+    },
+    sample::{
+        VerifyMode,
+        SamplePlan
+    }
+};
+
+/// how the passes of a wipe get checked
+#[derive (Clone, Copy)]
+struct VerifyOptions {
+    /// whether to check at all
+    check: bool,
+    /// how many times to rewrite a pass that does not check out
+    retries: usize,
+    mode: VerifyMode,
+    /// picks the blocks read by a sampled check
+    seed: [u8; SEED_LEN]
+}
+
+impl VerifyOptions {
+    /// the blocks of `disk` to read when checking pass number `pass`, or
+    /// nothing if every byte gets read
+    fn sample(&self, disk: &DiskData, io: &IoOptions, pass: u64) -> Option<SamplePlan> {
+        self.mode.plan(disk.bytes, io.block_size as u64, self.seed, pass)
+    }
+}
+
+/// what a wipe is going to write
+#[derive (Clone)]
+enum Plan {
+    /// a named standard, tied to the wiper's seed when the wipe starts
+    Method(&'static Method),
+    /// passes that are ready to go, and what to call them
+    Passes(Vec<WipePass>, String)
+}
+
+/// sets up the wipe of a single disk. everything but the disk has a default,
+/// so `Wiper::new(disk).run()` writes zeros over all of it once and checks
+/// every byte, as NIST 800-88 Clear asks
+pub struct Wiper {
+    disk: DiskData,
+    plan: Plan,
+    seed: [u8; SEED_LEN],
+    io: IoOptions,
+    verify: VerifyOptions,
+    journal: Option<String>,
+    resume: Option<Journal>,
+    progress: Arc<dyn ProgressSink>,
+    cancel: CancelToken
+}
+
+impl Wiper {
+    /// starts setting up a wipe of `disk`, as found by `list_disks` or `find_target`
+    pub fn new(disk: DiskData) -> Self {
+        Wiper {
+            disk,
+            plan: Plan::Passes(simple_plan(1, None), "1 pass(es) of zeros".to_string()),
+            seed: new_seed(),
+            io: IoOptions::default(),
+            verify: VerifyOptions { check: true, retries: 5, mode: VerifyMode::Full, seed: new_seed() },
+            journal: None,
+            resume: None,
+            progress: Arc::new(SilentSink),
+            cancel: CancelToken::new()
+        }
+    }
+
+    /// wipes according to a named standard, such as one of `METHODS`
+    pub fn method(mut self, method: &'static Method) -> Self {
+        self.plan = Plan::Method(method);
+        self
+    }
+
+    /// wipes with `passes`, such as those from `custom_plan`, described as `description`
+    pub fn passes(mut self, passes: Vec<WipePass>, description: String) -> Self {
+        self.plan = Plan::Passes(passes, description);
+        self
+    }
+
+    /// the seed for the random passes of a method (default is freshly generated)
+    pub fn seed(mut self, seed: [u8; SEED_LEN]) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// how much to read or write at a time. must be a multiple of the disk's
+    /// logical block size
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.io.block_size = block_size;
+        self
+    }
+
+    /// how to talk to the disk, replacing anything set by `block_size`
+    pub fn io(mut self, io: IoOptions) -> Self {
+        self.io = io;
+        self
+    }
+
+    /// checks the passes the plan asks for by reading back as much as `mode` says
+    pub fn verify(mut self, mode: VerifyMode) -> Self {
+        self.verify.check = true;
+        self.verify.mode = mode;
+        self
+    }
+
+    /// does not check any passes
+    pub fn no_verify(mut self) -> Self {
+        self.verify.check = false;
+        self
+    }
+
+    /// how many times to rewrite the sectors of a pass that do not check out
+    /// before calling them bad (default is 5)
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.verify.retries = retries;
+        self
+    }
+
+    /// the seed that picks the blocks read by a sampled check (default is freshly generated)
+    pub fn verify_seed(mut self, seed: [u8; SEED_LEN]) -> Self {
+        self.verify.seed = seed;
+        self
+    }
+
+    /// where to checkpoint the wipe (default is under `JOURNAL_DIR`). it must
+    /// not be on the disk being wiped
+    pub fn journal(mut self, path: String) -> Self {
+        self.journal = Some(path);
+        self
+    }
+
+    /// carries on the wipe saved in `journal` instead of starting a new one.
+    /// the passes and seed come from the journal
+    pub fn resume(mut self, journal: Journal) -> Self {
+        self.resume = Some(journal);
+        self
+    }
+
+    /// sends what happens during the wipe to `sink`, such as a
+    /// `TerminalSink` around a bar added to a `MultiProgress` (default is `SilentSink`)
+    pub fn progress(mut self, sink: Arc<dyn ProgressSink>) -> Self {
+        self.progress = sink;
+        self
+    }
+
+    /// stops the wipe at the next block once `token` is cancelled. the disk is
+    /// synced and the journal checkpointed, so the wipe can be resumed, and the
+    /// record says how far it got (default is a token of its own, which
+    /// `WipeHandle::cancel` uses)
+    pub fn cancel_with(mut self, token: CancelToken) -> Self {
+        self.cancel = token;
+        self
+    }
+
+    /// the disk that is going to be wiped
+    pub fn disk(&self) -> &DiskData {
+        &self.disk
+    }
+
+    /// makes sure the wipe can go ahead, without touching the disk. `start`
+    /// and `run` call it again just before the disk is opened, and it looks
+    /// afresh at what is using the disk each time
+    pub fn validate(&self) -> Result<(), Error> {
+        let disk = &self.disk;
+        // what was in use when the disk was listed still counts, along with
+        // anything that has started using it since
+        let mut reasons = disk.in_use_because();
+        for reason in disk.current_hazards().iter().map(|h| h.to_string()) {
+            if !reasons.contains(&reason) {
+                reasons.push(reason);
+            }
+        }
+        if !reasons.is_empty() {
+            return Err(Error::Busy(format!("Refusing to wipe {}, as it is in use: {}", disk.path, reasons.join("; "))));
+        }
+        if self.io.block_size == 0 || !(self.io.block_size as u64).is_multiple_of(disk.logical_block_size.max(1)) {
+            return Err(Error::Invalid(format!("Block size {} is not a multiple of the logical block size of {} ({})",
+                self.io.block_size, disk.path, disk.logical_block_size)));
+        }
+        match (&self.resume, &self.plan) {
+            (Some(journal), _) if !journal.matches(disk) =>
+                Err(Error::Invalid(format!("The journal was not started on {} {}", disk.path, disk.identity()))),
+            (None, Plan::Passes(passes, _)) if passes.is_empty() => Err(Error::Invalid("There are no passes to run".to_string())),
+            (None, Plan::Method(method)) => method.plan(self.seed).map(|_| ()),
+            _ => Ok(())
+        }
+    }
+
+    /// starts the wipe on a thread of its own, giving a handle to wait for it
+    pub fn start(self) -> Result<WipeHandle, Error> {
+        self.validate()?;
+        let (disk, cancel) = (self.disk.clone(), self.cancel.clone());
+        let thread = match std::thread::Builder::new().name(format!("wipe {}", disk.path)).spawn(move || self.wipe()) {
+            Ok(a) => a,
+            Err(e) => return Err(Error::Internal(format!("Failed to start the wipe of {}: {}", disk.path, e)))
+        };
+
+        Ok(WipeHandle { disk, cancel, thread })
+    }
+
+    /// runs the wipe to the end on this thread, giving its record
+    pub fn run(self) -> Result<WipeRecord, Error> {
+        self.validate()?;
+        Ok(self.wipe())
+    }
+
+    /// the journal the wipe starts from, either the one being resumed or a
+    /// fresh one for the plan
+    fn take_journal(&mut self) -> Journal {
+        if let Some(journal) = self.resume.take() {
+            return journal;
+        }
+        let (passes, description) = match &self.plan {
+            // `validate` has made sure the method can be planned
+            Plan::Method(method) => (method.plan(self.seed).unwrap_or_default(), method.description.to_string()),
+            Plan::Passes(passes, description) => (passes.clone(), description.clone())
+        };
+        let seed = passes.iter().find_map(|p| p.pass.seed()).map(|s| seed_to_hex(&s));
+        let record = WipeRecord::new(&self.disk, description, seed);
+        let path = match &self.journal {
+            Some(a) => a.clone(),
+            None => Journal::default_path(&self.disk)
+        };
+
+        Journal::new(path, record, passes)
+    }
+
+    /// runs every pass left in the journal over the disk, checkpointing as it
+    /// goes, and gives the record of the finished wipe. if it is cancelled,
+    /// the journal is kept and the record is marked incomplete
+    fn wipe(mut self) -> WipeRecord {
+        let mut journal = self.take_journal();
+        let (disk, io, verify, sink, cancel) = (&self.disk, &self.io, &self.verify, self.progress.as_ref(), &self.cancel);
+        let mut guard = FinishGuard { sink, disk, finished: false };
+        match journal.save() {
+            Ok(_) => note(sink, disk, Level::Info, format!("Checkpointing progress to {} (continue with `--resume {}`)", journal.path(), journal.path())),
+            Err(e) => note(sink, disk, Level::Failure, format!("[-] This wipe cannot be resumed if interrupted: {}", e))
+        }
+        note(sink, disk, Level::Info, format!("Securely formatting drive ({}). This will take a while...", journal.record.method));
+        note(sink, disk, Level::Info, format!("Target: {} (started at {})", disk.identity(), journal.record.started));
+
+        let (first_pass, first_offset) = (journal.pass, journal.offset);
+        let plan = journal.plan.clone();
+        let mut stopped = None;
+        for (i, wipe_pass) in plan.iter().enumerate().skip(first_pass) {
+            if cancel.is_cancelled() {
+                stopped = Some(None);
+                break;
+            }
+            sink.event(&Event::PassStarted { disk: disk.path.clone(), pass: i+1, passes: plan.len(), pattern: wipe_pass.pass.to_string() });
+            let mut pass_record = PassRecord::new(i+1, wipe_pass.pass.to_string());
+            let start = if i == first_pass {first_offset} else {0};
+            if start > 0 {
+                note(sink, disk, Level::Info, format!("[ ] Picking up pass #{} from offset {}", i+1, start));
+                pass_record.resumed_from = Some(start);
+            }
+
+            let mut checkpoint = |offset: u64| {
+                if let Err(e) = journal.checkpoint(i, offset) {
+                    note(sink, disk, Level::Failure, format!("[-] Failed to checkpoint: {}", e));
+                }
+            };
+            let written = match overwrite_drive(disk, &wipe_pass.pass, start, io, sink, cancel, &mut checkpoint){
+                Ok(outcome) => {
+                    pass_record.bytes_written = outcome.bytes_written;
+                    pass_record.errors = outcome.errors;
+                    pass_record.bad_ranges = outcome.bad_ranges;
+                    pass_record.stopped_at = outcome.stopped_at;
+                    true
+                },
+                Err(e) => {
+                    note(sink, disk, Level::Failure, format!("Overwrite drive issue hit: {}", e));
+                    pass_record.errors.push(e.to_string());
+                    false
+                }
+            };
+            pass_record.finish();
+
+            // see if this pass is one we are supposed to double check. a pass
+            // that never got written is left unverified rather than checked
+            // and rewritten, as whatever stopped it would stop those too
+            if verify.check && wipe_pass.verify && written && !cancel.is_cancelled() {
+                let sample = verify.sample(disk, io, (i + 1) as u64);
+                self.verify_pass(&wipe_pass.pass, sample.as_ref(), &mut pass_record);
+                pass_record.sample = sample.map(|plan| plan.record());
+            }
+            sink.event(&Event::PassFinished {
+                disk: disk.path.clone(),
+                pass: i+1,
+                bytes_written: pass_record.bytes_written,
+                seconds: pass_record.seconds,
+                verified: pass_record.verified,
+                errors: pass_record.errors.len()
+            });
+
+            // a pass cut short stays out of the journal, so resuming starts
+            // its record afresh from the last checkpoint
+            if cancel.is_cancelled() {
+                stopped = Some(Some(pass_record));
+                break;
+            }
+            journal.record.passes.push(pass_record);
+            if let Err(e) = journal.checkpoint(i + 1, 0) {
+                note(sink, disk, Level::Failure, format!("[-] Failed to checkpoint: {}", e));
+            }
+        }
+
+        if let Some(pass_record) = stopped {
+            let record = self.stop(&journal, pass_record);
+            guard.finished = true;
+            return record;
+        }
+
+        // the wipe is over, so there is nothing left to resume
+        if let Err(e) = journal.remove() {
+            note(sink, disk, Level::Failure, format!("[-] {}", e));
+        }
+        let mut record = journal.record;
+        record.finish();
+        sink.event(&Event::Finished { disk: disk.path.clone(), result: record.status().to_string() });
+        guard.finished = true;
+
+        record
+    }
+
+    /// winds up a wipe that was cancelled, with `pass_record` the pass it was
+    /// part way through if any, giving the record of what got done
+    fn stop(&self, journal: &Journal, pass_record: Option<PassRecord>) -> WipeRecord {
+        let (disk, sink) = (&self.disk, self.progress.as_ref());
+        sink.event(&Event::Stopped {
+            disk: disk.path.clone(),
+            pass: journal.pass + 1,
+            offset: journal.offset,
+            bytes_written: pass_record.as_ref().map_or(0, |p| p.bytes_written)
+        });
+        note(sink, disk, Level::Warning, format!("[ ] Continue with `--resume {}`", journal.path()));
+
+        let mut record = journal.record.clone();
+        record.passes.extend(pass_record);
+        record.interrupt();
+        sink.event(&Event::Finished { disk: disk.path.clone(), result: record.status().to_string() });
+
+        record
+    }
+
+    /// checks that `pass` really made it onto the disk, reading all of it or
+    /// just the parts picked by `sample`. random passes are checked by
+    /// generating their keystream again for each block, so nothing written has
+    /// to be kept. every sector found wrong is written again and checked
+    /// again, up to the retries asked for, and any still wrong after that are
+    /// reported as bad. a check that is cancelled leaves the pass unverified
+    fn verify_pass(&self, pass: &Pass, sample: Option<&SamplePlan>, record: &mut PassRecord) {
+        let (disk, io, sink, cancel) = (&self.disk, &self.io, self.progress.as_ref(), &self.cancel);
+        match sample {
+            Some(plan) => note(sink, disk, Level::Warning, format!("[ ] Just double checking my work, sampling {} bytes ({:.2}% of the disk)...",
+                plan.bytes(), plan.coverage())),
+            None => note(sink, disk, Level::Warning, "[ ] Just double checking my work...".to_string())
+        }
+        let mut bad = match find_mismatches(disk, pass, sample, io, sink, cancel) {
+            Ok(a) => a,
+            Err(Error::Aborted) => return,
+            Err(e) => {
+                note(sink, disk, Level::Failure, format!("[-] Failed to check the pass: {}", e));
+                record.errors.push(e.to_string());
+                record.verified = Some(false);
+                return;
+            }
+        };
+        if bad.is_empty() {
+            record.verified = Some(true);
+            return;
+        }
+
+        // only write again what was wrong, rather than everything after it
+        note(sink, disk, Level::Info, format!("[ ] Rewriting just the {}...", LbaRange::describe(&bad)));
+        record.rewritten = bad.clone();
+        for attempt in 1..=self.verify.retries {
+            match rewrite_ranges(disk, pass, &bad, io, sink, cancel) {
+                Ok(_) => note(sink, disk, Level::Warning, format!("[+] Rewrite #{} complete. Checking success now...", attempt)),
+                Err(Error::Aborted) => return,
+                Err(e) => note(sink, disk, Level::Failure, format!("[-] Failed secondary write: {}", e))
+            }
+            match check_ranges(disk, pass, &bad, io, sink, cancel) {
+                Ok(a) => bad = a,
+                Err(Error::Aborted) => return,
+                Err(e) => note(sink, disk, Level::Failure, format!("[-] Failed secondary check: {}", e))
+            }
+            if bad.is_empty() {
+                note(sink, disk, Level::Success, "[+] Successfully overwrote volume!".to_string());
+                record.verified = Some(true);
+                return;
+            }
+        }
+
+        // whatever is left will not take the pass, so treat it like any other bad sector
+        let error = format!("{} still did not hold the pass after {} rewrite(s)", LbaRange::describe(&bad), self.verify.retries);
+        note(sink, disk, Level::Failure, format!("[-] Giving up: {}", error));
+        record.errors.push(error);
+        record.bad_ranges.extend(bad);
+        LbaRange::merge(&mut record.bad_ranges);
+        record.verified = Some(false);
+    }
+}
+
+/// a wipe running on its own thread
+pub struct WipeHandle {
+    disk: DiskData,
+    cancel: CancelToken,
+    thread: JoinHandle<WipeRecord>
+}
+
+impl WipeHandle {
+    /// the disk being wiped
+    pub fn disk(&self) -> &DiskData {
+        &self.disk
+    }
+
+    /// asks the wipe to stop at the next block. `join` then gives a record
+    /// marked incomplete
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// whether the wipe is over, so `join` will not block
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// waits for the wipe to finish and gives its record
+    pub fn join(self) -> Result<WipeRecord, Error> {
+        match self.thread.join() {
+            Ok(a) => Ok(a),
+            Err(_) => Err(Error::Internal(format!("The wipe of {} crashed", self.disk.path)))
+        }
+    }
+}
+
+/// sends a message about the wipe of `disk` to `sink`
+fn note(sink: &dyn ProgressSink, disk: &DiskData, level: Level, text: String) {
+    sink.event(&Event::Message { disk: disk.path.clone(), level, text });
+}
+
+/// makes sure a wipe always ends with a `Finished` event, even if it panics,
+/// as a terminal waits for every bar to be finished before it lets go
+struct FinishGuard<'a> {
+    sink: &'a dyn ProgressSink,
+    disk: &'a DiskData,
+    finished: bool
+}
+
+impl Drop for FinishGuard<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.sink.event(&Event::Finished { disk: self.disk.path.clone(), result: "FAILED".to_string() });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{
+        defs::tests::disk,
+        safety::Hazard
+    };
+
+    /// the kind and exit code `wiper` is refused with, if it is
+    fn refusal(wiper: Wiper) -> Option<(&'static str, i32)> {
+        wiper.validate().err().map(|e| (e.kind(), e.exit_code()))
+    }
+
+    #[test]
+    fn refuses_wipes_that_cannot_go_ahead_with_the_right_error() {
+        // a path no device will ever have, so nothing on this system can be using it
+        let drive = disk("/dev/checked_wipe-test", Some("SERIAL1"), None);
+        assert_eq!(refusal(Wiper::new(drive.clone())), None);
+        assert_eq!(refusal(Wiper::new(drive.clone()).block_size(1000)), Some(("invalid", 2)));
+        assert_eq!(refusal(Wiper::new(drive.clone()).passes(Vec::new(), "nothing".to_string())), Some(("invalid", 2)));
+
+        let other = disk("/dev/checked_wipe-test", Some("SERIAL2"), None);
+        let journal = Journal::new(String::new(), WipeRecord::new(&other, "test".to_string(), None), simple_plan(1, None));
+        assert_eq!(refusal(Wiper::new(drive.clone()).resume(journal)), Some(("invalid", 2)));
+
+        let mut mounted = drive.clone();
+        mounted.is_mounted = true;
+        assert_eq!(refusal(Wiper::new(mounted)), Some(("busy", 5)));
+    }
+
+    #[test]
+    fn refuses_disks_in_use_and_gives_every_reason() {
+        let mut drive = disk("/dev/checked_wipe-test", None, None);
+        drive.hazards.push(Hazard::Swap { device: drive.path.clone() });
+        drive.hazards.push(Hazard::Held { device: drive.path.clone(), holder: "md0".to_string(), what: "RAID array".to_string() });
+        let error = Wiper::new(drive).validate().expect_err("Disk in use was accepted");
+        assert_eq!(error.kind(), "busy");
+        assert_eq!(error.to_string(), "Refusing to wipe /dev/checked_wipe-test, as it is in use: /dev/checked_wipe-test is in use as swap; \
+            /dev/checked_wipe-test is part of RAID array (/dev/md0)");
+    }
+}
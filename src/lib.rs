@@ -0,0 +1,100 @@
+//! securely wipes disks and disk images, checking the passes really made it
+//! onto the media, and keeps a record (and signed certificate) of how it went.
+//!
+//! ```no_run
+//! use checked_wipe::{find_method, find_target, list_disks, VerifyMode, Wiper};
+//!
+//! let mut disks = list_disks()?;
+//! let idx = find_target(&mut disks, "/dev/disk/by-id/ata-XXXX")?;
+//! let record = Wiper::new(disks.swap_remove(idx))
+//!     .method(find_method("dod").unwrap())
+//!     .block_size(4 * 1024 * 1024)
+//!     .verify(VerifyMode::Sample(5.0))
+//!     .start()?
+//!     .join()?;
+//! println!("{}", record.status());
+//! # Ok::<(), checked_wipe::Error>(())
+//! ```
+//!
+//! nothing is printed unless `set_output` asks for it
+
+/// prints a message meant for a person, wherever `set_output` says they go
+#[macro_export]
+macro_rules! say {
+    ($($arg:tt)*) => {
+        match $crate::output() {
+            $crate::Output::Terminal => println!($($arg)*),
+            $crate::Output::Machine => eprintln!($($arg)*),
+            $crate::Output::Silent => ()
+        }
+    };
+}
+
+mod definitions;
+pub use definitions::{
+    // finding disks
+    DiskData,
+    Hazard,
+    list_disks,
+    find_disk,
+    find_target,
+    parse_partitions,
+    print_top_levels,
+    // what gets written
+    Pass,
+    WipePass,
+    Method,
+    METHODS,
+    METHOD_NAMES,
+    find_method,
+    simple_plan,
+    custom_plan,
+    new_seed,
+    parse_seed,
+    seed_to_hex,
+    SEED_LEN,
+    // how it gets written and checked
+    IoOptions,
+    parse_size,
+    uring_support,
+    VerifyMode,
+    SamplePlan,
+    // wiping
+    Wiper,
+    WipeHandle,
+    Journal,
+    JOURNAL_DIR,
+    PassOutcome,
+    overwrite_drive,
+    rewrite_ranges,
+    find_mismatches,
+    check_ranges,
+    Event,
+    Stage,
+    Level,
+    ProgressSink,
+    TerminalSink,
+    JsonLinesSink,
+    SilentSink,
+    CancelToken,
+    // what happened
+    WipeRecord,
+    PassRecord,
+    DiskIdentity,
+    SampleRecord,
+    LbaRange,
+    Certificate,
+    SignedCertificate,
+    current_operator,
+    load_or_create_key,
+    public_key_hex,
+    DEFAULT_KEY_PATH,
+    // what went wrong
+    Error,
+    // talking to people
+    Output,
+    set_output,
+    output,
+    machine_output,
+    new_bar
+};
@@ -1,26 +1,67 @@
-#![feature(pattern)]
 use std::{
     io::{
         self,
         prelude::*,
-    }
+    },
+    sync::Arc
 };
-use clap::{Arg, App};
-use chrono;
+use clap::{Arg, App, ArgMatches, SubCommand, AppSettings};
 use colored::Colorize;
-use nix::unistd::Uid;
-#[macro_use] extern crate scan_fmt;
+use indicatif::{
+    MultiProgress,
+    ProgressBar
+};
+use nix::{
+    sys::signal::{
+        SigSet,
+        Signal
+    },
+    unistd::Uid
+};
 
-// import our local stuff
-mod definitions;
-use definitions::{
+// import our library
+use checked_wipe::{
+    say,
     DiskData,
-    parse_partitions,
+    Wiper,
+    WipeHandle,
+    list_disks,
+    find_target,
     print_top_levels,
-    zero_drive,
-    zero_check_from,
-    assert_check,
-    assert_check_from
+    find_mismatches,
+    Event,
+    ProgressSink,
+    TerminalSink,
+    JsonLinesSink,
+    SilentSink,
+    CancelToken,
+    WipePass,
+    WipeRecord,
+    IoOptions,
+    VerifyMode,
+    parse_size,
+    uring_support,
+    Journal,
+    Certificate,
+    SignedCertificate,
+    current_operator,
+    load_or_create_key,
+    public_key_hex,
+    DEFAULT_KEY_PATH,
+    METHODS,
+    METHOD_NAMES,
+    find_method,
+    simple_plan,
+    custom_plan,
+    new_seed,
+    parse_seed,
+    seed_to_hex,
+    SEED_LEN,
+    Error,
+    Output,
+    set_output,
+    machine_output,
+    new_bar
 };
 
 
@@ -28,205 +69,923 @@ use definitions::{
 
 /// prints a welcome message to the user
 fn print_welcome(){
-    println!("Welcome to Ch3cked W1pe");
+    say!("Welcome to Ch3cked W1pe");
 }
 
-
-
-/// funny enough this is the main function
-fn main() {
-    print_welcome();
-    // check we are running as root
-    if !Uid::effective().is_root() {
-        panic!("[-] This program must be run as root");
-    }
-
-    // fetch the CLI arguments
-    let matches = App::new("NetCDF Averager")
-			.version("0.2.0")
-			.author("Nick Ammann")
-			.about("Securely wipes a disk")
-			.arg(Arg::with_name("loops")
-					.short("n")
-					.long("number")
-					.takes_value(true)
-					.help("The number of times to overwrite the disk (default is 5)"))
-            .arg(Arg::with_name("checked")
-                    .short("c")
-                    .long("nocheck")
-                    .help("Do not check to make sure the drive is really zeroed after the wipe"))
-			.get_matches();
-
-    let loop_num: usize;
-    if matches.is_present("loops") {
-        loop_num = matches.value_of("loops").unwrap().to_string().parse::<usize>().unwrap();
+/// prompts the user and reads a line of input from them. if there is no one
+/// there to answer, we take that as them backing out
+fn read_input() -> String {
+    let mut input_text = String::new();
+    let flushed = if machine_output() {
+        eprint!(" > ");
+        io::stderr().flush()
     } else {
-        loop_num = 5;
+        print!(" > ");
+        io::stdout().flush()
+    };
+    match flushed.and_then(|_| io::stdin().read_line(&mut input_text)) {
+        Ok(0) | Err(_) => fail(Error::Aborted),
+        Ok(_) => input_text
     }
+}
 
-    // see if we are gonna double-check our work
-    let check: bool;
-    if matches.is_present("checked") {
-        check = false;
-    } else {
-        check = true;
+/// prints `value` as JSON on stdout
+fn print_json<T: serde::Serialize>(value: &T) {
+    match serde_json::to_string_pretty(value) {
+        Ok(a) => println!("{}", a),
+        Err(e) => fail(Error::Internal(format!("Failed to serialize output: {}", e)))
     }
+}
 
-    // get the partition/disk info we need
-    let mut drives_vec: Vec<DiskData> = Vec::new();
-    parse_partitions(&mut drives_vec).expect("Failed to read drives");
+/// reports `error` and quits with its exit code. for machine readable output
+/// the error is also the JSON document on stdout, so there always is one
+fn fail(error: Error) -> ! {
+    if machine_output() {
+        println!("{}", serde_json::json!({
+            "error": error.kind(),
+            "message": error.to_string(),
+            "exit_code": error.exit_code()
+        }));
+    }
+    match error {
+        Error::Aborted => eprintln!("{}", "[-] Caught non-affirmative. Quitting...".red().clear()),
+        _ => eprintln!("{} {}", "[-]".red().bold(), error)
+    }
+    std::process::exit(error.exit_code());
+}
 
-    println!("{}", "All Drives ____________________________________________________".green());
+/// prints every drive along with its partitions, and why it cannot be
+/// wiped if it is in use
+fn print_drives(drives_vec: &[DiskData]) {
+    say!("{}", "All Drives ____________________________________________________".green());
     for drive in drives_vec.iter(){
-        println!("\t{}", drive.to_string().red());
+        say!("\t{}", drive.to_string().red());
+        say!("\t{}", drive.identity().italic());
         for partition in drive.partitions.iter(){
-            println!("\t\t{}", partition.to_string().italic().yellow());
+            say!("\t\t{}", partition.to_string().italic().yellow());
+        }
+        for reason in drive.in_use_because() {
+            say!("\t{} {}", "[!] In use:".yellow().bold(), reason);
         }
     }
+}
 
-    println!("\n{}", "All Drives Currently Unmounted ________________________________".green());
-    let mut umount_idx_vec: Vec<usize> = Vec::new();
-    let mut ctr = 0;
-    let mut idx = 0;
-    for drive in drives_vec.iter(){
-        let mut is_drive_mounted: bool = false;
-        for partition in drive.partitions.iter(){
-            if partition.is_mounted {
-                is_drive_mounted = true ;
-            }
-        }
+/// lists every drive and has the user pick one or more of the ones nothing
+/// is using, giving their indexes in `drives_vec`
+fn select_drives(drives_vec: &[DiskData]) -> Vec<usize> {
+    print_drives(drives_vec);
 
-        // if the drive is not mounted, print it and save the index
-        if !is_drive_mounted {
-            println!("{}\t{}", ctr+1, drive.to_string().red());
+    say!("\n{}", "All Drives Not In Use _________________________________________".green());
+    let mut umount_idx_vec: Vec<usize> = Vec::new();
+    for (idx, drive) in drives_vec.iter().enumerate(){
+        // if nothing is using the drive, print it and save the index
+        if !drive.in_use() {
             umount_idx_vec.push(idx);
-
-            ctr += 1;
+            say!("{}\t{}", umount_idx_vec.len(), drive.to_string().red());
+            say!("\t{}", drive.identity().italic());
         }
-        idx += 1;
     }
 
-    println!("{}", "_______________________________________________________________".green());
-    println!("{}", "Select the drive you would like to format (`q` to quit)".yellow().clear());
-    let mut user_selection = -1;
-    let mut is_done = false;
+    // anything in use cannot be picked at all, the reasons being listed above
+    let excluded: Vec<&str> = drives_vec.iter().filter(|d| d.in_use()).map(|d| &d.path[..]).collect();
+    if !excluded.is_empty() {
+        say!("{}", format!("[!] Left out {}, as they are in use (see above for why)", excluded.join(", ")).yellow());
+    }
+    if umount_idx_vec.is_empty() {
+        fail(Error::Busy("Every drive is in use, so there is nothing that can be wiped".to_string()));
+    }
 
-    // get the user's desired drive, either quitting or looping on character input
-    while !is_done {
-        let mut input_text = String::new();
-        print!(" > ");
-        io::stdout().flush().unwrap();
-        io::stdin().read_line(&mut input_text).expect("failed to read from stdin");
+    say!("{}", "_______________________________________________________________".green());
+    say!("{}", "Select the drive(s) you would like to format, separated by spaces (`q` to quit)".yellow().clear());
+
+    // get the user's desired drives, either quitting or looping on character input
+    loop {
+        let input_text = read_input();
 
         let trimmed = input_text.trim();
-        match trimmed.parse::<i32>() {
-            // user gave proper selection
-            Ok(i) => {
-                // make sure the user isnt being an idiot
-                if i < 1 || i > umount_idx_vec.len() as i32 {
-                    println!("{}", "[-] Not a valid drive index. Please try again".red().clear());
-                } else {
-                    user_selection = i;
-                    is_done = true
-                }
-                
-            },
-            Err(..) => {
-                if &trimmed[0..1] == "q" {
-                    println!("[ ] Caught quitting input. Doing so...");
-                    std::process::exit(0);
-                } else {
-                    println!("{}", "[-] Not a valid drive index. Please try again".red().clear());
-                }
-            },
-        };
+        if trimmed.starts_with('q') {
+            say!("[ ] Caught quitting input. Doing so...");
+            std::process::exit(Error::Aborted.exit_code());
+        }
+
+        // make sure the user isnt being an idiot
+        let selection: Vec<usize> = trimmed.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| s.parse::<usize>().ok())
+            .filter(|i| *i >= 1 && *i <= umount_idx_vec.len())
+            .map(|i| umount_idx_vec[i - 1])
+            .collect();
+        let given = trimmed.split(|c: char| c == ',' || c.is_whitespace()).filter(|s| !s.is_empty()).count();
+        if selection.is_empty() || selection.len() != given {
+            say!("{}", "[-] Not a valid drive index. Please try again".red().clear());
+            continue;
+        }
+
+        return selection;
     }
+}
 
-    
-    // print drive partition information
-    println!("{}", "_______________________________________________________________".green());
-    println!("You have selected disk # {}", user_selection);
-    println!("{}", drives_vec[umount_idx_vec[user_selection as usize-1]].to_string().red());
-    match print_top_levels(&drives_vec[umount_idx_vec[user_selection as usize-1]]){
+/// prints everything we know about `disk`, including the top level
+/// contents of each of its partitions
+fn print_disk(disk: &DiskData) {
+    say!("{}", disk.to_string().red());
+    say!("{}", disk.identity().bold());
+    for partition in disk.partitions.iter(){
+        say!("\t{}", partition.to_string().italic().yellow());
+    }
+    match print_top_levels(disk){
         Ok(_) => (),
-        Err(e) => println!("{}: {}", "[-] Failed to print all the things".red(), e)
+        Err(e) => say!("{}: {}", "[-] Failed to print all the things".red(), e)
     };
+}
+
+/// shows the user what is on each of `disks` and makes them confirm (twice)
+/// that they really want it gone, quitting if they do not
+fn confirm_wipe(disks: &[&DiskData]) {
+    // print drive partition information
+    for disk in disks.iter() {
+        say!("{}", "_______________________________________________________________".green());
+        say!("You have selected disk {}", disk.path);
+        print_disk(disk);
+    }
 
     // make sure the user wants to continue
-    println!("{}", "Does this information look correct? (y/N)".yellow().clear());
-    let mut input_text = String::new();
-    print!(" > ");
-    io::stdout().flush().unwrap();
-    io::stdin().read_line(&mut input_text).expect("failed to read from stdin");
+    say!("{}", "Does this information look correct? (y/N)".yellow().clear());
+    let input_text = read_input();
     
     let trimmed = input_text.trim();
     if trimmed.to_lowercase() != "y" {
-        println!("{}", "[-] Caught non-affirmative. Quitting...".red().clear());
-        std::process::exit(0);
+        fail(Error::Aborted);
     }
 
     // final safety check. is the user really sure they want to format everything???
-    println!("{}", "_______________________________________________________________".red().bold());
-    println!("{}", "WARNING WARNING WARNING WARNING WARNING WARNING WARNING WARNING".red().bold());
-    println!("{}", "_______________________________________________________________".red().bold());
-    println!("");
-    println!("{}", "YOU ARE ABOUT TO PERMANENTLY DELETE ALL INFORMATION FROM THIS DISK.".red().bold());
-    println!("{}", "ARE YOU SURE YOU WISH TO CONTINUE? THERE IS NO GOING BACK AFTER THIS".red().bold());
-    println!("{}", "(y/N)".yellow());
+    say!("{}", "_______________________________________________________________".red().bold());
+    say!("{}", "WARNING WARNING WARNING WARNING WARNING WARNING WARNING WARNING".red().bold());
+    say!("{}", "_______________________________________________________________".red().bold());
+    say!();
+    if disks.len() > 1 {
+        say!("{} {} {}", "YOU ARE ABOUT TO PERMANENTLY DELETE ALL INFORMATION FROM".red().bold(),
+            disks.len().to_string().red().bold(), "DISKS.".red().bold());
+    } else {
+        say!("{}", "YOU ARE ABOUT TO PERMANENTLY DELETE ALL INFORMATION FROM THIS DISK.".red().bold());
+    }
+    say!("{}", "ARE YOU SURE YOU WISH TO CONTINUE? THERE IS NO GOING BACK AFTER THIS".red().bold());
+    say!("{}", "(y/N)".yellow());
 
-    let mut input_text = String::new();
-    print!(" > ");
-    io::stdout().flush().unwrap();
-    io::stdin().read_line(&mut input_text).expect("failed to read from stdin");
+    let input_text = read_input();
     
     let trimmed = input_text.trim();
     if trimmed.to_lowercase() != "y" {
-        println!("[-] Caught non-affirmative. Quitting...");
+        fail(Error::Aborted);
+    }
+}
+
+
+
+/// makes sure we are running as root, as we need to read (and write) raw disks
+fn require_root() {
+    if !Uid::effective().is_root() {
+        fail(Error::Permission("This program must be run as root".to_string()));
+    }
+}
+
+/// reads the details of every disk on the system
+fn discover_drives() -> Vec<DiskData> {
+    list_disks().unwrap_or_else(|e| fail(e))
+}
+
+/// finds the disk named by `spec` in `drives_vec`, quitting if there is none.
+/// a path to a regular file is taken as a disk image, and added to `drives_vec`
+fn find_disk_or_exit(drives_vec: &mut Vec<DiskData>, spec: &str) -> usize {
+    find_target(drives_vec, spec).unwrap_or_else(|e| fail(e))
+}
+
+/// the arguments that describe which passes to run, shared by `wipe` and `verify`
+fn pass_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+			Arg::with_name("loops")
+					.short("n")
+					.long("number")
+					.takes_value(true)
+					.help("The number of times to overwrite the disk (default is 5)"),
+            Arg::with_name("random")
+                    .short("r")
+                    .long("random")
+                    .help("Overwrite the disk with seeded pseudo-random data instead of zeros"),
+            Arg::with_name("seed")
+                    .short("s")
+                    .long("seed")
+                    .takes_value(true)
+                    .help("The hex seed for random passes (default is freshly generated)"),
+            Arg::with_name("method")
+                    .short("m")
+                    .long("method")
+                    .takes_value(true)
+                    .possible_values(METHOD_NAMES)
+                    .conflicts_with_all(&["loops", "random"])
+                    .help("Wipe according to a named standard instead of plain passes"),
+            Arg::with_name("passes")
+                    .short("p")
+                    .long("passes")
+                    .takes_value(true)
+                    .conflicts_with_all(&["loops", "random", "method"])
+                    .help("Comma separated passes to run, each one of `random`, `complement`, a hex pattern (`0x55AA`) or `@<file>` to repeat a pattern file")
+    ]
+}
+
+/// the arguments that describe how to talk to the disk, shared by `wipe` and `verify`
+fn io_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+            Arg::with_name("bs")
+                    .long("bs")
+                    .takes_value(true)
+                    .value_name("size")
+                    .help("How much to read or write at a time, such as 512K or 4M. Must be a multiple of the disk's logical block size [default: 1M]"),
+            Arg::with_name("queue-depth")
+                    .long("queue-depth")
+                    .takes_value(true)
+                    .value_name("n")
+                    .help("How many blocks to keep in flight with io_uring, if built with the io_uring feature. 1 turns it off [default: 16]"),
+            Arg::with_name("no-direct")
+                    .long("no-direct")
+                    .help("Go through the page cache instead of straight to the device with O_DIRECT"),
+            Arg::with_name("sync")
+                    .long("sync")
+                    .help("Open the disk with O_SYNC, so every write waits for the device (slower)")
+    ]
+}
+
+/// the argument that picks where progress goes, shared by `wipe` and `verify`
+fn progress_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("progress")
+            .long("progress")
+            .takes_value(true)
+            .possible_values(&["bar", "json", "none"])
+            .default_value("bar")
+            .help("Show progress as bars, as JSON lines on stderr for other tools to follow, or not at all")
+}
+
+/// where progress goes, as picked by `--progress`. bars are drawn on `bar`,
+/// which may be hidden, in which case only the messages get printed
+fn sink_from_args(matches: &ArgMatches, bar: ProgressBar) -> Arc<dyn ProgressSink> {
+    match matches.value_of("progress") {
+        Some("json") => Arc::new(JsonLinesSink),
+        Some("none") => Arc::new(SilentSink),
+        _ => Arc::new(TerminalSink::new(bar))
+    }
+}
+
+/// the arguments that describe how to check the disk, shared by `wipe` and `verify`
+fn verify_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+            Arg::with_name("verify")
+                    .long("verify")
+                    .takes_value(true)
+                    .value_name("mode")
+                    .help("How much of the disk to read back: `full`, or `sample:<percent>` to read that much in random blocks plus the first and last 16MiB [default: full]"),
+            Arg::with_name("verify-seed")
+                    .long("verify-seed")
+                    .takes_value(true)
+                    .value_name("seed")
+                    .requires("verify")
+                    .help("The hex seed that picks the blocks to sample, to repeat an earlier check (default is freshly generated)")
+    ]
+}
+
+/// works out how to check the disk from the verification arguments
+fn verify_from_args(matches: &ArgMatches) -> (VerifyMode, [u8; SEED_LEN]) {
+    let mode = match matches.value_of("verify").map(VerifyMode::parse) {
+        Some(Ok(a)) => a,
+        Some(Err(e)) => fail(Error::Invalid(format!("Invalid --verify: {}", e))),
+        None => VerifyMode::Full
+    };
+    let seed = match matches.value_of("verify-seed") {
+        Some(s) => match parse_seed(s) {
+            Ok(a) => a,
+            Err(e) => fail(Error::Invalid(format!("Invalid --verify-seed: {}", e)))
+        },
+        None => new_seed()
+    };
+    if let VerifyMode::Sample(_) = mode {
+        say!("[ ] Sampling verification with {}, seed {}", mode, seed_to_hex(&seed));
+    }
+
+    (mode, seed)
+}
+
+/// works out the passes to run from the pass arguments, along with the seed
+/// for any random ones and a description of the method
+fn plan_from_args(matches: &ArgMatches) -> (Vec<WipePass>, [u8; SEED_LEN], String) {
+    let loop_num: usize = match matches.value_of("loops").map(|n| n.parse::<usize>()) {
+        Some(Ok(a)) => a,
+        Some(Err(e)) => fail(Error::Invalid(format!("Invalid --number: {}", e))),
+        None => 5
+    };
+
+    let method = matches.value_of("method").and_then(find_method);
+    let seed = match matches.value_of("seed") {
+        Some(s) => match parse_seed(s) {
+            Ok(a) => a,
+            Err(e) => fail(Error::Invalid(format!("Invalid --seed: {}", e)))
+        },
+        None => new_seed()
+    };
+    let passes: Vec<WipePass> = match (method, matches.value_of("passes")) {
+        (Some(m), _) => m.plan(seed).unwrap_or_else(|e| fail(e)),
+        (None, Some(spec)) => match custom_plan(spec, seed) {
+            Ok(a) => a,
+            Err(e) => fail(Error::Invalid(format!("Invalid pass list: {}", e)))
+        },
+        (None, None) => simple_plan(loop_num, if matches.is_present("random") {Some(seed)} else {None})
+    };
+    let description = match (method, matches.value_of("passes")) {
+        (Some(m), _) => m.description.to_string(),
+        (None, Some(spec)) => format!("custom: {}", spec),
+        (None, None) => format!("{} pass(es) of {}", passes.len(), 
+            if matches.is_present("random") {"random data"} else {"zeros"})
+    };
+
+    (passes, seed, description)
+}
+
+
+/// works out how to write to the disk from the I/O arguments
+fn io_from_args(matches: &ArgMatches) -> IoOptions {
+    let mut io = IoOptions::default();
+    if let Some(bs) = matches.value_of("bs") {
+        io.block_size = match parse_size(bs) {
+            Ok(a) => a,
+            Err(e) => fail(Error::Invalid(format!("Invalid --bs: {}", e)))
+        };
+    }
+    if let Some(depth) = matches.value_of("queue-depth") {
+        io.queue_depth = match depth.parse::<usize>() {
+            Ok(a) if a > 0 => a,
+            _ => fail(Error::Invalid(format!("Invalid --queue-depth: {}", depth)))
+        };
+    }
+    io.direct = !matches.is_present("no-direct");
+    io.sync = matches.is_present("sync");
+
+    // only say which engine we are using if there is a choice to be made
+    if io.queue_depth > 1 {
+        match uring_support() {
+            Ok(_) => say!("[ ] Using io_uring with {} requests in flight", io.queue_depth),
+            Err(e) => {
+                say!("[ ] {}, using synchronous I/O", e);
+                io.queue_depth = 1;
+            }
+        }
+    }
+    io
+}
+
+/// cancels `token` on the first Ctrl-C or SIGTERM, so the wipes stop cleanly
+/// at the next block, and quits on the second. the signals are blocked here
+/// and waited for on a thread of their own, so this has to be called before
+/// any other threads are started for the block to carry over to them
+fn cancel_on_signals(token: CancelToken) {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGTERM);
+    if let Err(e) = signals.thread_block() {
+        say!("[-] Failed to catch Ctrl-C, so stopping the wipe will not be clean: {}", e);
+        return;
+    }
+
+    let waiter = std::thread::Builder::new().name("signals".to_string()).spawn(move || {
+        if let Ok(signal) = signals.wait() {
+            eprintln!("{} Caught {}, stopping at the next block. Do it again to quit right away", "[-]".red().bold(), signal);
+            token.cancel();
+        }
+        if signals.wait().is_ok() {
+            std::process::exit(Error::Aborted.exit_code());
+        }
+    });
+    if let Err(e) = waiter {
+        say!("[-] Failed to catch Ctrl-C, so stopping the wipe will not be clean: {}", e);
+        let _ = signals.thread_unblock();
+    }
+}
+
+/// `list`: prints every disk we can find
+fn cmd_list(matches: &ArgMatches) {
+    let drives_vec = discover_drives();
+    if matches.is_present("json") || machine_output() {
+        print_json(&drives_vec);
+    } else {
+        print_drives(&drives_vec);
+    }
+}
+
+/// `inspect`: shows the partitions and top level contents of one disk
+fn cmd_inspect(matches: &ArgMatches) {
+    let mut drives_vec = discover_drives();
+    let idx = find_disk_or_exit(&mut drives_vec, matches.value_of("device").unwrap());
+    if machine_output() {
+        print_json(&drives_vec[idx]);
+    } else {
+        print_disk(&drives_vec[idx]);
+    }
+}
+
+/// `verify`: checks that a disk holds what the final pass of a wipe should
+/// have left on it, without writing anything
+fn cmd_verify(matches: &ArgMatches) {
+    let (passes, _, description) = plan_from_args(matches);
+    let mut drives_vec = discover_drives();
+    let idx = find_disk_or_exit(&mut drives_vec, matches.value_of("device").unwrap());
+    let disk = &drives_vec[idx];
+    let pass = match passes.last() {
+        Some(a) => &a.pass,
+        None => fail(Error::Invalid("There are no passes to verify".to_string()))
+    };
+
+    say!("Verifying {} {} against the final pass of {} ({})", disk.path, disk.identity(), description, pass);
+    // random data can only be generated again from the seed the wipe used
+    if pass.uses_seed() && !matches.is_present("seed") {
+        fail(Error::Invalid(format!("Verifying random data needs the seed it was written with. Pass the seed from the wipe's output or record with --seed: {}", pass)));
+    }
+    let io = io_from_args(matches);
+    let (mode, seed) = verify_from_args(matches);
+    // the wipe sampled each pass with its number as the stream, and this is the final pass
+    let stream = match matches.value_of("verify-stream").map(|s| s.parse::<u64>()) {
+        Some(Ok(a)) => a,
+        Some(Err(e)) => fail(Error::Invalid(format!("Invalid --verify-stream: {}", e))),
+        None => passes.len() as u64
+    };
+    let sample = mode.plan(disk.bytes, io.block_size as u64, seed, stream);
+    let sink = sink_from_args(matches, new_bar());
+    let mismatches = find_mismatches(disk, pass, sample.as_ref(), &io, sink.as_ref(), &CancelToken::new()).unwrap_or_else(|e| fail(e));
+    sink.event(&Event::Finished { disk: disk.path.clone(), result: (if mismatches.is_empty() {"verified"} else {"FAILED verification"}).to_string() });
+    let coverage = sample.as_ref().map_or(100.0, |plan| plan.coverage());
+    if machine_output() {
+        print_json(&serde_json::json!({
+            "disk": disk.path,
+            "identity": disk.identity(),
+            "pattern": pass.to_string(),
+            "verified": mismatches.is_empty(),
+            "mismatch_offset": mismatches.first().map(|r| r.first * disk.logical_block_size.max(1)),
+            "mismatches": mismatches,
+            "sample": sample.as_ref().map(|plan| plan.record()),
+            "coverage": coverage
+        }));
+    }
+    if mismatches.is_empty() {
+        say!("{} ({:.2}% of the disk read)", "[+] Disk verified".green().bold(), coverage);
+    } else {
+        let error = Error::Mismatch { path: disk.path.clone(), ranges: mismatches };
+        eprintln!("{} {}", "[-] Verification failed:".red().bold(), error);
+        std::process::exit(error.exit_code());
+    }
+}
+
+/// `report`: prints a saved wipe record
+fn cmd_report(matches: &ArgMatches) {
+    match WipeRecord::load(matches.value_of("record").unwrap()) {
+        Ok(record) if machine_output() => print_json(&record),
+        Ok(record) => say!("{}", record),
+        Err(e) => fail(e)
+    }
+}
+
+/// signs a certificate of erasure for `record` and saves it to `path`
+fn save_certificate(record: &WipeRecord, matches: &ArgMatches, path: &str) {
+    let key_path = matches.value_of("key").unwrap_or(DEFAULT_KEY_PATH);
+    let key = match load_or_create_key(key_path) {
+        Ok(a) => a,
+        Err(e) => {
+            say!("{} {}", "[-] Could not sign the certificate:".red().bold(), e);
+            return;
+        }
+    };
+    let operator = match matches.value_of("operator") {
+        Some(a) => a.to_string(),
+        None => current_operator()
+    };
+
+    match Certificate::new(record.clone(), operator).sign(&key).and_then(|c| c.save(path)) {
+        Ok(files) => {
+            say!("[+] Saved certificate of erasure to {}", files.join(", "));
+            say!("[ ] Signed with public key {}", public_key_hex(&key));
+        },
+        Err(e) => say!("{} {}", "[-]".red().bold(), e)
+    }
+}
+
+/// `verify-certificate`: checks the signature on a certificate of erasure
+fn cmd_verify_certificate(matches: &ArgMatches) {
+    let cert = SignedCertificate::load(matches.value_of("certificate").unwrap()).unwrap_or_else(|e| fail(e));
+
+    // the key can be given directly or as a file holding it
+    let expected = matches.value_of("public-key").map(|k| match std::fs::read_to_string(k) {
+        Ok(a) => a.trim().to_string(),
+        Err(_) => k.to_string()
+    });
+    let result = cert.verify(expected.as_deref());
+    if machine_output() {
+        print_json(&serde_json::json!({
+            "certificate": cert,
+            "valid": result.is_ok(),
+            "trusted_key": expected.is_some(),
+            "error": result.as_ref().err().map(|e| e.to_string())
+        }));
+    } else {
+        say!("{}", cert);
+    }
+    match result {
+        Ok(_) => {
+            say!("{}", "[+] Signature is valid".green().bold());
+            if expected.is_none() {
+                say!("{}", "[!] Only checked against the key inside the certificate. Pass --public-key to check who signed it".yellow());
+            }
+        },
+        Err(e) => {
+            eprintln!("{} {}", "[-] Certificate is not valid:".red().bold(), e);
+            std::process::exit(e.exit_code());
+        }
+    }
+}
+
+/// gives where to save a file about `disk`. that is just `path` unless several
+/// disks are being wiped, in which case the disk goes in the name too
+fn per_disk_path(path: &str, disk: &DiskData, many: bool) -> String {
+    if !many {
+        return path.to_string();
+    }
+    match path.rfind('.') {
+        Some(idx) if !path[idx..].contains('/') => format!("{}-{}{}", &path[..idx], disk.file_stem(), &path[idx..]),
+        _ => format!("{}-{}", path, disk.file_stem())
+    }
+}
+
+/// `wipe`: picks the disks, confirms them with the user and overwrites them
+/// all at once, each on its own thread
+fn cmd_wipe(matches: &ArgMatches) {
+    if matches.is_present("list-methods") {
+        for method in METHODS.iter() {
+            say!("{:<12}{} ({} pass(es))", method.name, method.description, method.passes.len());
+        }
         std::process::exit(0);
     }
 
-    // do it
-    println!("{}", "_______________________________________________________________".green());
-    println!("Securely formatting drive ({} pass(es) of zeros). This will take a while...", loop_num);
-    println!("Started at {:?}", chrono::offset::Local::now());
-    let useridx = umount_idx_vec[user_selection as usize-1];
-    for i in 0..loop_num {
-        println!("On pass #{}", i+1);
-        std::io::stdout().flush().unwrap();
-        match zero_drive(&drives_vec[useridx]){
-            Ok(_) => (),
-            Err(e) => println!("Zero drive issue hit: {}", e)
+    // see if we are gonna double-check our work
+    let check: bool = !matches.is_present("checked");
+    let retries: usize = match matches.value_of("retries").unwrap_or("5").parse::<usize>() {
+        Ok(a) => a,
+        Err(e) => fail(Error::Invalid(format!("Invalid --retries: {}", e)))
+    };
+    let io = io_from_args(matches);
+    let (mode, verify_seed) = verify_from_args(matches);
+    let specs: Vec<&str> = matches.values_of("device").map(|v| v.collect()).unwrap_or_default();
+
+    // either pick an interrupted wipe back up or work out what each pass is going to write
+    let mut resumed = match matches.value_of("resume") {
+        Some(path) => match Journal::load(path) {
+            Ok(a) => {
+                say!("[ ] Resuming {} from pass #{} at offset {}", a.record.method, a.pass + 1, a.offset);
+                Some(a)
+            },
+            Err(e) => fail(e)
+        },
+        None => None
+    };
+    let (passes, seed, description) = plan_from_args(matches);
+    let uses_seed = passes.iter().any(|p| p.pass.uses_seed());
+    if uses_seed && resumed.is_none() {
+        say!("[ ] Random pass seed: {}", seed_to_hex(&seed));
+    }
+
+    // get the partition/disk info we need
+    say!("Reading partition information...");
+    let mut drives_vec = discover_drives();
+
+    // work out which drives we are wiping, either from the journal, the command line or by asking.
+    // a resumed wipe finds its disk by serial or WWN, as the path may have changed since
+    let mut targets: Vec<usize> = match &resumed {
+        Some(journal) => {
+            if specs.len() > 1 {
+                fail(Error::Invalid("Only one disk can be resumed at a time".to_string()));
+            }
+            vec![find_disk_or_exit(&mut drives_vec, specs.first().copied().unwrap_or_else(|| journal.disk_spec()))]
+        },
+        None if !specs.is_empty() => specs.iter().map(|spec| find_disk_or_exit(&mut drives_vec, spec)).collect(),
+        None => select_drives(&drives_vec)
+    };
+    let mut seen: Vec<usize> = Vec::new();
+    targets.retain(|idx| if seen.contains(idx) {false} else {seen.push(*idx); true});
+    let many = targets.len() > 1;
+    if many && matches.is_present("journal") {
+        fail(Error::Invalid("--journal can only be used when wiping a single disk".to_string()));
+    }
+
+    // set up a wipe of each disk, and make sure they can all go ahead before asking
+    let wipers: Vec<Wiper> = targets.iter().map(|idx| {
+        let mut wiper = Wiper::new(drives_vec[*idx].clone())
+            .passes(passes.clone(), description.clone())
+            .io(io)
+            .verify(mode)
+            .verify_seed(verify_seed)
+            .retries(retries);
+        if !check {
+            wiper = wiper.no_verify();
+        }
+        if let Some(path) = matches.value_of("journal") {
+            wiper = wiper.journal(path.to_string());
+        }
+        if let Some(journal) = resumed.take() {
+            wiper = wiper.resume(journal);
+        }
+        wiper
+    }).collect();
+    for wiper in wipers.iter() {
+        if let Err(e) = wiper.validate() {
+            fail(e);
         }
     }
 
-    println!("{}", "_______________________________________________________________".green());
-    println!("{}", "[+] Wipe complete!".green());
-    
-    // see if we are gonna be doing our own checking
-    if check {
-        println!("{}", "[ ] Just double checking my work...".yellow());
-        match assert_check(&drives_vec[useridx]){
-            Ok(_) => (),
-            Err(e) => {
-                println!("[ ] Attempting to zero non-zeroed data...");
-                for _ in 0..loop_num {
-                    match zero_check_from(&drives_vec[useridx], e) {
-                        Ok(_) => (),
-                        Err(e) => println!("{} {}", "[-] Failed secondary write:".red().bold(), e.red().bold())
-                    }
-                    println!("{}", "[+] Secondary write complete. Checking success now...".yellow())
-                }
-                    
-                match assert_check_from(&drives_vec[useridx], e) {
-                    Ok(_) => println!("{}", "[+] Successfully zeroed volume!".green()),
-                    Err(e) => println!("{} (offset {})", "[-] Failed secondary check. Aborting...".red().bold(), e)
+    // automation has to name every disk it means to destroy, otherwise we ask
+    match matches.values_of("yes-destroy") {
+        Some(tokens) => {
+            let tokens: Vec<&str> = tokens.collect();
+            for disk in wipers.iter().map(|w| w.disk()) {
+                if !tokens.iter().any(|token| disk.confirmed_by(token, &drives_vec)) {
+                    let expected = disk.confirmation_token_among(&drives_vec);
+                    let what = if disk.serial.as_deref() == Some(expected) {"serial"} else if disk.wwn.as_deref() == Some(expected) {"WWN"} else {"path"};
+                    fail(Error::Invalid(format!("--yes-destroy does not give the {} of {} {}", what, disk.path, disk.identity())));
                 }
-                
+                say!("[ ] Destruction of {} confirmed from the command line", disk.path);
             }
+        },
+        None => confirm_wipe(&wipers.iter().map(|w| w.disk()).collect::<Vec<&DiskData>>())
+    };
+
+    // do it. the progress bars are only drawn together on a terminal, elsewhere
+    // each disk just reports as it goes
+    say!("{}", "_______________________________________________________________".green());
+    let cancel = CancelToken::new();
+    cancel_on_signals(cancel.clone());
+    let bars = matches.value_of("progress") == Some("bar");
+    let multi = if bars && !machine_output() && nix::unistd::isatty(2).unwrap_or(false) {
+        Some(MultiProgress::new())
+    } else {
+        None
+    };
+    let handles: Vec<(DiskData, Result<WipeHandle, Error>)> = wipers.into_iter().map(|wiper| {
+        let bar = match &multi {
+            Some(m) => m.add(ProgressBar::new(0)),
+            None => ProgressBar::hidden()
         };
+        let disk = wiper.disk().clone();
+        let sink = sink_from_args(matches, bar);
+        let handle = wiper.progress(sink.clone()).cancel_with(cancel.clone()).start();
+        if handle.is_err() {
+            // the bar still has to be finished, or drawing it never ends
+            sink.event(&Event::Finished { disk: disk.path.clone(), result: "FAILED".to_string() });
+        }
+        (disk, handle)
+    }).collect();
+    if let Some(m) = &multi {
+        if let Err(e) = m.join() {
+            say!("[-] Failed to draw progress: {}", e);
+        }
+    }
+    let results: Vec<(DiskData, Result<WipeRecord, Error>)> = handles.into_iter()
+        .map(|(disk, handle)| (disk, handle.and_then(|h| h.join())))
+        .collect();
+
+    say!("{}", "_______________________________________________________________".green());
+    if cancel.is_cancelled() {
+        say!("{}", "[-] Wipe stopped before it finished".red().bold());
     } else {
-        println!("[ ] Skipping success assertion check");
+        say!("{}", "[+] Wipe complete!".green());
+    }
+    if !check {
+        say!("[ ] Skipping success assertion check");
+    }
+
+    // save what each disk needs, and sum it all up
+    let mut records: Vec<&WipeRecord> = Vec::new();
+    for (disk, record) in results.iter() {
+        let record = match record {
+            Ok(a) => a,
+            Err(e) => {
+                say!("{} {} {}", "[-]".red().bold(), e, disk.identity());
+                continue;
+            }
+        };
+        if let Some(path) = matches.value_of("record") {
+            let path = per_disk_path(path, disk, many);
+            match record.save(&path) {
+                Ok(_) => say!("[+] Saved wipe record for {} to {}", disk.path, path),
+                Err(e) => say!("{} {}", "[-]".red().bold(), e)
+            }
+        }
+        if let Some(path) = matches.value_of("certificate") {
+            if record.interrupted {
+                say!("[-] Not certifying {}, as its wipe was cancelled before it finished", disk.path);
+            } else {
+                save_certificate(record, matches, &per_disk_path(path, disk, many));
+            }
+        }
+        records.push(record);
+    }
+
+    say!("{}", "Summary _______________________________________________________".green());
+    for (disk, record) in results.iter() {
+        match record {
+            Ok(record) => {
+                let status = match record.status() {
+                    "success" => "[+] success".green().bold(),
+                    "completed with errors" => "[!] completed with errors".yellow().bold(),
+                    other => format!("[-] {}", other).red().bold()
+                };
+                let errors: usize = record.passes.iter().map(|p| p.errors.len()).sum();
+                say!("{} {} {}", status, disk.path, disk.identity());
+                say!("\t{} pass(es), {} bytes written, {} error(s), finished at {}", record.passes.len(),
+                    record.passes.iter().map(|p| p.bytes_written).sum::<u64>(), errors,
+                    record.finished.as_deref().unwrap_or(""));
+                if record.interrupted {
+                    say!("\tcancelled before it finished, so the disk is in an indeterminate state");
+                    if let Some(pass) = record.passes.last().filter(|p| p.stopped_at.is_some()) {
+                        say!("\tpass #{} stopped at offset {}", pass.number, pass.stopped_at.unwrap_or(0));
+                    }
+                }
+                if !record.bad_ranges.is_empty() {
+                    say!("\t{} unwritable block(s) skipped, at LBA {}", record.bad_blocks(),
+                        record.bad_ranges.iter().map(|r| r.to_string()).collect::<Vec<String>>().join(", "));
+                }
+            },
+            Err(_) => say!("{} {} {}", "[-] CRASHED".red().bold(), disk.path, disk.identity())
+        }
+    }
+    if machine_output() {
+        if many {
+            print_json(&records);
+        } else if let Some(record) = records.first() {
+            print_json(record);
+        }
+    }
+
+    // scripts can tell how it went from the exit code alone. with several
+    // disks, the first one that did not succeed decides it
+    let error = results.iter().find_map(|(_, record)| match record {
+        Ok(a) => a.error(),
+        Err(e) => Some(e.clone())
+    });
+    if let Some(error) = error {
+        std::process::exit(error.exit_code());
     }
+}
+
+
+
+/// funny enough this is the main function
+fn main() {
+    // fetch the CLI arguments
+    let matches = App::new("Checked Wipe")
+			.version(env!("CARGO_PKG_VERSION"))
+			.author("Nick Ammann")
+			.about("Securely wipes a disk")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .arg(Arg::with_name("output")
+                    .short("o")
+                    .long("output")
+                    .takes_value(true)
+                    .possible_values(&["text", "json"])
+                    .default_value("text")
+                    .global(true)
+                    .help("Print results as text for people or JSON for other tools. With json, stdout only holds the JSON document"))
+            .subcommand(SubCommand::with_name("list")
+                    .about("Lists every disk on the system")
+                    .arg(Arg::with_name("json")
+                            .long("json")
+                            .help("Print the disks as JSON")))
+            .subcommand(SubCommand::with_name("inspect")
+                    .about("Shows the partitions and top level contents of a disk")
+                    .arg(Arg::with_name("device")
+                            .required(true)
+                            .help("The disk, by path, /dev/disk/by-id link, serial or WWN, or a disk image file")))
+            .subcommand(SubCommand::with_name("wipe")
+                    .about("Overwrites a disk")
+                    .args(&pass_args())
+                    .arg(Arg::with_name("checked")
+                            .short("c")
+                            .long("nocheck")
+                            .conflicts_with("verify")
+                            .help("Do not check to make sure the drive was really overwritten after the wipe"))
+                    .args(&verify_args())
+                    .arg(Arg::with_name("retries")
+                            .long("retries")
+                            .takes_value(true)
+                            .value_name("n")
+                            .conflicts_with("checked")
+                            .help("How many times to rewrite and check again the sectors that fail verification, before reporting them as bad [default: 5]"))
+                    .args(&io_args())
+                    .arg(progress_arg())
+                    .arg(Arg::with_name("list-methods")
+                            .long("list-methods")
+                            .help("Print the available wipe methods and exit"))
+                    .arg(Arg::with_name("device")
+                            .short("d")
+                            .long("device")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1)
+                            .help("A disk to wipe, by path, /dev/disk/by-id link, serial or WWN, or a disk image file, instead of picking from a menu. Repeat it to wipe several disks at once"))
+                    .arg(Arg::with_name("yes-destroy")
+                            .long("yes-destroy")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1)
+                            .value_name("serial")
+                            .requires("device")
+                            .help("Skip every confirmation. Must be the serial of the disk given to --device (or its WWN, or path if it has neither or they are shared with another disk), repeated for each disk"))
+                    .arg(Arg::with_name("record")
+                            .long("record")
+                            .takes_value(true)
+                            .value_name("file")
+                            .help("Save a record of the wipe to this file. With several disks, each gets its own file named after its serial"))
+                    .arg(Arg::with_name("journal")
+                            .long("journal")
+                            .takes_value(true)
+                            .value_name("file")
+                            .conflicts_with("resume")
+                            .help("Where to checkpoint progress so the wipe can be resumed. Must not be on the disk being wiped [default: /var/lib/checked_wipe/<serial>.journal]"))
+                    .arg(Arg::with_name("resume")
+                            .long("resume")
+                            .takes_value(true)
+                            .value_name("journal")
+                            .conflicts_with_all(&["loops", "random", "seed", "method", "passes"])
+                            .help("Carry on with an interrupted wipe from its journal. The disk is found again by its serial or WWN, and the passes and seed come from the journal"))
+                    .arg(Arg::with_name("certificate")
+                            .long("certificate")
+                            .takes_value(true)
+                            .value_name("file")
+                            .help("Save a signed certificate of erasure to this file, with .txt and .html copies next to it. With several disks, each gets its own named after its serial"))
+                    .arg(Arg::with_name("operator")
+                            .long("operator")
+                            .takes_value(true)
+                            .requires("certificate")
+                            .help("Who ran the wipe, for the certificate. Defaults to the user behind sudo"))
+                    .arg(Arg::with_name("key")
+                            .long("key")
+                            .takes_value(true)
+                            .value_name("file")
+                            .requires("certificate")
+                            .help("The Ed25519 key to sign the certificate with, created if missing [default: /etc/checked_wipe/signing.key]")))
+            .subcommand(SubCommand::with_name("verify")
+                    .about("Checks a disk still holds what the final pass of a wipe wrote, without writing to it")
+                    .args(&pass_args())
+                    .args(&verify_args())
+                    .arg(Arg::with_name("verify-stream")
+                            .long("verify-stream")
+                            .takes_value(true)
+                            .value_name("stream")
+                            .requires("verify-seed")
+                            .help("The stream of the sample to repeat, as saved with the pass in the record [default: the number of the final pass]"))
+                    .args(&io_args())
+                    .arg(progress_arg())
+                    .arg(Arg::with_name("device")
+                            .required(true)
+                            .help("The disk, by path, /dev/disk/by-id link, serial or WWN, or a disk image file")))
+            .subcommand(SubCommand::with_name("report")
+                    .about("Prints a saved wipe record")
+                    .arg(Arg::with_name("record")
+                            .required(true)
+                            .help("The record file saved by `wipe --record`")))
+            .subcommand(SubCommand::with_name("verify-certificate")
+                    .about("Checks the signature on a certificate of erasure, without needing the signing key")
+                    .arg(Arg::with_name("certificate")
+                            .required(true)
+                            .help("The .json certificate saved by `wipe --certificate`"))
+                    .arg(Arg::with_name("public-key")
+                            .long("public-key")
+                            .takes_value(true)
+                            .help("The public key (hex, or a file holding it) the certificate must be signed with")))
+			.get_matches();
 
-    println!("{}", "[+] All operations completed".green().bold());
+    set_output(if matches.value_of("output") == Some("json") {Output::Machine} else {Output::Terminal});
+    match matches.subcommand() {
+        ("list", Some(sub)) => {
+            require_root();
+            cmd_list(sub)
+        },
+        ("inspect", Some(sub)) => {
+            require_root();
+            cmd_inspect(sub)
+        },
+        ("wipe", Some(sub)) => {
+            print_welcome();
+            require_root();
+            cmd_wipe(sub)
+        },
+        ("verify", Some(sub)) => {
+            require_root();
+            cmd_verify(sub)
+        },
+        ("report", Some(sub)) => cmd_report(sub),
+        ("verify-certificate", Some(sub)) => cmd_verify_certificate(sub),
+        _ => unreachable!()
+    }
 }